@@ -1,20 +1,32 @@
 use anyhow::Result;
-use clap::{Arg, Command};
+use clap::{Arg, ArgAction, Command};
 use regex::Regex;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs::OpenOptions;
 use std::fs::{self, File};
 use std::io::{BufRead, BufReader, Write};
 use std::path::Path;
 use std::path::PathBuf;
+use std::str::FromStr;
 
-use crate::{errors::*, sync::*};
+use crate::errors::*;
+use crate::sync::location::{Location, Registry};
+use crate::sync::modes::{ConflictStrategy, SyncOptions};
 
 // Function that retrieves the config file (and creates it if it does not exist)
 fn config_file() -> Result<String> {
+    cfg_file("locations.cfg")
+}
+
+// Conflict strategies of location pairs, one per line: <STRATEGY> <SOURCE> <OTHER>
+fn conflicts_file() -> Result<String> {
+    cfg_file("conflicts.cfg")
+}
+
+fn cfg_file(name: &str) -> Result<String> {
     let home_dir = dirs_next::home_dir().expect("Failed to find home directory; could not retrieve locations; Try creating /home/user/.adv_rsync/cfg/locations.cfg");
 
-    let cfg_path: PathBuf = home_dir.join(".adv_rsync/cfg/locations.cfg");
+    let cfg_path: PathBuf = home_dir.join(".adv_rsync/cfg").join(name);
     if cfg_path.exists() {
         return Ok(cfg_path.to_str().unwrap_or("").to_string());
     }
@@ -51,7 +63,7 @@ fn append_to_cfg(locations: &Vec<String>) -> Result<()> {
 }
 
 // Parsing the given arguments
-pub fn parse_args() -> Result<Option<Vec<String>>> {
+pub fn parse_args() -> Result<SyncOptions> {
     let matches = Command::new("advanced_rsync")
         .version("1.0")
         .author("Iancu Stefan <iancustefanteodor@gmail.com>")
@@ -65,12 +77,111 @@ pub fn parse_args() -> Result<Option<Vec<String>>> {
                 .required(false)
                 .num_args(1..=100),
         )
+        .arg(
+            Arg::new("checksum")
+                .short('c')
+                .long("checksum")
+                .help("Compare files by size and content hash instead of modification time")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dry_run")
+                .short('n')
+                .long("dry-run")
+                .help("Only print what would be created, updated and deleted, without touching anything")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("conflict")
+                .long("conflict")
+                .value_name("STRATEGY")
+                .help("What to do with files changed in both locations of a pair: newest-wins (default), source-wins, keep-both or fail")
+                .value_parser(ConflictStrategy::from_str),
+        )
+        .arg(
+            Arg::new("pair_conflict")
+                .long("pair-conflict")
+                .value_names(["STRATEGY", "SOURCE", "OTHER"])
+                .help("Conflict strategy of one location pair, added to the conflicts cfg file; SOURCE is the location that wins with source-wins")
+                .num_args(3),
+        )
+        .arg(
+            Arg::new("plan_out")
+                .long("plan-out")
+                .value_name("FILE")
+                .help("Save the planned operations as JSON instead of executing them, so they can be reviewed")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("plan_in")
+                .long("plan-in")
+                .value_name("FILE")
+                .help("Execute a plan saved with --plan-out, then quit")
+                .value_parser(clap::value_parser!(PathBuf))
+                .conflicts_with_all(["plan_out", "dry_run"]),
+        )
+        .arg(
+            Arg::new("jobs")
+                .short('j')
+                .long("jobs")
+                .value_name("N")
+                .help("How many files are copied in parallel (default 4)")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("4"),
+        )
+        .arg(
+            Arg::new("server")
+                .long("server")
+                .value_name("FOLDER")
+                .help("Serve a folder over stdin/stdout to an ssh: location (started by it on the remote host)")
+                .value_parser(clap::value_parser!(PathBuf))
+                .exclusive(true),
+        )
         .get_matches();
 
+    // Nothing else is read or printed, stdout is the channel of the ssh: location
+    if let Some(root) = matches.get_one::<PathBuf>("server") {
+        return Ok(SyncOptions {
+            server: Some(root.clone()),
+            ..Default::default()
+        });
+    }
+
+    if let Some(pair_conflict) = matches.get_many::<String>("pair_conflict") {
+        let pair_conflict: Vec<&String> = pair_conflict.collect();
+        ConflictStrategy::from_str(pair_conflict[0])?;
+        let registry = Registry::default();
+        registry.open(pair_conflict[1])?;
+        registry.open(pair_conflict[2])?;
+        let mut cfg_file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(conflicts_file()?)?;
+        writeln!(
+            cfg_file,
+            "{} {} {}",
+            pair_conflict[0], pair_conflict[1], pair_conflict[2]
+        )?;
+    }
+
+    let options = SyncOptions {
+        checksum: matches.get_flag("checksum"),
+        dry_run: matches.get_flag("dry_run"),
+        conflict: matches
+            .get_one::<ConflictStrategy>("conflict")
+            .copied()
+            .unwrap_or_default(),
+        pair_conflicts: retrieve_pair_conflicts()?,
+        plan_out: matches.get_one::<PathBuf>("plan_out").cloned(),
+        plan_in: matches.get_one::<PathBuf>("plan_in").cloned(),
+        jobs: matches.get_one::<usize>("jobs").copied().unwrap_or(4),
+        server: None,
+    };
+
     let locations: Option<Vec<String>> = matches
         .get_many::<String>("locations")
         .map(|vals| vals.cloned().collect());
-    let location_regex = Regex::new(r"^(ftp|zip|folder):.+$")?;
+    let location_regex = location_regex(&Registry::default())?;
 
     match locations {
         Some(locations) => {
@@ -80,17 +191,63 @@ pub fn parse_args() -> Result<Option<Vec<String>>> {
                 }
             }
             append_to_cfg(&locations)?;
-            Ok(Some(locations))
         }
         None => {
             println!("Running...");
-            Ok(None)
         }
     }
+    Ok(options)
+}
+
+// Reading the conflict strategies of location pairs from their CFG file
+// Pairs are keyed by how their locations are displayed (without passwords)
+fn retrieve_pair_conflicts() -> Result<HashMap<(String, String), ConflictStrategy>> {
+    let content = fs::read_to_string(conflicts_file()?)?;
+    let registry = Registry::default();
+    let mut pair_conflicts = HashMap::new();
+
+    for (index, line) in content.lines().enumerate() {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.is_empty() || parts[0].starts_with('#') {
+            continue;
+        }
+        let pair_conflict = match parts[..] {
+            [strategy, source, other] => ConflictStrategy::from_str(strategy)
+                .map_err(anyhow::Error::from)
+                .and_then(|strategy| {
+                    let pair = (
+                        registry.open(source)?.to_string(),
+                        registry.open(other)?.to_string(),
+                    );
+                    Ok((pair, strategy))
+                }),
+            _ => Err(anyhow::anyhow!("expected <STRATEGY> <SOURCE> <OTHER>")),
+        };
+        match pair_conflict {
+            Ok((pair, strategy)) => {
+                pair_conflicts.insert(pair, strategy);
+            }
+            Err(e) => println!("Line {} of {}: {}", index, conflicts_file()?, e),
+        }
+    }
+    Ok(pair_conflicts)
+}
+
+// <LOCATION_TYPE>:<Path_in_location> where the type is any registered backend
+fn location_regex(registry: &Registry) -> Result<Regex> {
+    Ok(Regex::new(&format!(
+        "^({}):.+$",
+        registry
+            .schemes()
+            .iter()
+            .map(|scheme| regex::escape(scheme))
+            .collect::<Vec<_>>()
+            .join("|")
+    ))?)
 }
 
 // Reading from the CFG file for running
-pub fn retrieve_locations() -> Result<Vec<LocTypes>> {
+pub fn retrieve_locations() -> Result<Vec<Box<dyn Location>>> {
     let cfg_file = config_file()?;
     if !Path::new(&cfg_file).exists() {
         File::create(&cfg_file)?;
@@ -100,39 +257,21 @@ pub fn retrieve_locations() -> Result<Vec<LocTypes>> {
         return Err(ArgErrors::EmptyCfg.into());
     }
     let mut locations = Vec::new();
-    let location_regex = Regex::new(r"^(ftp|zip|folder):.+$")?;
+    let registry = Registry::default();
+    let location_regex = location_regex(&registry)?;
 
     for (index, line) in content.lines().enumerate() {
         if !location_regex.is_match(line) {
-            if line != "\n" {
+            if !line.trim().is_empty() {
                 println!(
                 "Line {}: Could not parse location and will not be taken into consideration : {}",
                 index, line
             );
             }
         } else {
-            let type_path = line.split_once(":").unwrap();
-            match type_path.0 {
-                "ftp" => {
-                    let (user_pass, url_path) = type_path.1.split_once("@").unwrap_or_default();
-                    let user_pass = user_pass.split_once(":").unwrap_or_default();
-                    let url_path = url_path.split_once("/").unwrap_or_default();
-                    locations.push(LocTypes::Ftp(
-                        user_pass.0.to_string(),
-                        user_pass.1.to_string(),
-                        url_path.0.to_string(),
-                        url_path.1.to_string(),
-                    ));
-                }
-                "folder" => {
-                    locations.push(LocTypes::Folder(type_path.1.to_string()));
-                }
-                "zip" => {
-                    locations.push(LocTypes::Zip(type_path.1.to_string()));
-                }
-                _ => {
-                    println!("Line {}: file type unrecognized: {}", index, line);
-                }
+            match registry.open(line) {
+                Ok(location) => locations.push(location),
+                Err(e) => println!("Line {}: {}", index, e),
             }
         }
     }
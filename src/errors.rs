@@ -7,6 +7,19 @@ pub enum ArgErrors {
     InvalidLocation(String),
     #[error("Config file empty, use --help to give --set arg some location paths, or modify ~/.adv_rsync/cfg/locations.cfg")]
     EmptyCfg,
+    #[error(
+        "Invalid conflict strategy: {0} (expected newest-wins, source-wins, keep-both or fail)"
+    )]
+    InvalidConflictStrategy(String),
+}
+
+// Errors of a synchronization
+#[derive(Debug, Error)]
+pub enum SyncErrors {
+    #[error("Unresolved conflicts between {0} and {1}: {2}")]
+    Conflicts(String, String, String),
+    #[error("Copies between {0} and {1} did not arrive whole (completed on the next sync): {2}")]
+    IncompleteCopies(String, String, String),
 }
 
 // Errors for file operations
@@ -7,9 +7,12 @@ pub mod sync;
 pub mod utils;
 
 fn main() -> Result<()> {
-    cli_parsing::parse_args()?;
+    let options = cli_parsing::parse_args()?;
+    if let Some(root) = &options.server {
+        return sync::serve(root);
+    }
     let locations = cli_parsing::retrieve_locations()?;
-    let mut adv_rsync = Synchronizer::new(locations, None);
+    let mut adv_rsync = Synchronizer::new(locations, options);
     adv_rsync.sync()?;
     Ok(())
 }
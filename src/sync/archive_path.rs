@@ -0,0 +1,151 @@
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use crate::sync::folder::write_part;
+use crate::sync::state::unix_secs;
+use crate::sync::{tar_archive, zip_archive};
+
+// Where an archive location points, written with '!' between the archives it goes through:
+//   /a/b.zip                      the whole archive
+//   /a/b.zip!/inner/dir           a folder inside the archive
+//   /a/b.tar.gz!/inner/c.zip!/dir  a folder inside an archive stored in another archive
+// Only '!' followed by '/' (or ending the path) separates archives, so "foo.zip.d" or "a!b" are
+// plain names
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchivePath {
+    outer: PathBuf,      // archive on the local file system
+    nested: Vec<String>, // archives stored inside the previous one, from the outer one inwards
+    subdir: String,      // folder of the innermost archive the location is rooted at ("" for all)
+}
+
+impl ArchivePath {
+    pub fn parse(spec: &str) -> Self {
+        // A trailing '!' points at the root of the last archive
+        let (spec, root) = match spec.strip_suffix('!') {
+            Some(spec) => (spec, true),
+            None => (spec, false),
+        };
+        let mut parts = spec.split("!/");
+        let outer = PathBuf::from(parts.next().unwrap_or_default());
+        let mut nested: Vec<String> = parts
+            .map(|part| part.trim_matches('/').to_string())
+            .collect();
+        let subdir = if root {
+            String::new()
+        } else {
+            nested.pop().unwrap_or_default()
+        };
+        Self {
+            outer,
+            nested,
+            subdir,
+        }
+    }
+
+    pub fn is_nested(&self) -> bool {
+        !self.nested.is_empty()
+    }
+
+    // The archive file itself, only a writable location has to know it (nested ones are read-only)
+    pub fn outer(&self) -> &Path {
+        &self.outer
+    }
+
+    // Local file holding the innermost archive
+    // Nested archives are extracted into ~/.adv_rsync/cache, once for every version (mtime and
+    // size) of the outer one; extracting a new version removes the ones of the older versions
+    pub fn file(&self) -> Result<PathBuf> {
+        let mut file = self.outer.clone();
+        if self.nested.is_empty() {
+            return Ok(file);
+        }
+        let metadata = fs::metadata(&self.outer)?;
+        let version = format!("{}-{}", unix_secs(metadata.modified()?), metadata.len());
+        let cache_dir = dirs_next::home_dir()
+            .unwrap_or_default()
+            .join(".adv_rsync/cache");
+        fs::create_dir_all(&cache_dir)?;
+        let mut key = self.outer.display().to_string();
+        for name in &self.nested {
+            key = format!("{}\n{}", key, name);
+            let id = hex::encode(Sha256::digest(&key));
+            let cached = cache_dir.join(format!("{}-{}", id, version));
+            if !cached.exists() {
+                remove_stale(&cache_dir, &id)?;
+                write_part(&cached, |out| extract(&file, name, out))?;
+            }
+            file = cached;
+        }
+        Ok(file)
+    }
+
+    // Name inside the innermost archive of a path relative to the location
+    pub fn entry(&self, rel_path: &str) -> String {
+        if self.subdir.is_empty() {
+            rel_path.to_string()
+        } else {
+            format!("{}/{}", self.subdir, rel_path)
+        }
+    }
+
+    // Path relative to the location of an entry name (without trailing '/'), None when the entry
+    // is outside of the folder the location is rooted at (or is that folder)
+    pub fn rel_path<'a>(&self, entry: &'a str) -> Option<&'a str> {
+        let rel_path = if self.subdir.is_empty() {
+            entry
+        } else {
+            entry.strip_prefix(&self.subdir)?.strip_prefix('/')?
+        };
+        (!rel_path.is_empty()).then_some(rel_path)
+    }
+}
+
+impl fmt::Display for ArchivePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.outer.display())?;
+        for name in &self.nested {
+            write!(f, "!/{}", name)?;
+        }
+        if self.is_nested() || !self.subdir.is_empty() {
+            write!(f, "!/{}", self.subdir)?;
+        }
+        Ok(())
+    }
+}
+
+// Removes what was extracted from older versions of an archive (their names start with it's id)
+fn remove_stale(cache_dir: &Path, id: &str) -> Result<()> {
+    let prefix = format!("{}-", id);
+    for entry in fs::read_dir(cache_dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with(&prefix) {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+// Copies an entry of an archive (ZIP or tar, found from it's first bytes) into out
+fn extract(path: &Path, name: &str, out: &mut File) -> Result<()> {
+    let mut start = [0; 4];
+    let read = File::open(path)?.read(&mut start)?;
+    if start[..read].starts_with(b"PK") {
+        let mut archive = zip_archive::archive(path)?;
+        let mut entry = archive.by_name(name)?;
+        io::copy(&mut entry, out)?;
+        return Ok(());
+    }
+    let mut archive = tar_archive::archive(path)?;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if tar_archive::entry_name(&entry.path()?) == name {
+            io::copy(&mut entry, out)?;
+            return Ok(());
+        }
+    }
+    Err(anyhow!("{} was not found in {}", name, path.display()))
+}
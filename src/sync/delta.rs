@@ -0,0 +1,337 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+// rsync algorithm: the old file is cut in blocks (weak rolling checksum + strong hash of each),
+// the new file is scanned byte by byte for those blocks, and rebuilt from the blocks it shares
+// with the old file plus the bytes in between
+
+// Smaller files are copied in full, the signature would cost more than it saves
+pub const MIN_SIZE: u64 = 64 * 1024;
+
+// Bytes not found in the old file are sent in pieces of at most this size
+const MAX_LITERAL: usize = 64 * 1024;
+
+// Block size grows with the file (square root, like rsync) so the signature stays small
+fn block_size(len: u64) -> usize {
+    ((len as f64).sqrt() as usize).clamp(1024, 128 * 1024)
+}
+
+// Checksum of a window that can be moved one byte forward in O(1)
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(block: &[u8]) -> Self {
+        let len = block.len() as u32;
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+        for (i, &byte) in block.iter().enumerate() {
+            a = a.wrapping_add(byte as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(byte as u32));
+        }
+        Self { a, b, len }
+    }
+
+    // Drops the first byte of the window and appends the next one
+    fn roll(&mut self, out: u8, inp: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(inp as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.b << 16) | (self.a & 0xffff)
+    }
+}
+
+fn strong_hash(block: &[u8]) -> [u8; 32] {
+    Sha256::digest(block).into()
+}
+
+// Weak checksum -> (index, strong hash) of every full block of the old file
+// It is all the side with the new content needs to know about the old file (ssh: sends it over)
+#[derive(Serialize, Deserialize)]
+pub struct Signature {
+    block_size: usize,
+    blocks: HashMap<u32, Vec<(usize, [u8; 32])>>,
+}
+
+impl Signature {
+    fn new(old: &mut dyn Read, block_size: usize) -> Result<Self> {
+        let mut blocks: HashMap<u32, Vec<(usize, [u8; 32])>> = HashMap::new();
+        let mut block = vec![0; block_size];
+        let mut index = 0;
+        loop {
+            let read = read_full(old, &mut block)?;
+            // A short last block can't be matched by a full window
+            if read < block_size {
+                break;
+            }
+            blocks
+                .entry(Rolling::new(&block).digest())
+                .or_default()
+                .push((index, strong_hash(&block)));
+            index += 1;
+        }
+        Ok(Self { block_size, blocks })
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn find(&self, weak: u32, window: &[u8]) -> Option<usize> {
+        let candidates = self.blocks.get(&weak)?;
+        let strong = strong_hash(window);
+        candidates
+            .iter()
+            .find(|(_, hash)| *hash == strong)
+            .map(|(index, _)| *index)
+    }
+}
+
+pub enum Op<'a> {
+    Copy(usize),    // block of the old file
+    Data(&'a [u8]), // bytes that are not in the old file
+}
+
+// Scans the new content for blocks of the old file, memory stays bounded by a few blocks
+pub fn diff(
+    signature: &Signature,
+    new: &mut dyn Read,
+    mut emit: impl FnMut(Op) -> Result<()>,
+) -> Result<()> {
+    let block_size = signature.block_size;
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut start = 0; // window start
+    let mut literal_start = 0; // first byte not emitted yet
+    let mut rolling: Option<Rolling> = None;
+    let mut eof = false;
+
+    loop {
+        // One byte more than the window so it can be rolled
+        while !eof && buffer.len() < start + block_size + 1 {
+            let len = buffer.len();
+            buffer.resize(len + block_size.max(MAX_LITERAL), 0);
+            let read = new.read(&mut buffer[len..])?;
+            buffer.truncate(len + read);
+            eof = read == 0;
+        }
+        if buffer.len() < start + block_size {
+            break;
+        }
+
+        let window = &buffer[start..start + block_size];
+        let weak = rolling.get_or_insert_with(|| Rolling::new(window)).digest();
+        if let Some(index) = signature.find(weak, window) {
+            if literal_start < start {
+                emit(Op::Data(&buffer[literal_start..start]))?;
+            }
+            emit(Op::Copy(index))?;
+            start += block_size;
+            literal_start = start;
+            rolling = None;
+        } else {
+            if buffer.len() == start + block_size {
+                // Nothing left to roll into
+                break;
+            }
+            if let Some(rolling) = rolling.as_mut() {
+                rolling.roll(buffer[start], buffer[start + block_size]);
+            }
+            start += 1;
+            if start - literal_start >= MAX_LITERAL {
+                emit(Op::Data(&buffer[literal_start..start]))?;
+                literal_start = start;
+            }
+        }
+
+        // Forget what was already emitted
+        if literal_start >= MAX_LITERAL {
+            buffer.drain(..literal_start);
+            start -= literal_start;
+            literal_start = 0;
+        }
+    }
+    if literal_start < buffer.len() {
+        emit(Op::Data(&buffer[literal_start..]))?;
+    }
+    Ok(())
+}
+
+pub fn signature(old_path: &Path) -> Result<Signature> {
+    let block_size = block_size(old_path.metadata()?.len());
+    Signature::new(&mut File::open(old_path)?, block_size)
+}
+
+// Writes the new content into out, taking every block it shares with the old file from the old file
+// Returns how many bytes were reused
+pub fn patch(old_path: &Path, new: &mut dyn Read, out: &mut dyn Write) -> Result<u64> {
+    let signature = signature(old_path)?;
+    let block_size = signature.block_size;
+
+    let mut old = File::open(old_path)?;
+    let mut reused = 0;
+    diff(&signature, new, |op| {
+        match op {
+            Op::Copy(index) => reused += copy_block(&mut old, block_size, index, out)?,
+            Op::Data(bytes) => out.write_all(bytes)?,
+        }
+        Ok(())
+    })?;
+    Ok(reused)
+}
+
+// Copies a block of the old file into out, returns how many bytes it copied
+pub fn copy_block(
+    old: &mut File,
+    block_size: usize,
+    index: usize,
+    out: &mut dyn Write,
+) -> Result<u64> {
+    old.seek(SeekFrom::Start((index * block_size) as u64))?;
+    Ok(io::copy(&mut old.take(block_size as u64), out)?)
+}
+
+// Fills the buffer unless the end of the stream comes first
+fn read_full(reader: &mut dyn Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..])? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    // The same bytes on every run, but no repeated blocks
+    fn random(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 33) as u8
+            })
+            .collect()
+    }
+
+    // Rebuilds new from old with patch, returns how many bytes came from old
+    fn round_trip(name: &str, old: &[u8], new: &[u8]) -> u64 {
+        let path = env::temp_dir().join(format!("adv_rsync-delta-{}-{}", name, process::id()));
+        fs::write(&path, old).unwrap();
+        let mut out = Vec::new();
+        let reused = patch(&path, &mut &new[..], &mut out);
+        fs::remove_file(&path).unwrap();
+        assert!(out == new, "{}: rebuilt content differs", name);
+        reused.unwrap()
+    }
+
+    #[test]
+    fn patch_rebuilds_edited_files() {
+        let old = random(300_000, 1);
+        let block = block_size(old.len() as u64) as u64;
+        let full_blocks = old.len() as u64 / block * block;
+
+        let inserted = [&old[..100_000], &random(777, 2), &old[100_000..]].concat();
+        let deleted = [&old[..100_000], &old[105_000..]].concat();
+        let mut changed = old.clone();
+        changed[150_000..150_010].copy_from_slice(b"0123456789");
+        let appended = [&old[..], &random(5000, 3)].concat();
+
+        assert_eq!(round_trip("same", &old, &old), full_blocks);
+        assert_eq!(round_trip("appended", &old, &appended), full_blocks);
+        // Only the blocks around the edit are sent
+        for (name, new) in [
+            ("inserted", inserted),
+            ("deleted", deleted),
+            ("changed", changed),
+        ] {
+            let reused = round_trip(name, &old, &new);
+            assert!(
+                reused >= full_blocks - 2 * block - 5000,
+                "{}: {}",
+                name,
+                reused
+            );
+        }
+        // Nothing in common
+        assert_eq!(round_trip("unrelated", &old, &random(200_000, 4)), 0);
+    }
+
+    #[test]
+    fn patch_rebuilds_small_and_empty_files() {
+        let old = random(MIN_SIZE as usize / 2, 5);
+        let mut new = old.clone();
+        new[10_000] ^= 1;
+        assert!(round_trip("small", &old, &new) > 0);
+        assert_eq!(
+            round_trip("shorter than a block", &old[..100], &old[..50]),
+            0
+        );
+        assert_eq!(round_trip("empty old", &[], &old), 0);
+        assert_eq!(round_trip("empty new", &old, &[]), 0);
+    }
+
+    #[test]
+    fn block_size_is_clamped() {
+        assert_eq!(block_size(0), 1024);
+        assert_eq!(block_size(MIN_SIZE), 1024);
+        assert_eq!(block_size(4 << 20), 2048);
+        assert_eq!(block_size(16 << 30), 128 * 1024);
+        assert_eq!(block_size(u64::MAX), 128 * 1024);
+    }
+
+    #[test]
+    fn literals_are_sent_in_bounded_pieces() {
+        let signature = Signature::new(&mut &random(10_000, 6)[..], 1024).unwrap();
+        let new = random(300_000, 7);
+        let mut sent = 0;
+        diff(&signature, &mut &new[..], |op| {
+            match op {
+                Op::Copy(index) => panic!("block {} copied", index),
+                Op::Data(bytes) => {
+                    assert!(bytes.len() <= MAX_LITERAL);
+                    sent += bytes.len();
+                }
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(sent, new.len());
+    }
+
+    #[test]
+    fn rolled_checksum_matches_a_new_one_at_every_offset() {
+        let mut data = random(5000, 8);
+        // Big bytes make the sums wrap
+        data[2000..2500].fill(0xff);
+        for window in [1, 16, 1024] {
+            let mut rolling = Rolling::new(&data[..window]);
+            for start in 1..=data.len() - window {
+                rolling.roll(data[start - 1], data[start + window - 1]);
+                let fresh = Rolling::new(&data[start..start + window]);
+                assert_eq!(rolling.digest(), fresh.digest(), "{} at {}", window, start);
+            }
+        }
+    }
+}
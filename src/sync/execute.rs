@@ -0,0 +1,282 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::sync::location::{FileEntry, Location};
+use crate::sync::plan::{Existing, Operation, PairPlan};
+use crate::sync::state::{unix_secs, HashReader, PartialFile};
+
+// How many times a write that was cut off is resumed before the copy fails
+const RESUME_ATTEMPTS: usize = 3;
+
+// Applies the operations of a plan to the locations they name
+// Deletes, conflict copies and folders go first and in order, then files are copied in parallel
+pub struct Executor<'a> {
+    locations: HashMap<String, &'a dyn Location>,
+    jobs: usize,                                  // files copied at the same time
+    partial: Mutex<HashMap<String, PartialFile>>, // what failed copies left in their target
+}
+
+impl<'a> Executor<'a> {
+    pub fn new(locations: &[&'a dyn Location], jobs: usize) -> Self {
+        Self {
+            locations: locations
+                .iter()
+                .map(|location| (location.to_string(), *location))
+                .collect(),
+            jobs: jobs.max(1),
+            partial: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Partial files left by the copies that failed, so the next sync can complete them
+    pub fn partial_files(&self) -> HashMap<String, PartialFile> {
+        std::mem::take(&mut self.partial.lock().unwrap())
+    }
+
+    // Returns the hash of every copied file, so they can be stored in the sync state
+    pub fn execute(&self, plan: &PairPlan) -> Result<HashMap<String, String>> {
+        let mut files = Vec::new();
+        for operation in &plan.operations {
+            match operation {
+                Operation::Copy {
+                    source,
+                    target,
+                    rel_path,
+                    entry,
+                    existing,
+                    ..
+                } if !entry.is_dir() => files.push((source, target, rel_path, entry, existing)),
+                Operation::Copy {
+                    source,
+                    target,
+                    rel_path,
+                    entry,
+                    ..
+                } => {
+                    copy_file(rel_path, entry, self.get(source)?, self.get(target)?, None)?;
+                }
+                Operation::Delete {
+                    location, rel_path, ..
+                } => delete_file(rel_path, self.get(location)?)?,
+                Operation::KeepConflict {
+                    location,
+                    other,
+                    rel_path,
+                    conflict_path,
+                    entry,
+                    ..
+                } => {
+                    let location = self.get(location)?;
+                    if location.read_only() {
+                        // Nothing is lost, the file stays in the read-only location
+                        continue;
+                    }
+                    location.rename(rel_path, conflict_path)?;
+                    println!(
+                        "Conflict: kept {}/{} as {}",
+                        location, rel_path, conflict_path
+                    );
+                    copy_file(conflict_path, entry, location, self.get(other)?, None)?;
+                }
+                Operation::Conflict { rel_path, .. } => println!(
+                    "Conflict: {} was changed in both {} and {}",
+                    rel_path, plan.loc_a, plan.loc_b
+                ),
+            }
+        }
+
+        // Every worker takes the next file until there is none left (or one of them failed)
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let copied = Mutex::new(HashMap::new());
+        let error = Mutex::new(None);
+        thread::scope(|scope| {
+            for _ in 0..self.jobs.min(files.len()) {
+                scope.spawn(|| {
+                    while !failed.load(Ordering::Relaxed) {
+                        let Some((source, target, rel_path, entry, existing)) =
+                            files.get(next.fetch_add(1, Ordering::Relaxed))
+                        else {
+                            break;
+                        };
+                        let copy = self.get(source).and_then(|source| {
+                            copy_file(
+                                rel_path,
+                                entry,
+                                source,
+                                self.get(target)?,
+                                existing.as_ref(),
+                            )
+                        });
+                        match copy {
+                            Ok(Some(hash)) => {
+                                copied.lock().unwrap().insert(rel_path.to_string(), hash);
+                            }
+                            Ok(None) => {}
+                            Err(e) => {
+                                let left = self
+                                    .get(target)
+                                    .ok()
+                                    .and_then(|target| {
+                                        leftover(rel_path, target, existing.as_ref())
+                                    })
+                                    .filter(|&size| entry.size.is_some_and(|total| size < total));
+                                if let Some(size) = left {
+                                    self.partial.lock().unwrap().insert(
+                                        rel_path.to_string(),
+                                        PartialFile {
+                                            in_a: target.as_str() == plan.loc_a,
+                                            size,
+                                            source_mtime: unix_secs(entry.mtime),
+                                            source_size: entry.size.unwrap_or(0),
+                                        },
+                                    );
+                                }
+                                failed.store(true, Ordering::Relaxed);
+                                error.lock().unwrap().get_or_insert(e);
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        if let Some(e) = error.into_inner().unwrap() {
+            return Err(e);
+        }
+        Ok(copied.into_inner().unwrap())
+    }
+
+    fn get(&self, name: &str) -> Result<&'a dyn Location> {
+        self.locations
+            .get(name)
+            .copied()
+            .ok_or_else(|| anyhow!("The plan uses a location that is not configured: {}", name))
+    }
+}
+
+// Function that copies a file (or creates a folder) found at rel_path into the target location
+// Returns the hash of the copied content so it can be stored in the sync state
+fn copy_file(
+    rel_path: &str,
+    entry: &FileEntry,
+    source: &dyn Location,
+    target: &dyn Location,
+    existing: Option<&Existing>,
+) -> Result<Option<String>> {
+    if target.read_only() {
+        // Read-only locations (ZIP files) are only used as sources
+        return Ok(None);
+    }
+    if entry.is_dir() {
+        target.mkdir(rel_path)?;
+        return Ok(None);
+    }
+
+    let mut reader = HashReader::new(source.open_read(rel_path)?);
+    let mut how = String::new();
+    let mut done = false;
+    // Whether the target still has to be given the time of the source
+    let mut set_mtime = true;
+    if let Some(existing) = existing {
+        if let Some(reused) = target.write_delta(rel_path, &mut reader, entry.mtime)? {
+            how = format!(" (delta, {} bytes reused)", reused);
+            done = true;
+            set_mtime = false;
+        } else if existing.partial || existing.synced_hash.is_some() {
+            // A file that only grew (like a log file) is appended to when the target can't do
+            // deltas, and so is the start of a file left by an interrupted copy
+            if existing.size > 0 && entry.size > Some(existing.size) {
+                let mut prefix = HashReader::new((&mut reader).take(existing.size));
+                io::copy(&mut prefix, &mut io::sink())?;
+                let prefix_hash = Some(prefix.hash());
+                if (existing.partial || prefix_hash == existing.synced_hash)
+                    && target.append(rel_path, existing.size, &mut reader)?
+                {
+                    let verb = if existing.partial {
+                        "resumed"
+                    } else {
+                        "appended"
+                    };
+                    how = format!(" ({} after {} bytes)", verb, existing.size);
+                    done = true;
+                } else {
+                    // A pooled remote (ftp:) may only have one session for the source, the old
+                    // reader gives it back before the file is opened again
+                    drop(reader);
+                    reader = HashReader::new(source.open_read(rel_path)?);
+                }
+            }
+        }
+    }
+    if !done {
+        match target.write_with_mtime(rel_path, &mut reader, entry.mtime) {
+            Ok(()) => set_mtime = false,
+            Err(e) => {
+                drop(reader);
+                let (resumed, offset) = resume_copy(rel_path, source, target, existing, e)?;
+                reader = resumed;
+                how = format!(" (resumed after {} bytes)", offset);
+            }
+        }
+    }
+    if set_mtime {
+        target.set_mtime(rel_path, entry.mtime)?;
+    }
+    println!(
+        "Synced{}: {}/{} -> {}/{}",
+        how, source, rel_path, target, rel_path
+    );
+
+    Ok(Some(reader.hash()))
+}
+
+// Completes a write that was cut off by appending to the part that arrived, with a new reader of
+// the source every time, as long as the target can append
+fn resume_copy<'a>(
+    rel_path: &str,
+    source: &'a dyn Location,
+    target: &dyn Location,
+    existing: Option<&Existing>,
+    mut error: anyhow::Error,
+) -> Result<(HashReader<Box<dyn Read + 'a>>, u64)> {
+    for _ in 0..RESUME_ATTEMPTS {
+        let Some(offset) = leftover(rel_path, target, existing) else {
+            break;
+        };
+        let mut reader = HashReader::new(source.open_read(rel_path)?);
+        if io::copy(&mut (&mut reader).take(offset), &mut io::sink())? < offset {
+            break;
+        }
+        println!(
+            "Resuming the copy of {}/{} after {} bytes: {}",
+            source, rel_path, offset, error
+        );
+        match target.append(rel_path, offset, &mut reader) {
+            Ok(true) => return Ok((reader, offset)),
+            Ok(false) => break,
+            Err(e) => error = e,
+        }
+    }
+    Err(error)
+}
+
+// Size of what a write that was cut off left in the target, when it can only be the start of the
+// new content: the target had no file, or the one it had was rewritten (it's size changed)
+fn leftover(rel_path: &str, target: &dyn Location, existing: Option<&Existing>) -> Option<u64> {
+    let size = target.stat(rel_path).ok()??.size?;
+    let rewritten = existing.is_none_or(|existing| existing.partial || existing.size != size);
+    (size > 0 && rewritten).then_some(size)
+}
+
+// Function that deletes a file that was deleted from the other location of the pair
+fn delete_file(rel_path: &str, location: &dyn Location) -> Result<()> {
+    if location.read_only() {
+        // Nothing can be deleted from a read-only location
+        return Ok(());
+    }
+    location.remove(rel_path)
+}
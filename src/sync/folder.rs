@@ -0,0 +1,184 @@
+use anyhow::Result;
+use filetime::FileTime;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use walkdir::WalkDir;
+
+use crate::sync::delta;
+use crate::sync::location::{FileEntry, Listing, Location};
+use crate::utils::{human_readable_time, relative_path};
+
+// Files are written next to their final path with this suffix and renamed once complete,
+// so an interrupted transfer never leaves a truncated file behind
+pub const PART_SUFFIX: &str = ".adv_rsync-part";
+
+// Local directory, fully synchronized and watched for changes
+pub struct FolderLocation {
+    root: PathBuf,
+    quiet: bool, // nothing is printed
+}
+
+impl FolderLocation {
+    // folder:/path/to/folder
+    pub fn open(_scheme: &str, spec: &str) -> Result<Box<dyn Location>> {
+        Ok(Box::new(Self {
+            root: PathBuf::from(spec),
+            quiet: false,
+        }))
+    }
+
+    // Folder served to an ssh: location, whose channel is our stdout
+    pub fn quiet(root: PathBuf) -> Self {
+        Self { root, quiet: true }
+    }
+
+    pub fn path(&self, rel_path: &str) -> PathBuf {
+        self.root.join(rel_path)
+    }
+}
+
+impl Location for FolderLocation {
+    // Lists the files of the dir in a HashMap that is convenient for searching in O(1)
+    fn list(&self) -> Result<Listing> {
+        let mut files = Listing::new();
+        for entry in WalkDir::new(&self.root).min_depth(1) {
+            let entry = entry?;
+            let entry_path = entry.path().to_string_lossy().to_string();
+            if entry_path.contains(".DS") || entry_path.ends_with(PART_SUFFIX) {
+                continue;
+            }
+            let rel_path = relative_path(&self.root.to_string_lossy(), &entry_path).unwrap();
+            files.insert(rel_path, file_entry(&entry.metadata()?)?);
+        }
+        Ok(files)
+    }
+
+    fn stat(&self, rel_path: &str) -> Result<Option<FileEntry>> {
+        match fs::metadata(self.path(rel_path)) {
+            Ok(metadata) => Ok(Some(file_entry(&metadata)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn open_read(&self, rel_path: &str) -> Result<Box<dyn Read + '_>> {
+        Ok(Box::new(File::open(self.path(rel_path))?))
+    }
+
+    // Creates the file and all of it's parents if necessary
+    fn write(&self, rel_path: &str, content: &mut dyn Read) -> Result<()> {
+        let path = self.path(rel_path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_part(&path, |file| {
+            io::copy(content, file)?;
+            Ok(())
+        })
+    }
+
+    fn write_delta(
+        &self,
+        rel_path: &str,
+        content: &mut dyn Read,
+        mtime: SystemTime,
+    ) -> Result<Option<u64>> {
+        let path = self.path(rel_path);
+        match fs::metadata(&path) {
+            Ok(metadata) if metadata.is_file() && metadata.len() >= delta::MIN_SIZE => {}
+            _ => return Ok(None),
+        }
+        let mut reused = 0;
+        write_part(&path, |file| {
+            let mut writer = BufWriter::new(file);
+            reused = delta::patch(&path, content, &mut writer)?;
+            writer.flush()?;
+            Ok(())
+        })?;
+        self.set_mtime(rel_path, mtime)?;
+        Ok(Some(reused))
+    }
+
+    fn mkdir(&self, rel_path: &str) -> Result<()> {
+        let path = self.path(rel_path);
+        fs::create_dir_all(&path)?;
+        if !self.quiet {
+            println!("Created: {:?}", path);
+        }
+        Ok(())
+    }
+
+    fn remove(&self, rel_path: &str) -> Result<()> {
+        let path = self.path(rel_path);
+
+        if path.is_dir() {
+            fs::remove_dir_all(&path)?;
+        } else if path.is_file() {
+            fs::remove_file(&path)?;
+        } else {
+            return Err(anyhow::anyhow!("No such file or directory"));
+        }
+        if !self.quiet {
+            println!("Deleted: {:?}", path);
+        }
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let to = self.path(to);
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(self.path(from), to)?;
+        Ok(())
+    }
+
+    fn set_mtime(&self, rel_path: &str, mtime: SystemTime) -> Result<()> {
+        let last_modif_time = FileTime::from_system_time(mtime);
+        filetime::set_file_times(self.path(rel_path), last_modif_time, last_modif_time)?;
+        Ok(())
+    }
+
+    fn watch_path(&self) -> Option<&Path> {
+        Some(&self.root)
+    }
+}
+
+impl fmt::Display for FolderLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "folder:{}", self.root.display())
+    }
+}
+
+// Writes a file through it's part file, which replaces it only once it is complete
+pub fn write_part(path: &Path, write: impl FnOnce(&mut File) -> Result<()>) -> Result<()> {
+    let mut part_path = path.as_os_str().to_owned();
+    part_path.push(PART_SUFFIX);
+    let mut file = File::create(&part_path)?;
+    let result = write(&mut file).and_then(|_| Ok(file.sync_all()?));
+    drop(file);
+    if let Err(e) = result {
+        let _ = fs::remove_file(&part_path);
+        return Err(e);
+    }
+    fs::rename(&part_path, path)?;
+    Ok(())
+}
+
+// (unix epoch - human readable time - size) of a local file
+fn file_entry(metadata: &fs::Metadata) -> Result<FileEntry> {
+    let modified_time = metadata.modified()?;
+    Ok(FileEntry {
+        mtime: modified_time,
+        modified: human_readable_time(modified_time),
+        size: if metadata.is_dir() {
+            None
+        } else {
+            Some(metadata.len())
+        },
+        version: None,
+    })
+}
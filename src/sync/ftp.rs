@@ -1,257 +1,1286 @@
-use crate::sync::LocTypes;
-use anyhow::Result;
-use chrono::{Datelike, NaiveDateTime};
-use ftp::FtpStream;
-use std::collections::HashMap;
-use std::io::Cursor;
-use std::path::Path;
-use std::time::SystemTime;
-
-use super::CreateType;
-
-// Function that connects to the FTP server and performs a GET for all the files in the given location
-// and stores them exactly as the system files
-pub fn connect_to_ftp(
-    user: &str,
-    password: &str,
-    url: &str,
-    path: &str,
-) -> Result<HashMap<String, (LocTypes, SystemTime, String)>> {
-    let mut ftp_stream = FtpStream::connect(format!("{}:21", url))?;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{self, Read};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use suppaftp::types::{FileType, Mode};
+use suppaftp::{
+    FtpError, ImplFtpStream, RustlsConnector, RustlsFtpStream, Status, TlsStream, TransferStream,
+};
+
+use crate::sync::location::{FileEntry, Listing, Location};
+use crate::sync::tls;
+
+// Sessions open at the same time on a location unless ?max_sessions= says otherwise
+const DEFAULT_MAX_SESSIONS: usize = 4;
+
+// Idle sessions are checked with NOOP before being reused after this long, servers close quiet
+// control connections (often after a few minutes)
+const KEEPALIVE: Duration = Duration::from_secs(15);
 
-    ftp_stream.login(user, password)?;
-    ftp_stream.cwd(path)?;
+const DEFAULT_PORT: u16 = 21;
 
-    let mut files: HashMap<String, (LocTypes, SystemTime, String)> = HashMap::new();
-    recursive_list(
-        user,
-        password,
-        url,
-        path.to_string(),
-        "".to_string(),
-        &mut ftp_stream,
-        &mut files,
-    )?;
+// How many times a broken download is resumed before the copy fails
+const RESUME_ATTEMPTS: usize = 3;
 
-    ftp_stream.quit()?;
+// Connecting, and waiting for a reply or for data, gives up after this long unless ?timeout= says
+// otherwise
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(120);
 
-    Ok(files)
+// Connection options of a FTP location, given after the path: ftps:user:pass@URL/path?ca=..&pin=..
+#[derive(Debug, Default, Eq, PartialEq, Hash, Clone)]
+pub struct FtpOptions {
+    pub secure: bool,                // ftps: negotiate AUTH TLS before login
+    pub ca_file: Option<String>,     // PEM bundle of trusted CAs instead of the bundled roots
+    pub pin: Option<String>,         // SHA-256 fingerprint of the server certificate
+    pub max_sessions: Option<usize>, // sessions open at the same time (DEFAULT_MAX_SESSIONS)
+    pub mode: DataMode,
+    pub timeout: Option<Duration>, // connect and read/write timeout (DEFAULT_TIMEOUT)
 }
 
-// Helping the above function
-fn recursive_list(
-    user: &str,
-    pass: &str,
-    url: &str,
-    root_path: String,
-    rel_path: String,
-    ftp_stream: &mut FtpStream,
-    hash_map: &mut HashMap<String, (LocTypes, SystemTime, String)>,
-) -> Result<()> {
-    let entries = ftp_stream.list(None)?;
+// How data connections (listings and transfers) are opened
+#[derive(Debug, Default, Eq, PartialEq, Hash, Clone, Copy)]
+pub enum DataMode {
+    // The client connects to the server: EPSV when the server has it (or the host is IPv6),
+    // otherwise PASV
+    #[default]
+    Passive,
+    // The server connects back to the client with PORT, over IPv4 only (an IPv6 connection is
+    // refused, use Passive there)
+    Active,
+}
 
-    for entry in entries {
-        let (entry, system_time, human_read_systime) = extract_ftp_file_data(entry);
-        let abs_path = format!("{}/{}", root_path.clone(), entry);
-        let rel_path = format!("{}/{}", rel_path.clone(), entry)
-            .trim_start_matches("/")
-            .to_string();
-        if ftp_stream.cwd(&entry).is_ok() {
-            // Insert folder info before recursion
-            hash_map.insert(
-                rel_path.clone(),
-                (
-                    LocTypes::Ftp(
-                        user.to_string(),
-                        pass.to_string(),
-                        url.to_string(),
-                        abs_path.clone(),
-                    ),
-                    system_time,
-                    human_read_systime,
-                ),
-            );
-            // Recursive call to process subdirectory
-            {
-                // Create a shorter borrow scope for the recursive call
-                let new_root_path = format!("{}/{}", root_path, entry);
-                recursive_list(
-                    user,
-                    pass,
-                    url,
-                    new_root_path,
-                    rel_path,
-                    ftp_stream,
-                    hash_map,
-                )?;
+// FTP server (ftp:user:password@host[:port]/path), or FTPS with explicit TLS (ftps:...)
+// Logged in sessions are kept in a pool and reused, so syncing many small files does not cost a
+// connection and a login each
+pub struct FtpLocation {
+    user: String,
+    pass: String,
+    url: String, // host[:port] as given, [address]:port for IPv6
+    host: String,
+    port: u16,
+    path: String,
+    options: FtpOptions,
+    pool: Mutex<Pool>,
+    released: Condvar, // a session went back to the pool or was closed
+}
+
+// Logged in control connection, with the absolute path of the location on the server
+struct Session {
+    stream: RustlsFtpStream,
+    root: String,
+    features: Features,
+    last_used: Instant,
+}
+
+// Commands the server says it supports (FEAT), servers that don't know FEAT get none of them
+#[derive(Default)]
+struct Features {
+    mlst: bool, // MLST and MLSD: exact UTC times, sizes and types
+    mdtm: bool,
+    epsv: bool,
+    mfmt: bool,  // sets the modification time of a file
+    utime: bool, // SITE UTIME is never announced, it's tried until the server refuses it
+}
+
+// One entry of a folder listing, the time is in UTC (unknown for some servers)
+struct ListEntry {
+    name: String,
+    dir: bool,
+    link: bool, // symbolic link, it may point to a folder
+    size: u64,
+    mtime: Option<NaiveDateTime>,
+}
+
+// Sessions of a location: the idle ones, and how many are open (idle or in use)
+#[derive(Default)]
+struct Pool {
+    idle: Vec<Session>,
+    open: usize,
+}
+
+// A session taken from the pool, it goes back when dropped unless it broke
+struct PooledSession<'a> {
+    location: &'a FtpLocation,
+    session: Option<Session>,
+    reused: bool,
+    broken: bool,
+}
+
+impl FtpLocation {
+    // ftp(s):user:password@URL/path[?options]
+    pub fn open(scheme: &str, spec: &str) -> Result<Box<dyn Location>> {
+        let (user_pass, url_path) = spec.split_once("@").unwrap_or_default();
+        let user_pass = user_pass.split_once(":").unwrap_or_default();
+        let url_path = url_path.split_once("/").unwrap_or_default();
+        let (path, query) = url_path.1.split_once("?").unwrap_or((url_path.1, ""));
+        let (host, port) = host_port(url_path.0)?;
+        Ok(Box::new(Self {
+            user: user_pass.0.to_string(),
+            pass: user_pass.1.to_string(),
+            url: url_path.0.to_string(),
+            host,
+            port,
+            path: path.trim_end_matches('/').to_string(),
+            options: ftp_options(scheme == "ftps", query)?,
+            pool: Mutex::new(Pool::default()),
+            released: Condvar::new(),
+        }))
+    }
+
+    // Takes an idle session (checked with NOOP when it was quiet for a while), or opens a new
+    // one, or waits for one when max_sessions are in use
+    fn session(&self) -> Result<PooledSession<'_>> {
+        let max_sessions = self.options.max_sessions.unwrap_or(DEFAULT_MAX_SESSIONS);
+        let mut pool = self.pool.lock().unwrap();
+        loop {
+            if let Some(mut session) = pool.idle.pop() {
+                drop(pool);
+                if session.last_used.elapsed() < KEEPALIVE || session.stream.noop().is_ok() {
+                    return Ok(PooledSession::new(self, session, true));
+                }
+                // The server closed it, it's replaced by a new one
+                self.closed();
+                pool = self.pool.lock().unwrap();
+                continue;
+            }
+            if pool.open < max_sessions {
+                pool.open += 1;
+                drop(pool);
+                return match self.connect() {
+                    Ok(session) => Ok(PooledSession::new(self, session, false)),
+                    Err(e) => {
+                        self.closed();
+                        Err(e)
+                    }
+                };
+            }
+            pool = self.released.wait(pool).unwrap();
+        }
+    }
+
+    // Runs an operation on a session, once more on a new one when a reused session turns out to
+    // be dead (server restarted, idle timeout shorter than KEEPALIVE...)
+    fn with_session<T>(&self, mut operation: impl FnMut(&mut Session) -> Result<T>) -> Result<T> {
+        let mut session = self.session()?;
+        match session.run(&mut operation) {
+            Err(_) if session.broken && session.reused => {
+                drop(session);
+                // The other idle sessions are as old, they most likely died too
+                self.close_idle();
+                self.session()?.run(&mut operation)
+            }
+            result => result,
+        }
+    }
+
+    // Tries every address of the host (IPv6 and IPv4) until one answers
+    fn connect_control(&self, timeout: Duration) -> Result<RustlsFtpStream> {
+        let mut last_error = anyhow!("{} has no address", self.host);
+        for address in (self.host.as_str(), self.port).to_socket_addrs()? {
+            // The timeouts are set before the server's greeting is read
+            let connected = TcpStream::connect_timeout(&address, timeout)
+                .and_then(|stream| timeouts(stream, timeout))
+                .map_err(FtpError::ConnectionError)
+                .and_then(RustlsFtpStream::connect_with_stream);
+            match connected {
+                Ok(ftp_stream) => return Ok(ftp_stream),
+                Err(e) => last_error = anyhow!("connecting to {}: {}", address, e),
+            }
+        }
+        Err(last_error)
+    }
+
+    // PASV only knows IPv4 and often answers with the server's private address behind NAT, so
+    // passive data connections go to the address of the control connection: with EPSV when the
+    // server has it (always on IPv6), otherwise with the port from PASV
+    fn data_mode(
+        &self,
+        mut ftp_stream: RustlsFtpStream,
+        features: &Features,
+        timeout: Duration,
+    ) -> Result<RustlsFtpStream> {
+        let ipv6 = ftp_stream.get_ref().peer_addr()?.is_ipv6();
+        match self.options.mode {
+            // suppaftp listens for the server on IPv4 only, it would never connect back
+            DataMode::Active if ipv6 => {
+                return Err(anyhow!(
+                    "{}: active mode needs an IPv4 connection, use mode=passive",
+                    self
+                ))
+            }
+            DataMode::Active => ftp_stream = ftp_stream.active_mode(timeout),
+            DataMode::Passive if ipv6 || features.epsv => {
+                ftp_stream.set_mode(Mode::ExtendedPassive)
+            }
+            DataMode::Passive => {
+                ftp_stream.set_mode(Mode::Passive);
+                ftp_stream.set_passive_nat_workaround(true);
+            }
+        }
+        Ok(ftp_stream.passive_stream_builder(move |address| {
+            TcpStream::connect_timeout(&address, timeout)
+                .and_then(|stream| timeouts(stream, timeout))
+                .map_err(FtpError::ConnectionError)
+        }))
+    }
+
+    fn release(&self, mut session: Session) {
+        session.last_used = Instant::now();
+        self.pool.lock().unwrap().idle.push(session);
+        self.released.notify_one();
+    }
+
+    // A session was dropped (or could not be opened)
+    fn closed(&self) {
+        self.pool.lock().unwrap().open -= 1;
+        self.released.notify_one();
+    }
+
+    fn close_idle(&self) {
+        let mut pool = self.pool.lock().unwrap();
+        pool.open -= pool.idle.len();
+        pool.idle.clear();
+        self.released.notify_all();
+    }
+
+    // Opens the control connection, upgrades it to TLS for ftps locations and logs in
+    // Every transfer is binary so that files are copied byte for byte
+    // Returns the session with the absolute path of the location on the server
+    fn connect(&self) -> Result<Session> {
+        let timeout = self.options.timeout.unwrap_or(DEFAULT_TIMEOUT);
+        let mut ftp_stream = self.connect_control(timeout)?;
+        if self.options.secure {
+            let config =
+                tls::client_config(self.options.ca_file.as_deref(), self.options.pin.as_deref())?;
+            ftp_stream = ftp_stream.into_secure(RustlsConnector::from(config), &self.host)?;
+        }
+        ftp_stream.login(&self.user, &self.pass)?;
+        ftp_stream.transfer_type(FileType::Binary)?;
+        let features = match ftp_stream.feat() {
+            Ok(features) => {
+                let has = |name: &str| features.keys().any(|key| key.eq_ignore_ascii_case(name));
+                Features {
+                    mlst: has("MLST"),
+                    mdtm: has("MDTM"),
+                    epsv: has("EPSV"),
+                    mfmt: has("MFMT"),
+                    utime: true,
+                }
             }
-            ftp_stream.cdup()?; // cd ..
+            Err(_) => Features {
+                utime: true,
+                ..Default::default()
+            },
+        };
+        ftp_stream = self.data_mode(ftp_stream, &features, timeout)?;
+
+        // The path is relative to the login dir unless it starts with '/'
+        let root = if self.path.starts_with('/') {
+            self.path.clone()
         } else {
-            hash_map.insert(
-                rel_path.clone(),
-                (
-                    LocTypes::Ftp(
-                        user.to_string(),
-                        pass.to_string(),
-                        url.to_string(),
-                        abs_path.clone(),
-                    ),
-                    system_time,
-                    human_read_systime,
-                ),
-            );
+            let home = ftp_stream.pwd()?;
+            format!("{}/{}", home.trim_end_matches('/'), self.path)
+        };
+        Ok(Session {
+            stream: ftp_stream,
+            root: root.trim_end_matches('/').to_string(),
+            features,
+            last_used: Instant::now(),
+        })
+    }
+}
+
+impl<'a> PooledSession<'a> {
+    fn new(location: &'a FtpLocation, session: Session, reused: bool) -> Self {
+        Self {
+            location,
+            session: Some(session),
+            reused,
+            broken: false,
         }
     }
-    Ok(())
+
+    // Runs an operation on the session, a lost connection breaks it
+    fn run<T>(&mut self, operation: &mut impl FnMut(&mut Session) -> Result<T>) -> Result<T> {
+        let session = self.session.as_mut().unwrap();
+        let result = operation(session);
+        if let Err(e) = &result {
+            self.broken = e.downcast_ref::<FtpError>().is_some_and(connection_lost);
+        }
+        result
+    }
 }
 
-// Extracting name and last modif date for a given FTP file
-fn extract_ftp_file_data(entry: String) -> (String, SystemTime, String) {
-    let columns: Vec<&str> = entry.split_whitespace().collect();
+impl PooledSession<'_> {
+    // Swaps a session whose control connection was lost for a new one
+    fn renew(&mut self) -> Result<()> {
+        if self.session.take().is_some() {
+            self.location.closed();
+        }
+        let mut fresh = self.location.session()?;
+        self.session = fresh.session.take();
+        self.reused = fresh.reused;
+        self.broken = false;
+        Ok(())
+    }
+}
 
-    let file_name = columns[8..].join(" ");
+impl Drop for PooledSession<'_> {
+    fn drop(&mut self) {
+        if let Some(session) = self.session.take() {
+            if self.broken {
+                drop(session);
+                self.location.closed();
+            } else {
+                self.location.release(session);
+            }
+        }
+    }
+}
 
-    let month = columns[5];
-    let day = columns[6];
-    let time_or_year = columns[7];
+// Idle sessions are logged out when the location goes away
+impl Drop for FtpLocation {
+    fn drop(&mut self) {
+        for mut session in self.pool.get_mut().unwrap().idle.drain(..) {
+            let _ = session.stream.quit();
+        }
+    }
+}
 
-    let current_year = chrono::Local::now().year(); // Current year
-    let year: i32;
-    let time: &str;
+impl Location for FtpLocation {
+    // Lists every folder of the location (MLSD, or LIST when the server has no MLSD)
+    fn list(&self) -> Result<Listing> {
+        self.with_session(
+            |Session {
+                 stream: ftp_stream,
+                 root,
+                 features,
+                 ..
+             }| {
+                let mut files = Listing::new();
+                let mut visited = HashSet::new();
+                recursive_list(
+                    "",
+                    root,
+                    ftp_stream,
+                    features,
+                    &mut files,
+                    &mut visited,
+                    false,
+                )?;
+                Ok(files)
+            },
+        )
+    }
 
-    if time_or_year.contains(':') {
-        year = current_year;
-        time = time_or_year;
-    } else {
-        year = time_or_year.parse().unwrap();
-        time = "00:00"; // Default
+    fn stat(&self, rel_path: &str) -> Result<Option<FileEntry>> {
+        self.with_session(
+            |Session {
+                 stream: ftp_stream,
+                 root,
+                 features,
+                 ..
+             }| {
+                let path = format!("{}/{}", root, rel_path);
+                if features.mlst {
+                    return match ftp_stream.mlst(Some(&path)) {
+                        Ok(line) => Ok(parse_mlsx(&line)?.map(|entry| entry.file_entry())),
+                        Err(e) if connection_lost(&e) => Err(e.into()),
+                        Err(_) => Ok(None),
+                    };
+                }
+                if ftp_stream.cwd(&path).is_ok() {
+                    return Ok(Some(FileEntry {
+                        mtime: SystemTime::UNIX_EPOCH,
+                        modified: "Unknown".to_string(),
+                        size: None,
+                        version: None,
+                    }));
+                }
+                match ftp_stream.size(&path) {
+                    Ok(size) => {
+                        let (mtime, modified) = match ftp_stream.mdtm(&path) {
+                            Ok(naive_datetime) => ftp_time(naive_datetime),
+                            Err(_) => (SystemTime::UNIX_EPOCH, "Unknown".to_string()),
+                        };
+                        Ok(Some(FileEntry {
+                            mtime,
+                            modified,
+                            size: Some(size as u64),
+                            version: None,
+                        }))
+                    }
+                    Err(e) if connection_lost(&e) => Err(e.into()),
+                    Err(_) => Ok(None),
+                }
+            },
+        )
     }
 
-    let datetime_str = format!("{} {} {} {}", year, month, day, time);
-    let naive_datetime = NaiveDateTime::parse_from_str(&datetime_str, "%Y %b %d %H:%M")
-        .expect("Failed to parse datetime");
+    // Streams a FTP file straight from the data connection (RETR)
+    fn open_read(&self, rel_path: &str) -> Result<Box<dyn Read + '_>> {
+        let mut session = self.session()?;
+        let (dir, file_name, size, transfer) =
+            session.run(&mut |Session {
+                                   stream: ftp_stream,
+                                   root,
+                                   ..
+                               }| {
+                let (dir, file_name) = split_path(root, rel_path);
+                ftp_stream.cwd(&dir)?;
+                let size = ftp_stream.size(&file_name).ok().map(|size| size as u64);
+                let transfer = ftp_stream.retr_as_stream(&file_name)?;
+                Ok((dir, file_name, size, transfer))
+            })?;
+        Ok(Box::new(FtpReader {
+            transfer: Some(transfer),
+            session,
+            dir,
+            file_name,
+            offset: 0,
+            size,
+            resumes_left: RESUME_ATTEMPTS,
+        }))
+    }
 
-    let system_time = SystemTime::UNIX_EPOCH
-        + std::time::Duration::from_secs(naive_datetime.and_utc().timestamp() as u64);
+    // Performs a PUT (creating the parent dirs if necessary)
+    fn write(&self, rel_path: &str, mut content: &mut dyn Read) -> Result<()> {
+        self.session()?.run(&mut |Session {
+                                      stream: ftp_stream,
+                                      root,
+                                      ..
+                                  }| {
+            let (dir, file_name) = split_path(root, rel_path);
+            make_dirs(ftp_stream, &dir)?;
+            ftp_stream.put_file(&file_name, &mut content)?;
+            Ok(())
+        })
+    }
 
-    let human_readable = naive_datetime.format("%Y-%m-%d %H:%M:%S").to_string();
+    // APPE, only when the server has exactly the part of the file we expect
+    fn append(&self, rel_path: &str, offset: u64, mut content: &mut dyn Read) -> Result<bool> {
+        self.session()?.run(&mut |Session {
+                                      stream: ftp_stream,
+                                      root,
+                                      ..
+                                  }| {
+            let (dir, file_name) = split_path(root, rel_path);
+            ftp_stream.cwd(&dir)?;
+            if ftp_stream.size(&file_name).ok() != Some(offset as usize) {
+                return Ok(false);
+            }
+            ftp_stream.append_file(&file_name, &mut content)?;
+            Ok(true)
+        })
+    }
+
+    // Creating a folder directly on the FTP server using it's specific commands
+    fn mkdir(&self, rel_path: &str) -> Result<()> {
+        self.with_session(
+            |Session {
+                 stream: ftp_stream,
+                 root,
+                 ..
+             }| { make_dirs(ftp_stream, &format!("{}/{}", root, rel_path)) },
+        )?;
+        println!("Created: {}/{}", self, rel_path);
+        Ok(())
+    }
 
-    (file_name, system_time, human_readable)
+    // Deleting a file and all of it's subdirs recursively, a link is deleted but never what it
+    // points to (CWD would follow it, the listing of the parent tells what it is)
+    fn remove(&self, rel_path: &str) -> Result<()> {
+        self.with_session(
+            |Session {
+                 stream: ftp_stream,
+                 root,
+                 features,
+                 ..
+             }| {
+                let path = format!("{}/{}", root, rel_path);
+                let (dir, file_name) = split_path(root, rel_path);
+                let entry = list_dir(ftp_stream, features, &dir, false)?
+                    .into_iter()
+                    .find(|entry| entry.name == file_name);
+                match entry {
+                    Some(entry) if entry.dir && !entry.link => {
+                        recursive_delete(ftp_stream, features, &path)
+                    }
+                    _ => Ok(ftp_stream.rm(&path)?),
+                }
+            },
+        )?;
+        println!("Deleted: {}/{}", self, rel_path);
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.with_session(
+            |Session {
+                 stream: ftp_stream,
+                 root,
+                 ..
+             }| {
+                let (to_dir, _) = split_path(root, to);
+                make_dirs(ftp_stream, &to_dir)?;
+                ftp_stream.rename(format!("{}/{}", root, from), format!("{}/{}", root, to))?;
+                Ok(())
+            },
+        )
+    }
+
+    // Uploads get the upload time as modification time, MFMT or SITE UTIME (in both the
+    // Pure-FTPd/Serv-U and the ProFTPD form) gives them the time of the source instead
+    // Servers that support neither keep the upload time, the listing taken after the sync
+    // goes into the sync state, so the upload is not seen as a newer file and copied back
+    fn set_mtime(&self, rel_path: &str, mtime: SystemTime) -> Result<()> {
+        let time = DateTime::<Utc>::from(mtime)
+            .format("%Y%m%d%H%M%S")
+            .to_string();
+        self.with_session(
+            |Session {
+                 stream: ftp_stream,
+                 root,
+                 features,
+                 ..
+             }| {
+                let path = format!("{}/{}", root, rel_path);
+                let mut commands = Vec::new();
+                if features.mfmt {
+                    commands.push(format!("MFMT {} {}", time, path));
+                }
+                if features.utime {
+                    commands.push(format!(
+                        "SITE UTIME {} {} {} {} UTC",
+                        path, time, time, time
+                    ));
+                    commands.push(format!("SITE UTIME {} {}", time, path));
+                }
+                for command in &commands {
+                    match ftp_stream.custom_command(command, &[Status::CommandOk, Status::File]) {
+                        Ok(_) => return Ok(()),
+                        Err(e) if connection_lost(&e) => return Err(e.into()),
+                        Err(_) => {}
+                    }
+                }
+                features.utime = false;
+                Ok(())
+            },
+        )
+    }
 }
 
-// Read the bytes of a FTP file
-pub fn read_ftp_file(user: &str, pass: &str, url: &str, path: &str) -> Option<Vec<u8>> {
-    let mut ftp_stream = FtpStream::connect(format!("{}:21", url)).ok()?;
+// suppaftp doesn't export the TLS stream type of RustlsFtpStream, it's named through this
+trait FtpStreamTls {
+    type Tls: TlsStream;
+}
+
+impl<T: TlsStream> FtpStreamTls for ImplFtpStream<T> {
+    type Tls = T;
+}
 
-    ftp_stream.login(user, pass).ok()?;
+type Transfer = TransferStream<<RustlsFtpStream as FtpStreamTls>::Tls>;
 
-    if ftp_stream.cwd(path).is_ok() {
-        ftp_stream.cdup().ok()?; // cd ..
-        return Some(Vec::new());
+// Data connection of a download, the transfer is finished (and checked) once everything was read
+// and the session goes back to the pool; one given up halfway is not reused
+// A transfer that breaks, or ends before the size the server gave, is resumed with REST from the
+// last byte that was read, on a new session when the control connection was lost too
+struct FtpReader<'a> {
+    transfer: Option<Transfer>,
+    session: PooledSession<'a>,
+    dir: String,
+    file_name: String,
+    offset: u64,         // bytes read so far
+    size: Option<u64>,   // SIZE of the file when the download started
+    resumes_left: usize, // RESUME_ATTEMPTS at first
+}
+
+impl FtpReader<'_> {
+    // Reads from the data connection, Ok(None) at the (complete) end of the file
+    fn read_transfer(&mut self, buf: &mut [u8]) -> Result<Option<usize>> {
+        let Some(transfer) = self.transfer.as_mut() else {
+            return Ok(Some(0));
+        };
+        let ended = match transfer.read(buf) {
+            Ok(read) if read > 0 || buf.is_empty() => return Ok(Some(read)),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        };
+        // The reply of the server is read even when the data connection failed
+        let finished = self.transfer.take().unwrap().finish();
+        if let Err(e) = &finished {
+            self.session.broken = connection_lost(e);
+        }
+        ended?;
+        finished?;
+        match self.size {
+            Some(size) if self.offset < size => Err(anyhow!(
+                "download ended after {} of {} bytes",
+                self.offset,
+                size
+            )),
+            _ => Ok(None),
+        }
     }
 
-    if let Some((dir, file_name)) = path.rsplit_once('/') {
-        ftp_stream.cwd(dir).ok()?;
-        let reader = ftp_stream.simple_retr(file_name).ok()?;
-        Some(reader.into_inner())
-    } else {
-        let reader = ftp_stream.simple_retr(path).ok()?;
-        Some(reader.into_inner())
+    // Restarts the download where it stopped
+    fn resume(&mut self) -> Result<()> {
+        if self.session.broken {
+            self.session.renew()?;
+        }
+        let (dir, file_name, offset) = (&self.dir, &self.file_name, self.offset);
+        let transfer = self.session.run(&mut |Session {
+                                                   stream: ftp_stream, ..
+                                               }| {
+            ftp_stream.cwd(dir)?;
+            ftp_stream.resume_transfer(offset as usize)?;
+            Ok(ftp_stream.retr_as_stream(file_name)?)
+        })?;
+        self.transfer = Some(transfer);
+        Ok(())
     }
 }
 
-// Performs a PUT
-pub fn put_file(
-    file_bytes: &[u8],
-    user: &str,
-    pass: &str,
-    url: &str,
-    ftp_path: &str,
-) -> Result<()> {
-    let mut ftp_stream = FtpStream::connect(format!("{}:21", url))?;
+impl Read for FtpReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let error = match self.read_transfer(buf) {
+                Ok(Some(read)) => {
+                    self.offset += read as u64;
+                    return Ok(read);
+                }
+                Ok(None) => return Ok(0),
+                Err(e) => e,
+            };
+            if self.resumes_left == 0 {
+                return Err(io::Error::other(error));
+            }
+            self.resumes_left -= 1;
+            println!(
+                "Resuming the download of {} after {} bytes: {}",
+                self.file_name, self.offset, error
+            );
+            self.resume().map_err(io::Error::other)?;
+        }
+    }
+}
+
+impl Drop for FtpReader<'_> {
+    fn drop(&mut self) {
+        if self.transfer.is_some() {
+            self.session.broken = true;
+        }
+    }
+}
+
+// The password is never shown (locations are printed in logs)
+impl fmt::Display for FtpLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = if self.options.secure { "ftps" } else { "ftp" };
+        write!(f, "{}:{}@{}/{}", scheme, self.user, self.url, self.path)
+    }
+}
+
+// Options given after the path of a FTP location: ?ca=/path/to/ca.pem&pin=<sha256 fingerprint>
+// &max_sessions=<n>&mode=passive|active&timeout=<seconds>
+fn ftp_options(secure: bool, query: &str) -> Result<FtpOptions> {
+    let mut options = FtpOptions {
+        secure,
+        ..Default::default()
+    };
+    for option in query.split('&').filter(|option| !option.is_empty()) {
+        match option.split_once('=') {
+            Some(("ca", ca_file)) => options.ca_file = Some(ca_file.to_string()),
+            Some(("pin", pin)) => options.pin = Some(pin.to_string()),
+            Some(("max_sessions", max)) => match max.parse() {
+                Ok(max) if max > 0 => options.max_sessions = Some(max),
+                _ => return Err(anyhow!("max_sessions must be a positive number: {}", max)),
+            },
+            Some(("mode", "passive")) => options.mode = DataMode::Passive,
+            Some(("mode", "active")) => options.mode = DataMode::Active,
+            Some(("mode", mode)) => {
+                return Err(anyhow!("FTP mode must be passive or active: {}", mode))
+            }
+            Some(("timeout", secs)) => match secs.parse() {
+                Ok(secs) if secs > 0 => options.timeout = Some(Duration::from_secs(secs)),
+                _ => {
+                    return Err(anyhow!(
+                        "timeout must be a positive number of seconds: {}",
+                        secs
+                    ))
+                }
+            },
+            _ => return Err(anyhow!("unrecognized FTP option: {}", option)),
+        }
+    }
+    Ok(options)
+}
+
+fn timeouts(stream: TcpStream, timeout: Duration) -> io::Result<TcpStream> {
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    Ok(stream)
+}
 
-    ftp_stream.login(user, pass)?;
-    let wdir = ftp_path.rsplit_once("/");
-    if let Some(wdir) = wdir {
-        ftp_stream.cwd(wdir.0)?;
-        let mut reader = Cursor::new(file_bytes);
-        ftp_stream.put(wdir.1, &mut reader)?;
-    } else {
-        let mut reader = Cursor::new(file_bytes);
-        ftp_stream.put(ftp_path, &mut reader)?;
+// host, host:port, [IPv6 address] or [IPv6 address]:port (a bare IPv6 address has no port)
+fn host_port(url: &str) -> Result<(String, u16)> {
+    let (host, port) = match url.strip_prefix('[') {
+        Some(bracketed) => match bracketed.split_once(']') {
+            Some((host, "")) => (host, None),
+            Some((host, port)) => match port.strip_prefix(':') {
+                Some(port) => (host, Some(port)),
+                None => return Err(anyhow!("expected [address]:port: {}", url)),
+            },
+            None => return Err(anyhow!("missing ] in FTP host: {}", url)),
+        },
+        None => match url.split_once(':') {
+            Some((host, port)) if !port.contains(':') => (host, Some(port)),
+            _ => (url, None),
+        },
+    };
+    if host.is_empty() {
+        return Err(anyhow!("expected ftp:user:password@host[:port]/path"));
     }
-    ftp_stream.quit()?;
+    let port = match port {
+        Some(port) => port
+            .parse()
+            .map_err(|_| anyhow!("invalid FTP port: {}", port))?,
+        None => DEFAULT_PORT,
+    };
+    Ok((host.to_string(), port))
+}
+
+// The control connection is gone (421: the server is closing it)
+fn connection_lost(e: &FtpError) -> bool {
+    match e {
+        FtpError::ConnectionError(_) => true,
+        FtpError::UnexpectedResponse(response) => response.status == Status::NotAvailable,
+        _ => false,
+    }
+}
 
+// (absolute dir - file name) of a path inside the location
+fn split_path(root: &str, rel_path: &str) -> (String, String) {
+    let path = format!("{}/{}", root, rel_path);
+    match path.rsplit_once('/') {
+        Some(("", file_name)) => ("/".to_string(), file_name.to_string()),
+        Some((dir, file_name)) => (dir.to_string(), file_name.to_string()),
+        None => (".".to_string(), path),
+    }
+}
+
+// Walks into an absolute dir, creating every missing part of it
+fn make_dirs(ftp_stream: &mut RustlsFtpStream, dir: &str) -> Result<()> {
+    ftp_stream.cwd("/")?;
+    for part in dir.split('/').filter(|part| !part.is_empty()) {
+        if ftp_stream.cwd(part).is_err() {
+            // Dir does not exist, create it
+            ftp_stream.mkdir(part)?;
+            ftp_stream.cwd(part)?;
+        }
+    }
     Ok(())
 }
 
-// Creating an FTP file directly on the FTP server using it's specific commands
-pub fn create_ftp_file(
-    user: &str,
-    pass: &str,
-    url: &str,
-    path: &str,
-    create_type: CreateType,
+// Helping the list function, dir is the absolute path of rel_path
+// Links to folders are followed, but not the ones that lead back to a folder already listed (as
+// the server shows it's path after CWD), nor the ones inside a followed link: a link to a parent
+// would loop forever on servers that only show the path of the link
+fn recursive_list(
+    rel_path: &str,
+    dir: &str,
+    ftp_stream: &mut RustlsFtpStream,
+    features: &Features,
+    hash_map: &mut Listing,
+    visited: &mut HashSet<String>,
+    linked: bool,
 ) -> Result<()> {
-    let mut ftp_stream = FtpStream::connect(format!("{}:21", url))?;
-    ftp_stream.login(user, pass)?;
-
-    let path = Path::new(path);
-    if let Some(parent_dirs) = path.parent() {
-        for dir in parent_dirs.iter() {
-            let dir_str = dir.to_string_lossy();
-            if ftp_stream.cwd(&dir_str).is_err() {
-                // Dir does not exist, create it
-                ftp_stream.mkdir(&dir_str)?;
-                ftp_stream.cwd(&dir_str)?;
+    visited.insert(dir.to_string());
+    for entry in list_dir(ftp_stream, features, dir, true)? {
+        if entry.name == "." || entry.name == ".." || entry.name.contains(".DS") {
+            continue;
+        }
+        let rel_path = format!("{}/{}", rel_path, entry.name)
+            .trim_start_matches("/")
+            .to_string();
+        let path = format!("{}/{}", dir, entry.name);
+        let mut file_entry = entry.file_entry();
+        if entry.link && ftp_stream.cwd(&path).is_ok() {
+            let target = ftp_stream.pwd().unwrap_or_else(|_| path.clone());
+            let target = target.trim_end_matches('/');
+            if linked || visited.contains(target) || dir.starts_with(&format!("{}/", target)) {
+                continue;
             }
+            file_entry.size = None;
+            hash_map.insert(rel_path.clone(), file_entry);
+            visited.insert(target.to_string());
+            recursive_list(
+                &rel_path, &path, ftp_stream, features, hash_map, visited, true,
+            )?;
+        } else if entry.dir {
+            // Insert folder info before recursion
+            file_entry.size = None;
+            hash_map.insert(rel_path.clone(), file_entry);
+            recursive_list(
+                &rel_path, &path, ftp_stream, features, hash_map, visited, linked,
+            )?;
+        } else {
+            hash_map.insert(rel_path, file_entry);
         }
     }
-    match create_type {
-        CreateType::File => {
-            let file_name = path
-                .file_name()
-                .unwrap_or_default()
-                .to_str()
-                .unwrap_or_default();
-            let empty_bytes: Vec<u8> = Vec::new();
-            let mut file_contents = Cursor::new(empty_bytes);
-            ftp_stream.put(file_name, &mut file_contents)?;
+    Ok(())
+}
+
+// Entries of a folder: MLSD when the server has it, LIST otherwise, whose minute (or day) precise
+// times in the server's time zone are replaced by MDTM ones (exact, UTC) when times is set
+fn list_dir(
+    ftp_stream: &mut RustlsFtpStream,
+    features: &Features,
+    dir: &str,
+    times: bool,
+) -> Result<Vec<ListEntry>> {
+    ftp_stream.cwd(dir)?;
+    if features.mlst {
+        match ftp_stream.mlsd(None) {
+            Ok(lines) => {
+                let entries = lines.iter().map(|line| parse_mlsx(line));
+                return Ok(entries
+                    .collect::<Result<Vec<_>>>()?
+                    .into_iter()
+                    .flatten()
+                    .collect());
+            }
+            Err(e) if connection_lost(&e) => return Err(e.into()),
+            // Some servers advertise MLST without MLSD
+            Err(_) => {}
         }
-        CreateType::Folder => {
-            // if the path itself a directory
-            let dir_str = path.to_string_lossy();
-            ftp_stream.mkdir(&dir_str)?;
+    }
+    let now = Utc::now().naive_utc();
+    let mut entries = Vec::new();
+    for line in ftp_stream.list(None)? {
+        entries.extend(parse_list_line(&line, now)?);
+    }
+    if times && features.mdtm {
+        for entry in entries.iter_mut().filter(|entry| !entry.dir && !entry.link) {
+            match ftp_stream.mdtm(&entry.name) {
+                Ok(mtime) => entry.mtime = Some(mtime),
+                Err(e) if connection_lost(&e) => return Err(e.into()),
+                Err(_) => {}
+            }
         }
     }
-    ftp_stream.quit()?;
-    Ok(())
+    Ok(entries)
 }
 
-// Deleteing a file and all of it's subdirs recursively
-pub fn delete_ftp_file(user: &str, pass: &str, url: &str, path: &str) -> Result<()> {
-    let mut ftp_stream = FtpStream::connect(format!("{}:21", url))?;
-    ftp_stream.login(user, pass)?;
+// Line of a MLSD (or MLST) answer: facts, a space and the name
+// type=file;size=1024;modify=20240102030405.123; name.txt
+fn parse_mlsx(line: &str) -> Result<Option<ListEntry>> {
+    let line = line.trim_end_matches(['\r', '\n']).trim_start();
+    let (facts, name) = line
+        .split_once(' ')
+        .ok_or_else(|| anyhow!("unrecognized MLSD line from the server: {}", line))?;
+    let mut entry = ListEntry {
+        name: name.to_string(),
+        dir: false,
+        link: false,
+        size: 0,
+        mtime: None,
+    };
+    for fact in facts.split(';') {
+        let Some((key, value)) = fact.split_once('=') else {
+            continue;
+        };
+        match key.to_ascii_lowercase().as_str() {
+            "type" => match value.to_ascii_lowercase().as_str() {
+                "file" => {}
+                "dir" => entry.dir = true,
+                // The folder itself and it's parent
+                "cdir" | "pdir" => {
+                    entry.dir = true;
+                    entry.name = ".".to_string();
+                }
+                kind if kind.starts_with("os.unix=slink")
+                    || kind.starts_with("os.unix=symlink") =>
+                {
+                    entry.link = true
+                }
+                // Devices, sockets...
+                _ => return Ok(None),
+            },
+            "size" | "sizd" => {
+                entry.size = value
+                    .parse()
+                    .map_err(|_| anyhow!("invalid size in MLSD line from the server: {}", line))?
+            }
+            // YYYYMMDDHHMMSS, sometimes followed by fractions of a second
+            "modify" => {
+                entry.mtime = value
+                    .get(..14)
+                    .and_then(|value| NaiveDateTime::parse_from_str(value, "%Y%m%d%H%M%S").ok())
+            }
+            _ => {}
+        }
+    }
+    Ok(Some(entry))
+}
 
-    recursive_delete(&mut ftp_stream, path)?;
+// Line of a LIST answer, in the format of ls -l (most servers) or of DOS (IIS)
+// Lines that are not entries ("total 42") and special files give no entry
+fn parse_list_line(line: &str, now: NaiveDateTime) -> Result<Option<ListEntry>> {
+    let line = line.trim_end_matches(['\r', '\n']);
+    if line.trim().is_empty() || line.starts_with("total ") {
+        return Ok(None);
+    }
+    if let Some(entry) = parse_dos_line(line) {
+        return Ok(Some(entry));
+    }
+    match line.chars().next() {
+        Some('-' | 'd' | 'l') => {}
+        Some('b' | 'c' | 'p' | 's') => return Ok(None),
+        _ => return Err(anyhow!("unrecognized LIST line from the server: {}", line)),
+    }
+    parse_unix_line(line, now)
+        .map(Some)
+        .ok_or_else(|| anyhow!("unrecognized LIST line from the server: {}", line))
+}
 
-    ftp_stream.quit()?;
-    Ok(())
+// drwxr-xr-x  2 owner group  4096 Jan  5 13:46 name (the group is missing on some servers)
+// Times of recent files come without a year, older ones without a time
+fn parse_unix_line(line: &str, now: NaiveDateTime) -> Option<ListEntry> {
+    let tokens = tokens(line);
+    // The size is right before the month, the owner could look like a month too
+    (2..tokens.len().saturating_sub(3))
+        .find_map(|month_at| unix_entry(line, &tokens, month_at, now))
 }
 
-// help for the above function
-fn recursive_delete(ftp_stream: &mut FtpStream, path: &str) -> Result<()> {
-    if ftp_stream.cwd(path).is_ok() {
-        let items = ftp_stream.nlst(None)?;
-        for item in items {
-            let item_path = format!("{}/{}", path, item);
-            recursive_delete(ftp_stream, &item_path)?;
+// The entry of a ls -l line when it's month is the token at month_at
+fn unix_entry(
+    line: &str,
+    tokens: &[(usize, &str)],
+    month_at: usize,
+    now: NaiveDateTime,
+) -> Option<ListEntry> {
+    let size = tokens[month_at - 1].1.parse().ok()?;
+    let month = month(tokens[month_at].1)?;
+    let day = tokens[month_at + 1].1.parse().ok()?;
+    let time_or_year = tokens[month_at + 2].1;
+    let mut name = &line[tokens[month_at + 3].0..];
+
+    let mtime = match time_or_year.split_once(':') {
+        Some((hour, minute)) => {
+            let (hour, minute) = (hour.parse().ok()?, minute.parse().ok()?);
+            let at = |year| NaiveDate::from_ymd_opt(year, month, day)?.and_hms_opt(hour, minute, 0);
+            // The year is the last one where the date is not in the future (a day of margin
+            // for time zones)
+            match at(now.year()) {
+                Some(mtime) if mtime <= now + chrono::Duration::days(1) => mtime,
+                _ => at(now.year() - 1)?,
+            }
+        }
+        None => {
+            NaiveDate::from_ymd_opt(time_or_year.parse().ok()?, month, day)?.and_hms_opt(0, 0, 0)?
+        }
+    };
+    let kind = line.chars().next()?;
+    if kind == 'l' {
+        name = name.split_once(" -> ").map_or(name, |(name, _)| name);
+    }
+    Some(ListEntry {
+        name: name.to_string(),
+        dir: kind == 'd',
+        link: kind == 'l',
+        size,
+        mtime: Some(mtime),
+    })
+}
+
+// 10-19-20  03:19PM       <DIR>          name
+// 04-08-2014  15:09           1,403 name.txt
+fn parse_dos_line(line: &str) -> Option<ListEntry> {
+    let tokens = tokens(line);
+    let (date, time, size_or_dir) = (tokens.first()?.1, tokens.get(1)?.1, tokens.get(2)?.1);
+    let name = &line[tokens.get(3)?.0..];
+    let date = NaiveDate::parse_from_str(date, "%m-%d-%y")
+        .or_else(|_| NaiveDate::parse_from_str(date, "%m-%d-%Y"))
+        .ok()?;
+    let time = NaiveTime::parse_from_str(time, "%I:%M%p")
+        .or_else(|_| NaiveTime::parse_from_str(time, "%H:%M"))
+        .ok()?;
+    let (dir, size) = match size_or_dir.eq_ignore_ascii_case("<DIR>") {
+        true => (true, 0),
+        false => (false, size_or_dir.replace(',', "").parse().ok()?),
+    };
+    Some(ListEntry {
+        name: name.to_string(),
+        dir,
+        link: false,
+        size,
+        mtime: Some(date.and_time(time)),
+    })
+}
+
+// Words of a line, with where they start (names may contain spaces)
+fn tokens(line: &str) -> Vec<(usize, &str)> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    for (index, c) in line.char_indices() {
+        match (c.is_whitespace(), start) {
+            (true, Some(from)) => {
+                tokens.push((from, &line[from..index]));
+                start = None;
+            }
+            (false, None) => start = Some(index),
+            _ => {}
+        }
+    }
+    if let Some(from) = start {
+        tokens.push((from, &line[from..]));
+    }
+    tokens
+}
+
+fn month(name: &str) -> Option<u32> {
+    const MONTHS: [&str; 12] = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+    let position = MONTHS
+        .iter()
+        .position(|month| name.eq_ignore_ascii_case(month))?;
+    Some(position as u32 + 1)
+}
+
+impl ListEntry {
+    fn file_entry(&self) -> FileEntry {
+        let (mtime, modified) = match self.mtime {
+            Some(mtime) => ftp_time(mtime),
+            None => (SystemTime::UNIX_EPOCH, "Unknown".to_string()),
+        };
+        FileEntry {
+            mtime,
+            modified,
+            size: (!self.dir).then_some(self.size),
+            version: None,
+        }
+    }
+}
+
+// (unix epoch - human readable time) of a time given by the server (always UTC)
+fn ftp_time(naive_datetime: NaiveDateTime) -> (SystemTime, String) {
+    let secs = naive_datetime.and_utc().timestamp().max(0) as u64;
+    let system_time = SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+
+    let human_readable = naive_datetime.format("%Y-%m-%d %H:%M:%S").to_string();
+    (system_time, human_readable)
+}
+
+// help for the remove function, the path is absolute and is a folder (not a link)
+fn recursive_delete(
+    ftp_stream: &mut RustlsFtpStream,
+    features: &Features,
+    path: &str,
+) -> Result<()> {
+    for entry in list_dir(ftp_stream, features, path, false)? {
+        if entry.name == "." || entry.name == ".." {
+            continue;
+        }
+        let entry_path = format!("{}/{}", path, entry.name);
+        if entry.dir && !entry.link {
+            recursive_delete(ftp_stream, features, &entry_path)?;
+        } else {
+            ftp_stream.rm(&entry_path)?;
         }
-        ftp_stream.cdup()?;
-        let dir = path.rsplit_once("/").unwrap_or(("", path));
-        ftp_stream.rmdir(dir.1)?;
-    } else {
-        ftp_stream.rm(path)?;
     }
+    ftp_stream.cwd("/")?;
+    ftp_stream.rmdir(path)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(date: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    // kind size mtime name of a parsed entry
+    fn show(entry: ListEntry) -> String {
+        let kind = match (entry.dir, entry.link) {
+            (true, _) => "dir",
+            (_, true) => "link",
+            _ => "file",
+        };
+        let mtime = entry
+            .mtime
+            .map_or("-".to_string(), |mtime| mtime.to_string());
+        format!("{} {} {} {}", kind, entry.size, mtime, entry.name)
+    }
+
+    #[test]
+    fn mlsd_lines() {
+        let cases = [
+            (
+                "type=file;size=1024;modify=20240102030405; name.txt",
+                Some("file 1024 2024-01-02 03:04:05 name.txt"),
+            ),
+            (
+                "type=cdir;modify=20240102030405; /data",
+                Some("dir 0 2024-01-02 03:04:05 ."),
+            ),
+            (
+                "type=pdir;modify=20240102030405; ..",
+                Some("dir 0 2024-01-02 03:04:05 ."),
+            ),
+            (
+                "type=dir;modify=20240102030405; sub",
+                Some("dir 0 2024-01-02 03:04:05 sub"),
+            ),
+            (
+                "type=OS.unix=slink:/srv/other;size=8;modify=20240102030405; ext",
+                Some("link 8 2024-01-02 03:04:05 ext"),
+            ),
+            (
+                "type=OS.unix=symlink;modify=20240102030405; ext",
+                Some("link 0 2024-01-02 03:04:05 ext"),
+            ),
+            (
+                "type=file;size=5;modify=20240102030405.123; fraction.txt",
+                Some("file 5 2024-01-02 03:04:05 fraction.txt"),
+            ),
+            (
+                "Type=File;Size=5;Modify=20240102030405;UNIX.mode=0644; a name with  spaces",
+                Some("file 5 2024-01-02 03:04:05 a name with  spaces"),
+            ),
+            ("type=file;size=5; no_time", Some("file 5 - no_time")),
+            (
+                " type=file;size=5;modify=20240102030405; x\r\n",
+                Some("file 5 2024-01-02 03:04:05 x"),
+            ),
+            ("type=OS.unix=chr-13/29;modify=20240102030405; tty", None),
+        ];
+        for (line, expected) in cases {
+            let entry = parse_mlsx(line).unwrap().map(show);
+            assert_eq!(entry.as_deref(), expected, "{}", line);
+        }
+    }
+
+    #[test]
+    fn unix_list_lines() {
+        let now = at("2024-03-10 12:00:00");
+        let cases = [
+            (
+                "-rw-r--r--   1 owner group  1024 Jan  5 13:46 file.txt",
+                Some("file 1024 2024-01-05 13:46:00 file.txt"),
+            ),
+            (
+                "drwxr-xr-x   2 owner group  4096 Nov 20  2019 old",
+                Some("dir 4096 2019-11-20 00:00:00 old"),
+            ),
+            // No group
+            (
+                "-rw-r--r--   1 owner  1024 Jan  5 13:46 file.txt",
+                Some("file 1024 2024-01-05 13:46:00 file.txt"),
+            ),
+            // Owners that look like a month
+            (
+                "-rw-r--r--   1 may  staff  12 Feb  5  2020 f",
+                Some("file 12 2020-02-05 00:00:00 f"),
+            ),
+            (
+                "-rw-r--r--   1 may  12 Feb  5  2020 f",
+                Some("file 12 2020-02-05 00:00:00 f"),
+            ),
+            // Later in the year than now, so last year
+            (
+                "-rw-r--r--   1 owner group  1 Dec 31 23:59 f",
+                Some("file 1 2023-12-31 23:59:00 f"),
+            ),
+            (
+                "lrwxrwxrwx   1 owner group  9 Jan  5 13:46 my link -> ../other dir",
+                Some("link 9 2024-01-05 13:46:00 my link"),
+            ),
+            (
+                "-rw-r--r--   1 owner group  5 Jan  5  2020   a name with  spaces",
+                Some("file 5 2020-01-05 00:00:00 a name with  spaces"),
+            ),
+            ("total 42", None),
+            ("", None),
+            ("crw-rw-rw-   1 root  root  1, 3 Jan  5 13:46 null", None),
+            ("srwxrwxrwx   1 owner group  0 Jan  5 13:46 socket", None),
+        ];
+        for (line, expected) in cases {
+            let entry = parse_list_line(line, now).unwrap().map(show);
+            assert_eq!(entry.as_deref(), expected, "{}", line);
+        }
+    }
+
+    #[test]
+    fn unix_list_times_around_new_year() {
+        let line = |date| format!("-rw-r--r-- 1 owner group 1 {} f", date);
+        let cases = [
+            ("Dec 31 23:59", "2023-12-31 23:59:00"),
+            ("Jan  1 00:01", "2024-01-01 00:01:00"),
+            // Tomorrow: the server may be ahead of us
+            ("Jan  2 08:00", "2024-01-02 08:00:00"),
+            ("Jan  9 08:00", "2023-01-09 08:00:00"),
+        ];
+        for (date, expected) in cases {
+            let entry = parse_list_line(&line(date), at("2024-01-01 10:00:00"))
+                .unwrap()
+                .unwrap();
+            assert_eq!(entry.mtime, Some(at(expected)), "{}", date);
+        }
+    }
+
+    #[test]
+    fn dos_list_lines() {
+        let now = at("2024-03-10 12:00:00");
+        let cases = [
+            (
+                "10-19-20  03:19PM       <DIR>          Program Files",
+                "dir 0 2020-10-19 15:19:00 Program Files",
+            ),
+            (
+                "04-08-2014  15:09           1,403 name.txt",
+                "file 1403 2014-04-08 15:09:00 name.txt",
+            ),
+            (
+                "01-02-99  11:05AM            12 old.txt",
+                "file 12 1999-01-02 11:05:00 old.txt",
+            ),
+            (
+                "12-31-2023  12:00AM       1,234,567 midnight.bin",
+                "file 1234567 2023-12-31 00:00:00 midnight.bin",
+            ),
+        ];
+        for (line, expected) in cases {
+            let entry = parse_list_line(line, now).unwrap().map(show);
+            assert_eq!(entry.as_deref(), Some(expected), "{}", line);
+        }
+    }
+
+    #[test]
+    fn unrecognized_lines_are_errors() {
+        let now = at("2024-03-10 12:00:00");
+        let lines = [
+            "garbage",
+            "-rw-r--r--",
+            "-rw-r--r--   1 owner group  big Jan  5 13:46 f",
+            "-rw-r--r--   1 owner group  1 Jan 32 13:46 f",
+            "-rw-r--r--   1 owner group  1 Foo  5 13:46 f",
+            "drwxr-xr-x   1 owner group  1 Jan  5 25:61 f",
+            "13-45-20  03:19PM       <DIR>          bad date",
+            "10-19-20  03:19PM       huge          bad size",
+        ];
+        for line in lines {
+            assert!(parse_list_line(line, now).is_err(), "{}", line);
+        }
+        for line in ["no_facts", "type=file;size=big; f"] {
+            assert!(parse_mlsx(line).is_err(), "{}", line);
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use anyhow::{anyhow, Result};
+use git2::{Commit, ObjectType, Repository, TreeWalkMode, TreeWalkResult};
+use std::fmt;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::errors::FileErrors;
+use crate::sync::location::{FileEntry, Listing, Location};
+use crate::utils::human_readable_time;
+
+// Files of a commit of a git repository, a read-only source like zip:
+// git:/path/to/repo[@ref], the ref (branch, tag, commit...) defaults to HEAD and is resolved again
+// on every listing, so the location follows a branch as it moves
+pub struct GitLocation {
+    repo: PathBuf,
+    reference: Option<String>,
+    git_dir: PathBuf, // .git folder (or the bare repository), watched for ref updates
+}
+
+impl GitLocation {
+    pub fn open(_scheme: &str, spec: &str) -> Result<Box<dyn Location>> {
+        // '@' can be part of the path, it only starts a ref when the whole spec is not a repository
+        let (repo, reference) = match spec.rsplit_once('@') {
+            Some((repo, reference)) if !Path::new(spec).exists() => {
+                (repo, Some(reference.to_string()))
+            }
+            _ => (spec, None),
+        };
+        let git_dir = Repository::open(repo)?.path().to_path_buf();
+        Ok(Box::new(Self {
+            repo: PathBuf::from(repo),
+            reference,
+            git_dir,
+        }))
+    }
+
+    // The repository is opened for every operation (a Repository can't be shared between the
+    // threads that copy files)
+    fn repository(&self) -> Result<Repository> {
+        Ok(Repository::open(&self.repo)?)
+    }
+
+    fn commit<'r>(&self, repo: &'r Repository) -> Result<Commit<'r>> {
+        let reference = self.reference.as_deref().unwrap_or("HEAD");
+        Ok(repo.revparse_single(reference)?.peel_to_commit()?)
+    }
+
+    fn read_only_error(&self) -> anyhow::Error {
+        FileErrors::InvalidFileForWriting("git repositories are read-only".to_string()).into()
+    }
+}
+
+impl Location for GitLocation {
+    // Every file of the tree gets the time of the commit, git does not keep the time of files
+    // Submodules and symbolic links are left out
+    fn list(&self) -> Result<Listing> {
+        let repo = self.repository()?;
+        let commit = self.commit(&repo)?;
+        let mtime = UNIX_EPOCH + Duration::from_secs(commit.time().seconds().max(0) as u64);
+        let odb = repo.odb()?;
+
+        let mut files = Listing::new();
+        let mut error = None;
+        commit.tree()?.walk(TreeWalkMode::PreOrder, |dir, entry| {
+            let Ok(name) = entry.name() else {
+                return TreeWalkResult::Skip;
+            };
+            let rel_path = format!("{}{}", dir, name);
+            if rel_path.contains(".DS") {
+                return TreeWalkResult::Skip;
+            }
+            let size = match entry.kind() {
+                Some(ObjectType::Tree) => None,
+                Some(ObjectType::Blob) if entry.filemode() != 0o120000 => {
+                    match odb.read_header(entry.id()) {
+                        Ok((size, _)) => Some(size as u64),
+                        Err(e) => {
+                            error = Some(e);
+                            return TreeWalkResult::Abort;
+                        }
+                    }
+                }
+                _ => return TreeWalkResult::Skip,
+            };
+            files.insert(
+                rel_path,
+                FileEntry {
+                    mtime,
+                    modified: human_readable_time(mtime),
+                    size,
+                    version: None,
+                },
+            );
+            TreeWalkResult::Ok
+        })?;
+        match error {
+            Some(e) => Err(e.into()),
+            None => Ok(files),
+        }
+    }
+
+    fn stat(&self, rel_path: &str) -> Result<Option<FileEntry>> {
+        Ok(self.list()?.remove(rel_path))
+    }
+
+    // Blobs are read from the object database, packed ones can't be streamed so the content is
+    // read at once
+    fn open_read(&self, rel_path: &str) -> Result<Box<dyn Read + '_>> {
+        let repo = self.repository()?;
+        let commit = self.commit(&repo)?;
+        let entry = commit.tree()?.get_path(Path::new(rel_path))?;
+        let blob = repo
+            .find_blob(entry.id())
+            .map_err(|_| anyhow!("{} is not a file in {}", rel_path, self))?;
+        Ok(Box::new(Cursor::new(blob.content().to_vec())))
+    }
+
+    fn write(&self, _rel_path: &str, _content: &mut dyn Read) -> Result<()> {
+        Err(self.read_only_error())
+    }
+
+    fn mkdir(&self, _rel_path: &str) -> Result<()> {
+        Err(self.read_only_error())
+    }
+
+    fn remove(&self, _rel_path: &str) -> Result<()> {
+        Err(FileErrors::InvalidFileForDelete("git repositories are read-only".to_string()).into())
+    }
+
+    fn rename(&self, _from: &str, _to: &str) -> Result<()> {
+        Err(self.read_only_error())
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+
+    // A commit, a checkout or a push moves the refs in there
+    fn watch_path(&self) -> Option<&Path> {
+        Some(&self.git_dir)
+    }
+}
+
+impl fmt::Display for GitLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "git:{}", self.repo.display())?;
+        if let Some(reference) = &self.reference {
+            write!(f, "@{}", reference)?;
+        }
+        Ok(())
+    }
+}
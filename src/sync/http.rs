@@ -0,0 +1,520 @@
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::DateTime;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::errors::FileErrors;
+use crate::sync::location::{FileEntry, Listing, Location};
+use crate::sync::xml;
+use crate::utils::{human_readable_time, uri_decode, uri_encode};
+
+// How many times a download that was cut is picked up again
+const RESUME_RETRIES: usize = 3;
+
+// Published files, a read-only source:
+//   http[s]://[user:password@]host/dir/            the directory index pages of the server
+//   http[s]://[user:password@]host/dir/files.json  a manifest listing the files next to it
+pub struct HttpLocation {
+    secure: bool,
+    host: String,             // host[:port]
+    root: String,             // folder on the server, without leading or trailing '/'
+    manifest: Option<String>, // name of the manifest in root, None to read the index pages
+    user: Option<String>,
+    authorization: Option<String>, // Basic credentials, sent with every request
+    agent: ureq::Agent,
+    // Responses of the last listing by path, asked again with If-Modified-Since
+    pages: Mutex<HashMap<String, Cached<String>>>,
+    heads: Mutex<HashMap<String, Cached<FileEntry>>>,
+}
+
+struct Cached<T> {
+    last_modified: String,
+    value: T,
+}
+
+impl HttpLocation {
+    pub fn open(scheme: &str, spec: &str) -> Result<Box<dyn Location>> {
+        let spec = spec
+            .strip_prefix("//")
+            .ok_or_else(|| anyhow!("expected {}://host/path", scheme))?;
+        let (credentials, host_path) = match spec.rsplit_once('@') {
+            Some((credentials, host_path)) => (Some(credentials), host_path),
+            None => (None, spec),
+        };
+        let (host, path) = host_path.split_once('/').unwrap_or((host_path, ""));
+        if host.is_empty() {
+            return Err(anyhow!("expected {}://host/path", scheme));
+        }
+        let (root, manifest) = match path.rsplit_once('/').unwrap_or(("", path)) {
+            (root, name) if name.ends_with(".json") => (root, Some(name.to_string())),
+            _ => (path, None),
+        };
+        let (user, authorization) = match credentials {
+            Some(credentials) => {
+                let (user, password) = credentials.split_once(':').unwrap_or((credentials, ""));
+                let basic = BASE64.encode(format!("{}:{}", user, password));
+                (Some(user.to_string()), Some(format!("Basic {}", basic)))
+            }
+            None => (None, None),
+        };
+
+        Ok(Box::new(Self {
+            secure: scheme == "https",
+            host: host.to_string(),
+            root: root.trim_matches('/').to_string(),
+            manifest,
+            user,
+            authorization,
+            agent: ureq::AgentBuilder::new().build(),
+            pages: Mutex::new(HashMap::new()),
+            heads: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    // Path on the server of a path relative to the location
+    fn path(&self, rel_path: &str) -> String {
+        let path = format!("{}/{}", self.root, rel_path);
+        format!("/{}", path.trim_matches('/'))
+    }
+
+    fn read_only_error(&self) -> anyhow::Error {
+        FileErrors::InvalidFileForWriting("HTTP locations are read-only".to_string()).into()
+    }
+
+    // Sends a request about a path on the server
+    // Error statuses are turned into errors, except the ones listed in allowed
+    fn send(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        allowed: &[u16],
+    ) -> Result<ureq::Response> {
+        let scheme = if self.secure { "https" } else { "http" };
+        let url = format!("{}://{}{}", scheme, self.host, uri_encode(path, false));
+        let mut request = self.agent.request(method, &url);
+        if let Some(authorization) = &self.authorization {
+            request = request.set("Authorization", authorization);
+        }
+        for (name, value) in headers {
+            request = request.set(name, value);
+        }
+        match request.call() {
+            Ok(response) => Ok(response),
+            Err(ureq::Error::Status(status, response)) if allowed.contains(&status) => Ok(response),
+            Err(ureq::Error::Status(status, response)) => Err(anyhow!(
+                "HTTP {} {}{} failed: {} {}",
+                method,
+                self.host,
+                path,
+                status,
+                response.status_text()
+            )),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Body of a page, the last one is kept and reused as long as the server answers
+    // 304 Not Modified
+    fn page(&self, path: &str) -> Result<String> {
+        let since = self
+            .pages
+            .lock()
+            .unwrap()
+            .get(path)
+            .map(|cached| cached.last_modified.clone());
+        let headers: Vec<(&str, &str)> = since
+            .iter()
+            .map(|since| ("If-Modified-Since", since.as_str()))
+            .collect();
+        let response = self.send("GET", path, &headers, &[])?;
+        let mut pages = self.pages.lock().unwrap();
+        if response.status() == 304 {
+            if let Some(cached) = pages.get(path) {
+                return Ok(cached.value.clone());
+            }
+        }
+        let last_modified = response.header("Last-Modified").map(str::to_string);
+        let body = response.into_string()?;
+        if let Some(last_modified) = last_modified {
+            pages.insert(
+                path.to_string(),
+                Cached {
+                    last_modified,
+                    value: body.clone(),
+                },
+            );
+        }
+        Ok(body)
+    }
+
+    // Size and mtime of a file from a HEAD request (with If-Modified-Since, like page)
+    // None when the server does not have it
+    fn head(&self, path: &str) -> Result<Option<FileEntry>> {
+        let since = self
+            .heads
+            .lock()
+            .unwrap()
+            .get(path)
+            .map(|cached| cached.last_modified.clone());
+        let headers: Vec<(&str, &str)> = since
+            .iter()
+            .map(|since| ("If-Modified-Since", since.as_str()))
+            .collect();
+        let response = self.send("HEAD", path, &headers, &[404])?;
+        let mut heads = self.heads.lock().unwrap();
+        match response.status() {
+            404 => return Ok(None),
+            304 => {
+                if let Some(cached) = heads.get(path) {
+                    return Ok(Some(cached.value.clone()));
+                }
+            }
+            _ => {}
+        }
+        let mtime = response
+            .header("Last-Modified")
+            .and_then(parse_time)
+            .unwrap_or(UNIX_EPOCH);
+        let entry = FileEntry {
+            mtime,
+            modified: human_readable_time(mtime),
+            size: Some(
+                response
+                    .header("Content-Length")
+                    .and_then(|size| size.parse().ok())
+                    .unwrap_or(0),
+            ),
+            version: None,
+        };
+        if let Some(last_modified) = response.header("Last-Modified") {
+            heads.insert(
+                path.to_string(),
+                Cached {
+                    last_modified: last_modified.to_string(),
+                    value: entry.clone(),
+                },
+            );
+        }
+        Ok(Some(entry))
+    }
+
+    // Walks the index pages of the server (Apache, nginx autoindex, python -m http.server...)
+    // Links ending with '/' are folders, the other ones files whose size and mtime come from a
+    // HEAD request; links going anywhere but one level down are ignored (parents, sorting...)
+    fn list_index(&self) -> Result<Listing> {
+        let mut files = Listing::new();
+        let mut folders = vec![String::new()];
+        while let Some(folder) = folders.pop() {
+            let dir = format!("{}/", self.path(&folder).trim_end_matches('/'));
+            let page = self.page(&dir)?;
+            for href in hrefs(&page) {
+                let Some((path, is_dir)) = resolve(&self.host, &dir, &href) else {
+                    continue;
+                };
+                let Some(name) = path.strip_prefix(&dir) else {
+                    continue;
+                };
+                if name.is_empty() || name.contains('/') || name.contains(".DS") {
+                    continue;
+                }
+                let rel_path = if folder.is_empty() {
+                    name.to_string()
+                } else {
+                    format!("{}/{}", folder, name)
+                };
+                if files.contains_key(&rel_path) {
+                    continue;
+                }
+                if is_dir {
+                    folders.push(rel_path.clone());
+                    files.insert(rel_path, dir_entry());
+                } else if let Some(entry) = self.head(&path)? {
+                    files.insert(rel_path, entry);
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    // The manifest is a JSON array of files (or an object with it under "files"):
+    //   [{"path": "bin/tool", "size": 1024, "mtime": 1700000000}, ...]
+    // mtime is unix seconds or an RFC 3339 / RFC 2822 date; files without size or mtime are asked
+    // with a HEAD request, paths ending with '/' are folders
+    fn list_manifest(&self, manifest: &str) -> Result<Listing> {
+        let page = self.page(&self.path(manifest))?;
+        let manifest: Value = serde_json::from_str(&page)?;
+        let entries = match manifest.get("files").unwrap_or(&manifest).as_array() {
+            Some(entries) => entries.clone(),
+            None => return Err(anyhow!("the manifest of {} has no list of files", self)),
+        };
+        let mut files = Listing::new();
+        for entry in entries {
+            let Some(path) = entry.get("path").and_then(Value::as_str) else {
+                continue;
+            };
+            let is_dir = path.ends_with('/');
+            let rel_path = path.trim_matches('/');
+            if rel_path.is_empty()
+                || rel_path.contains(".DS")
+                || rel_path.split('/').any(|part| part == "..")
+            {
+                continue;
+            }
+            let mut parent = rel_path;
+            while let Some((dir, _)) = parent.rsplit_once('/') {
+                files.entry(dir.to_string()).or_insert_with(dir_entry);
+                parent = dir;
+            }
+            if is_dir {
+                files.insert(rel_path.to_string(), dir_entry());
+                continue;
+            }
+            let size = entry.get("size").and_then(Value::as_u64);
+            let mtime = match entry.get("mtime") {
+                Some(Value::Number(secs)) => secs
+                    .as_u64()
+                    .map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+                Some(Value::String(time)) => parse_time(time),
+                _ => None,
+            };
+            let entry = match (size, mtime) {
+                (Some(size), Some(mtime)) => FileEntry {
+                    mtime,
+                    modified: human_readable_time(mtime),
+                    size: Some(size),
+                    version: None,
+                },
+                _ => match self.head(&self.path(rel_path))? {
+                    Some(entry) => entry,
+                    None => continue,
+                },
+            };
+            files.insert(rel_path.to_string(), entry);
+        }
+        Ok(files)
+    }
+}
+
+impl Location for HttpLocation {
+    fn list(&self) -> Result<Listing> {
+        match &self.manifest {
+            Some(manifest) => self.list_manifest(manifest),
+            None => self.list_index(),
+        }
+    }
+
+    fn stat(&self, rel_path: &str) -> Result<Option<FileEntry>> {
+        Ok(self.list()?.remove(rel_path))
+    }
+
+    fn open_read(&self, rel_path: &str) -> Result<Box<dyn Read + '_>> {
+        let path = self.path(rel_path);
+        let response = self.send("GET", &path, &[], &[])?;
+        // If-Range needs a strong ETag, or else a date
+        let validator = response
+            .header("ETag")
+            .filter(|etag| !etag.starts_with("W/"))
+            .or(response.header("Last-Modified"))
+            .map(str::to_string);
+        let size = response
+            .header("Content-Length")
+            .and_then(|size| size.parse().ok());
+        let resumable = response.header("Accept-Ranges") == Some("bytes");
+        Ok(Box::new(Download {
+            location: self,
+            path,
+            validator: validator.filter(|_| resumable),
+            size,
+            reader: response.into_reader(),
+            offset: 0,
+            retries: 0,
+        }))
+    }
+
+    fn write(&self, _rel_path: &str, _content: &mut dyn Read) -> Result<()> {
+        Err(self.read_only_error())
+    }
+
+    fn mkdir(&self, _rel_path: &str) -> Result<()> {
+        Err(self.read_only_error())
+    }
+
+    fn remove(&self, _rel_path: &str) -> Result<()> {
+        Err(FileErrors::InvalidFileForDelete("HTTP locations are read-only".to_string()).into())
+    }
+
+    fn rename(&self, _from: &str, _to: &str) -> Result<()> {
+        Err(self.read_only_error())
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+}
+
+// The password is never shown (locations are printed in logs)
+impl fmt::Display for HttpLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = if self.secure { "https" } else { "http" };
+        write!(f, "{}://", scheme)?;
+        if let Some(user) = &self.user {
+            write!(f, "{}@", user)?;
+        }
+        write!(f, "{}/{}", self.host, self.root)?;
+        if let Some(manifest) = &self.manifest {
+            write!(f, "/{}", manifest)?;
+        }
+        Ok(())
+    }
+}
+
+// Download that picks up where it stopped (Range: bytes=<offset>-) when the connection drops
+// If-Range makes sure the rest comes from the same version of the file
+struct Download<'a> {
+    location: &'a HttpLocation,
+    path: String,
+    validator: Option<String>, // None when the server can't resume
+    size: Option<u64>,         // Content-Length of the whole file, when known
+    reader: Box<dyn Read + Send + Sync + 'static>,
+    offset: u64,
+    retries: usize,
+}
+
+impl Download<'_> {
+    fn resume(&mut self) -> Result<()> {
+        let validator = self
+            .validator
+            .as_deref()
+            .ok_or_else(|| anyhow!("the server can't resume the download"))?;
+        let range = format!("bytes={}-", self.offset);
+        let response = self.location.send(
+            "GET",
+            &self.path,
+            &[("Range", &range), ("If-Range", validator)],
+            &[],
+        )?;
+        // 200 means the whole file was sent again: it changed since the download started
+        if response.status() != 206 {
+            return Err(anyhow!(
+                "{} changed on the server while it was downloaded",
+                self.path
+            ));
+        }
+        self.reader = response.into_reader();
+        Ok(())
+    }
+}
+
+impl Read for Download<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let error = match self.reader.read(buf) {
+                // A connection closed before Content-Length bytes is a cut too
+                Ok(0) if !buf.is_empty() && self.size.is_some_and(|size| self.offset < size) => {
+                    io::Error::from(io::ErrorKind::UnexpectedEof)
+                }
+                Ok(read) => {
+                    self.offset += read as u64;
+                    return Ok(read);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => e,
+            };
+            if self.retries == RESUME_RETRIES || self.validator.is_none() {
+                return Err(error);
+            }
+            self.retries += 1;
+            println!(
+                "Download of {}{} cut at {} bytes, resuming: {}",
+                self.location.host, self.path, self.offset, error
+            );
+            self.resume().map_err(io::Error::other)?;
+        }
+    }
+}
+
+fn dir_entry() -> FileEntry {
+    FileEntry {
+        mtime: UNIX_EPOCH,
+        modified: "Unknown".to_string(),
+        size: None,
+        version: None,
+    }
+}
+
+// HTTP dates are RFC 2822 ones, manifests may use RFC 3339
+fn parse_time(time: &str) -> Option<SystemTime> {
+    DateTime::parse_from_rfc2822(time.trim())
+        .or_else(|_| DateTime::parse_from_rfc3339(time.trim()))
+        .ok()
+        .map(SystemTime::from)
+}
+
+// Targets of the links of an HTML page
+fn hrefs(page: &str) -> Vec<String> {
+    let mut hrefs = Vec::new();
+    let lower = page.to_ascii_lowercase();
+    let mut start = 0;
+    while let Some(found) = lower[start..].find("href=") {
+        let value = start + found + "href=".len();
+        let quote = page[value..].chars().next();
+        let (value, end) = match quote {
+            Some(quote @ ('"' | '\'')) => {
+                let value = value + 1;
+                match page[value..].find(quote) {
+                    Some(end) => (value, value + end),
+                    None => break,
+                }
+            }
+            _ => {
+                let end = page[value..]
+                    .find(|c: char| c.is_whitespace() || c == '>')
+                    .map_or(page.len(), |end| value + end);
+                (value, end)
+            }
+        };
+        hrefs.push(xml::unescape(&page[value..end]));
+        start = end;
+    }
+    hrefs
+}
+
+// Decoded path on the server (without trailing '/') a link of the page of dir points to, and
+// whether it is a folder; None for links to other servers
+fn resolve(host: &str, dir: &str, href: &str) -> Option<(String, bool)> {
+    let href = href.split(['?', '#']).next().unwrap_or_default();
+    if href.is_empty() {
+        return None;
+    }
+    let path = match href.split_once("://") {
+        Some((_, rest)) => {
+            let (link_host, path) = rest.split_once('/').unwrap_or((rest, ""));
+            if link_host != host {
+                return None;
+            }
+            format!("/{}", path)
+        }
+        None if href.starts_with('/') => href.to_string(),
+        None => format!("{}/{}", dir.trim_end_matches('/'), href),
+    };
+    let is_dir = path.ends_with('/');
+    let mut parts: Vec<&str> = Vec::new();
+    let path = uri_decode(&path);
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    Some((format!("/{}", parts.join("/")), is_dir))
+}
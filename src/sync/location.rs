@@ -0,0 +1,162 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::errors::ArgErrors;
+use crate::sync::{
+    folder, ftp, git, http, mem, rsync, s3, sftp, ssh, tar_archive, webdav, zip_archive,
+};
+
+// What a location knows about one of it's files
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub mtime: SystemTime, // last modification time
+    pub modified: String,  // same time, human readable
+    pub size: Option<u64>, // None for folders
+    // Changes with the content on remotes that have such a tag (WebDAV ETag), so a file rewritten
+    // within the same second with the same size is still seen as changed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+}
+
+impl FileEntry {
+    pub fn is_dir(&self) -> bool {
+        self.size.is_none()
+    }
+}
+
+// Listing of a location, convenient for searching in O(1)
+// K = rel_path_to_file, V = what the location knows about it
+pub type Listing = HashMap<String, FileEntry>;
+
+// A place that can be synchronized with others (a folder, a FTP server, a ZIP archive...)
+// Every path given to a location is relative to it's root and uses '/' as separator
+pub trait Location: fmt::Display + Send + Sync {
+    fn list(&self) -> Result<Listing>; // Every file and folder, recursively
+    fn stat(&self, rel_path: &str) -> Result<Option<FileEntry>>; // None if nothing is found
+    fn open_read(&self, rel_path: &str) -> Result<Box<dyn Read + '_>>; // Read a file as a stream
+    fn write(&self, rel_path: &str, content: &mut dyn Read) -> Result<()>; // Create or overwrite a file from a stream (parents included)
+    fn mkdir(&self, rel_path: &str) -> Result<()>; // Create a folder (parents included)
+    fn remove(&self, rel_path: &str) -> Result<()>; // Remove a file, or a folder with all of it's content
+    fn rename(&self, from: &str, to: &str) -> Result<()>; // Move a file or a folder inside the location
+
+    // Rewrites an existing file from it's new content, reusing the blocks it already has (rsync algorithm)
+    // Only worth it where the old file can be read cheaply, returns None (without reading) otherwise
+    // Some(bytes reused from the old file) when the file was rewritten, it then has the given mtime
+    fn write_delta(
+        &self,
+        _rel_path: &str,
+        _content: &mut dyn Read,
+        _mtime: SystemTime,
+    ) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    // Like write, with the modification time of the source; remotes where changing it afterwards
+    // costs another transfer or rewrite (rsync:, archives) send it along with the content
+    fn write_with_mtime(
+        &self,
+        rel_path: &str,
+        content: &mut dyn Read,
+        mtime: SystemTime,
+    ) -> Result<()> {
+        self.write(rel_path, content)?;
+        self.set_mtime(rel_path, mtime)
+    }
+
+    // Appends to a file that is exactly offset bytes long, for remotes that can't rewrite a part of
+    // a file but can extend it (FTP APPE), returns false (without reading) when it can't be done
+    fn append(&self, _rel_path: &str, _offset: u64, _content: &mut dyn Read) -> Result<bool> {
+        Ok(false)
+    }
+
+    // Give a file the modification time of it's source, remotes that can't do it keep the upload time
+    fn set_mtime(&self, _rel_path: &str, _mtime: SystemTime) -> Result<()> {
+        Ok(())
+    }
+
+    // Read-only locations (like ZIP archives) are only used as sources
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    // Whether the files are still there on the next run, the sync state of a pair with a location
+    // that forgets them (mem:) is only kept for the run
+    fn persistent(&self) -> bool {
+        true
+    }
+
+    // SHA-256 of a file computed by the remote that has it (ssh:), so it's content does not have to
+    // travel; None when it has to be read through open_read
+    fn hash(&self, _rel_path: &str) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    // Local path that the file system watcher can observe for changes
+    fn watch_path(&self) -> Option<&Path> {
+        None
+    }
+}
+
+// Builds a location from what follows "<scheme>:" in the config file
+// The scheme is given too so one backend can serve several schemes (ftp and ftps)
+pub type Factory = fn(scheme: &str, spec: &str) -> Result<Box<dyn Location>>;
+
+// Backends keyed by the scheme of their locations, a new backend only has to be registered here
+pub struct Registry {
+    backends: HashMap<&'static str, Factory>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        let mut registry = Self {
+            backends: HashMap::new(),
+        };
+        registry.register("folder", folder::FolderLocation::open);
+        registry.register("zip", zip_archive::ZipLocation::open);
+        registry.register("zip+rw", zip_archive::ZipLocation::open);
+        registry.register("ftp", ftp::FtpLocation::open);
+        registry.register("ftps", ftp::FtpLocation::open);
+        registry.register("sftp", sftp::SftpLocation::open);
+        registry.register("s3", s3::S3Location::open);
+        registry.register("tar", tar_archive::TarLocation::open);
+        registry.register("tar+rw", tar_archive::TarLocation::open);
+        registry.register("git", git::GitLocation::open);
+        registry.register("mem", mem::MemLocation::open);
+        registry.register("http", http::HttpLocation::open);
+        registry.register("https", http::HttpLocation::open);
+        registry.register("webdav", webdav::WebDavLocation::open);
+        registry.register("webdavs", webdav::WebDavLocation::open);
+        registry.register("ssh", ssh::SshLocation::open);
+        registry.register("rsync", rsync::RsyncLocation::open);
+        registry
+    }
+}
+
+impl Registry {
+    pub fn register(&mut self, scheme: &'static str, factory: Factory) {
+        self.backends.insert(scheme, factory);
+    }
+
+    // Registered schemes, sorted so they can be shown to the user
+    pub fn schemes(&self) -> Vec<&'static str> {
+        let mut schemes: Vec<&'static str> = self.backends.keys().copied().collect();
+        schemes.sort();
+        schemes
+    }
+
+    // Opens a location written as <scheme>:<path_in_location>
+    pub fn open(&self, location: &str) -> Result<Box<dyn Location>> {
+        let (scheme, spec) = location
+            .split_once(':')
+            .ok_or_else(|| ArgErrors::InvalidLocation(location.to_string()))?;
+        match self.backends.get(scheme) {
+            Some(factory) => factory(scheme, spec),
+            None => Err(ArgErrors::InvalidLocation(location.to_string()).into()),
+        }
+    }
+}
@@ -0,0 +1,407 @@
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{Cursor, Read};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::errors::FileErrors;
+use crate::sync::location::{FileEntry, Listing, Location};
+use crate::utils::human_readable_time;
+
+// Files of every mem: location by name, a name means the same files for the whole run
+static STORES: LazyLock<Mutex<HashMap<String, Store>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+type Store = Arc<Mutex<BTreeMap<String, Node>>>;
+
+// A file (with it's content) or a folder
+struct Node {
+    content: Option<Vec<u8>>, // None for folders
+    mtime: SystemTime,
+}
+
+// In-memory file system, nothing touches the disk: mem:<name>[?options]
+// Options:
+//   time=<unix secs>  the clock starts there instead of following the real one
+//   tick=<secs>       how far that clock moves on every change (1 by default)
+//   read_only=true    a source only, like zip:
+// With a fixed clock every run does the same thing, which makes it the backend for tests
+pub struct MemLocation {
+    name: String,
+    files: Store,
+    clock: Mutex<Option<SystemTime>>, // None to follow the real clock
+    tick: Duration,
+    read_only: bool,
+}
+
+impl MemLocation {
+    pub fn open(_scheme: &str, spec: &str) -> Result<Box<dyn Location>> {
+        Ok(Box::new(Self::new(spec)?))
+    }
+
+    // <name>[?options], see MemLocation
+    pub fn new(spec: &str) -> Result<Self> {
+        let (name, query) = spec.split_once('?').unwrap_or((spec, ""));
+        if name.is_empty() {
+            return Err(anyhow!("expected mem:<name>"));
+        }
+        let mut clock = None;
+        let mut tick = Duration::from_secs(1);
+        let mut read_only = false;
+        for option in query.split('&').filter(|option| !option.is_empty()) {
+            match option.split_once('=') {
+                Some(("time", value)) => {
+                    clock = Some(UNIX_EPOCH + Duration::from_secs(value.parse()?));
+                }
+                Some(("tick", value)) => tick = Duration::from_secs(value.parse()?),
+                Some(("read_only", value)) => read_only = value == "true",
+                _ => return Err(anyhow!("unrecognized mem option: {}", option)),
+            }
+        }
+        let files = STORES
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .clone();
+        Ok(Self {
+            name: name.to_string(),
+            files,
+            clock: Mutex::new(clock),
+            tick,
+            read_only,
+        })
+    }
+
+    // Moves a fixed clock forward, as if that much time passed before the next change (the real
+    // clock can't be moved); tests use it to order changes made between two syncs
+    #[cfg(test)]
+    pub fn advance(&self, by: Duration) {
+        if let Some(clock) = self.clock.lock().unwrap().as_mut() {
+            *clock += by;
+        }
+    }
+
+    // Time of a change, a fixed clock moves by one tick after it
+    fn now(&self) -> SystemTime {
+        match self.clock.lock().unwrap().as_mut() {
+            Some(clock) => {
+                let now = *clock;
+                *clock += self.tick;
+                now
+            }
+            None => SystemTime::now(),
+        }
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(FileErrors::InvalidFileForWriting(format!("{} is read-only", self)).into());
+        }
+        Ok(())
+    }
+
+    // Folders above rel_path that don't exist yet are created
+    fn create_parents(files: &mut BTreeMap<String, Node>, rel_path: &str, mtime: SystemTime) {
+        for (end, _) in rel_path.match_indices('/') {
+            files.entry(rel_path[..end].to_string()).or_insert(Node {
+                content: None,
+                mtime,
+            });
+        }
+    }
+}
+
+impl Location for MemLocation {
+    fn list(&self) -> Result<Listing> {
+        let files = self.files.lock().unwrap();
+        Ok(files
+            .iter()
+            .map(|(rel_path, node)| (rel_path.clone(), node.entry()))
+            .collect())
+    }
+
+    fn stat(&self, rel_path: &str) -> Result<Option<FileEntry>> {
+        Ok(self.files.lock().unwrap().get(rel_path).map(Node::entry))
+    }
+
+    fn open_read(&self, rel_path: &str) -> Result<Box<dyn Read + '_>> {
+        match self.files.lock().unwrap().get(rel_path) {
+            Some(Node {
+                content: Some(content),
+                ..
+            }) => Ok(Box::new(Cursor::new(content.clone()))),
+            _ => Err(FileErrors::InvalidFileForReading(format!("{}/{}", self, rel_path)).into()),
+        }
+    }
+
+    // The content is read before the files are locked, a slow source does not block the others
+    fn write(&self, rel_path: &str, content: &mut dyn Read) -> Result<()> {
+        self.check_writable()?;
+        let mut data = Vec::new();
+        content.read_to_end(&mut data)?;
+        let mtime = self.now();
+        let mut files = self.files.lock().unwrap();
+        Self::create_parents(&mut files, rel_path, mtime);
+        files.insert(
+            rel_path.to_string(),
+            Node {
+                content: Some(data),
+                mtime,
+            },
+        );
+        Ok(())
+    }
+
+    fn mkdir(&self, rel_path: &str) -> Result<()> {
+        self.check_writable()?;
+        let mtime = self.now();
+        let mut files = self.files.lock().unwrap();
+        Self::create_parents(&mut files, rel_path, mtime);
+        files.entry(rel_path.to_string()).or_insert(Node {
+            content: None,
+            mtime,
+        });
+        println!("Created: {}/{}", self, rel_path);
+        Ok(())
+    }
+
+    fn remove(&self, rel_path: &str) -> Result<()> {
+        if self.read_only {
+            return Err(FileErrors::InvalidFileForDelete(format!("{} is read-only", self)).into());
+        }
+        let prefix = format!("{}/", rel_path);
+        let mut files = self.files.lock().unwrap();
+        if files.remove(rel_path).is_none() {
+            return Err(FileErrors::InvalidFileForDelete(format!("{}/{}", self, rel_path)).into());
+        }
+        files.retain(|path, _| !path.starts_with(&prefix));
+        println!("Deleted: {}/{}", self, rel_path);
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.check_writable()?;
+        let prefix = format!("{}/", from);
+        let mut files = self.files.lock().unwrap();
+        let moved: Vec<String> = files
+            .keys()
+            .filter(|path| *path == from || path.starts_with(&prefix))
+            .cloned()
+            .collect();
+        if moved.is_empty() {
+            return Err(FileErrors::InvalidFileForReading(format!("{}/{}", self, from)).into());
+        }
+        let mtime = self.now();
+        Self::create_parents(&mut files, to, mtime);
+        for path in moved {
+            if let Some(node) = files.remove(&path) {
+                files.insert(format!("{}{}", to, &path[from.len()..]), node);
+            }
+        }
+        Ok(())
+    }
+
+    fn set_mtime(&self, rel_path: &str, mtime: SystemTime) -> Result<()> {
+        self.check_writable()?;
+        if let Some(node) = self.files.lock().unwrap().get_mut(rel_path) {
+            node.mtime = mtime;
+        }
+        Ok(())
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn persistent(&self) -> bool {
+        false
+    }
+}
+
+impl fmt::Display for MemLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "mem:{}", self.name)
+    }
+}
+
+impl Node {
+    fn entry(&self) -> FileEntry {
+        FileEntry {
+            mtime: self.mtime,
+            modified: human_readable_time(self.mtime),
+            size: self.content.as_ref().map(|content| content.len() as u64),
+            version: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::modes::{ConflictStrategy, SyncOptions};
+    use crate::sync::Synchronizer;
+
+    // The stores live for the whole test run, so every test uses names of it's own
+    // Both sides get a fixed clock starting at the same time, advance orders their changes
+    fn pair(test: &str) -> (MemLocation, MemLocation) {
+        let open = |side| MemLocation::new(&format!("{}_{}?time=1700000000", test, side)).unwrap();
+        (open("a"), open("b"))
+    }
+
+    // One sync of the pair, a being the first location (the source for source-wins)
+    fn sync(a: &MemLocation, b: &MemLocation, conflict: ConflictStrategy) -> Result<()> {
+        let locations = vec![
+            MemLocation::open("mem", &a.name)?,
+            MemLocation::open("mem", &b.name)?,
+        ];
+        let options = SyncOptions {
+            conflict,
+            jobs: 2,
+            ..Default::default()
+        };
+        Synchronizer::new(locations, options).initial_sync()
+    }
+
+    fn write(location: &MemLocation, rel_path: &str, content: &str) {
+        location.write(rel_path, &mut content.as_bytes()).unwrap();
+    }
+
+    fn read(location: &MemLocation, rel_path: &str) -> Option<String> {
+        let mut content = String::new();
+        location
+            .open_read(rel_path)
+            .ok()?
+            .read_to_string(&mut content)
+            .unwrap();
+        Some(content)
+    }
+
+    fn mtime(location: &MemLocation, rel_path: &str) -> SystemTime {
+        location.stat(rel_path).unwrap().unwrap().mtime
+    }
+
+    // Both sides are synced with f.txt, then it's changed in b and, later, in a
+    fn edited_in_both(test: &str) -> (MemLocation, MemLocation) {
+        let (a, b) = pair(test);
+        write(&a, "f.txt", "base");
+        sync(&a, &b, ConflictStrategy::NewestWins).unwrap();
+        b.advance(Duration::from_secs(60));
+        write(&b, "f.txt", "from b");
+        a.advance(Duration::from_secs(120));
+        write(&a, "f.txt", "from a");
+        (a, b)
+    }
+
+    #[test]
+    fn creates_edits_and_deletes_are_propagated() {
+        let (a, b) = pair("propagated");
+        write(&a, "x.txt", "x");
+        write(&a, "d/y.txt", "y");
+        sync(&a, &b, ConflictStrategy::NewestWins).unwrap();
+        assert_eq!(read(&b, "x.txt").as_deref(), Some("x"));
+        assert_eq!(read(&b, "d/y.txt").as_deref(), Some("y"));
+        assert!(b.stat("d").unwrap().unwrap().is_dir());
+        assert_eq!(mtime(&b, "x.txt"), mtime(&a, "x.txt"));
+
+        a.advance(Duration::from_secs(60));
+        write(&a, "x.txt", "edited");
+        write(&b, "z.txt", "z");
+        sync(&a, &b, ConflictStrategy::NewestWins).unwrap();
+        assert_eq!(read(&b, "x.txt").as_deref(), Some("edited"));
+        assert_eq!(mtime(&b, "x.txt"), mtime(&a, "x.txt"));
+        assert_eq!(read(&a, "z.txt").as_deref(), Some("z"));
+
+        b.remove("d/y.txt").unwrap();
+        sync(&a, &b, ConflictStrategy::NewestWins).unwrap();
+        assert_eq!(read(&a, "d/y.txt"), None);
+        assert!(a.stat("d").unwrap().is_some());
+
+        // Nothing changed since, nothing is done
+        let before = (a.list().unwrap(), b.list().unwrap());
+        sync(&a, &b, ConflictStrategy::NewestWins).unwrap();
+        assert_eq!((a.list().unwrap(), b.list().unwrap()), before);
+    }
+
+    #[test]
+    fn newest_wins_keeps_the_latest_edit() {
+        let (a, b) = edited_in_both("newest_wins");
+        sync(&a, &b, ConflictStrategy::NewestWins).unwrap();
+        assert_eq!(read(&a, "f.txt").as_deref(), Some("from a"));
+        assert_eq!(read(&b, "f.txt").as_deref(), Some("from a"));
+    }
+
+    #[test]
+    fn source_wins_keeps_the_first_location() {
+        let (a, b) = pair("source_wins");
+        write(&a, "f.txt", "base");
+        sync(&a, &b, ConflictStrategy::NewestWins).unwrap();
+        // b has the latest edit, but a is the source
+        a.advance(Duration::from_secs(60));
+        write(&a, "f.txt", "from a");
+        b.advance(Duration::from_secs(120));
+        write(&b, "f.txt", "from b");
+        sync(&a, &b, ConflictStrategy::SourceWins).unwrap();
+        assert_eq!(read(&a, "f.txt").as_deref(), Some("from a"));
+        assert_eq!(read(&b, "f.txt").as_deref(), Some("from a"));
+    }
+
+    #[test]
+    fn keep_both_renames_the_older_edit() {
+        let (a, b) = edited_in_both("keep_both");
+        sync(&a, &b, ConflictStrategy::KeepBoth).unwrap();
+        for location in [&a, &b] {
+            assert_eq!(read(location, "f.txt").as_deref(), Some("from a"));
+            let kept: Vec<String> = location
+                .list()
+                .unwrap()
+                .into_keys()
+                .filter(|rel_path| rel_path.starts_with("f.conflict-"))
+                .collect();
+            assert_eq!(kept.len(), 1, "{:?}", kept);
+            assert!(kept[0].ends_with(".txt"));
+            assert_eq!(read(location, &kept[0]).as_deref(), Some("from b"));
+        }
+    }
+
+    #[test]
+    fn fail_leaves_both_edits_and_reports_them() {
+        let (a, b) = edited_in_both("fail");
+        assert!(sync(&a, &b, ConflictStrategy::Fail).is_err());
+        assert_eq!(read(&a, "f.txt").as_deref(), Some("from a"));
+        assert_eq!(read(&b, "f.txt").as_deref(), Some("from b"));
+        // Still a conflict on the next sync
+        assert!(sync(&a, &b, ConflictStrategy::Fail).is_err());
+    }
+
+    #[test]
+    fn deleted_folder_is_deleted_on_the_other_side() {
+        let (a, b) = pair("folder_deleted");
+        write(&a, "d/old.txt", "old");
+        write(&a, "d/e/keep.txt", "keep");
+        sync(&a, &b, ConflictStrategy::NewestWins).unwrap();
+        a.remove("d").unwrap();
+        sync(&a, &b, ConflictStrategy::NewestWins).unwrap();
+        assert!(b.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn deleted_folder_with_new_content_on_the_other_side_is_recreated() {
+        let (a, b) = pair("folder_recreated");
+        write(&a, "d/old.txt", "old");
+        write(&a, "d/edited.txt", "before");
+        sync(&a, &b, ConflictStrategy::NewestWins).unwrap();
+        a.remove("d").unwrap();
+        b.advance(Duration::from_secs(60));
+        write(&b, "d/new.txt", "new");
+        write(&b, "d/edited.txt", "after");
+        sync(&a, &b, ConflictStrategy::NewestWins).unwrap();
+        for location in [&a, &b] {
+            assert_eq!(read(location, "d/new.txt").as_deref(), Some("new"));
+            assert_eq!(read(location, "d/edited.txt").as_deref(), Some("after"));
+            // Unchanged, so it's deletion still goes through
+            assert_eq!(read(location, "d/old.txt"), None);
+        }
+    }
+}
@@ -1,188 +1,104 @@
-use anyhow::Result;
-use filetime::FileTime;
-use ftp::{connect_to_ftp, put_file, read_ftp_file};
+use anyhow::{anyhow, Result};
+use chrono::Local;
 use notify::event::ModifyKind;
 use notify::{RecursiveMode, Watcher};
-use std::cmp::Ordering;
 use std::collections::HashMap;
-use std::path::Path;
+use std::io;
 use std::sync::mpsc;
-use std::time::SystemTime;
-use std::{fmt, thread};
+use std::thread;
 
+mod archive_path;
+mod delta;
+mod execute;
+mod folder;
 mod ftp;
+mod git;
+mod http;
+pub mod location;
+mod mem;
 pub mod modes;
+pub mod plan;
+mod rsync;
+mod s3;
+mod sftp;
+mod ssh;
+mod state;
+mod tar_archive;
+mod tls;
+mod webdav;
+mod xml;
+mod zip_archive;
 
-use crate::utils::*;
-use crate::{errors::*, utils};
-use ftp::*;
-use modes::{CreateType, SyncMode};
+use crate::errors::SyncErrors;
+use crate::utils;
+use execute::Executor;
+use location::{FileEntry, Listing, Location};
+use modes::SyncOptions;
+use plan::{Operation, PairPlan, Planner, SyncPlan};
+use state::{unix_secs, HashReader, PartialFile, SyncState, SyncedFile};
 
-// Option <Vec<Ftp_servers<K, V>>>
-// K = rel_path_to_file
-// V = (File, timestamp, human_readable_timestamp)
-type FtpServers = Option<Vec<HashMap<String, (LocTypes, SystemTime, String)>>>;
+pub use ftp::FtpOptions;
+pub use ssh::serve;
 
-#[derive(Debug, Eq, PartialEq, Hash, Clone)]
-pub enum LocTypes {
-    Ftp(String, String, String, String), // ftp:user:password@URL/path
-    Zip(String),                         // zip:/path/to/archive.zip
-    Folder(String),                      // folder:/path/to/folder
-    SimpleFile(String),                  // /path/to/folder/file.ext
-}
-
-// ReadOnly trait for the ZIP archives
-pub trait ReadOnly {
-    fn list_files(&self) -> Result<HashMap<String, (LocTypes, SystemTime, String)>>; // Returns file paths with last modified times
-    fn read_file(&self) -> Option<Vec<u8>>; // Read files as bytes
-}
-
-// ReadWrite trait that extends ReadOnly for Folder and FTP locations because I can modify them
-pub trait ReadWrite: ReadOnly {
-    fn write_file(&self, content: &[u8]) -> Result<()>; // Write bytes into file
-    fn delete_file(&self) -> Result<()>; // Delete the file
-    fn create_file(&self, path: &str, create_type: CreateType) -> Result<()>; // Create a file in path of type create_type
-}
-
-impl ReadOnly for LocTypes {
-    fn list_files(&self) -> Result<HashMap<String, (LocTypes, SystemTime, String)>> {
-        match self {
-            LocTypes::Ftp(user, pass, url, path) => Ok(connect_to_ftp(user, pass, url, path)?),
-            LocTypes::Zip(path) => Ok(list_files_in_zip(path)?),
-            LocTypes::Folder(path) => Ok(list_files_recursive(path)?),
-            LocTypes::SimpleFile(_) => Err(FileErrors::InvalidFileForListing(
-                "a simple file could not be iterated".to_string(),
-            )
-            .into()),
-        }
+// Hash of a file (checksum mode), taken from the sync state when the file did not change since
+fn file_hash(
+    location: &dyn Location,
+    rel_path: &str,
+    entry: &FileEntry,
+    base: Option<&SyncedFile>,
+    side_a: bool,
+) -> Result<String> {
+    let cached = base
+        .filter(|base| !base.changed(side_a, entry))
+        .and_then(|base| base.hash.clone());
+    if let Some(hash) = cached {
+        return Ok(hash);
     }
-
-    fn read_file(&self) -> Option<Vec<u8>> {
-        match self {
-            LocTypes::Ftp(user, pass, url, path) => read_ftp_file(user, pass, url, path),
-            LocTypes::Zip(path) => file_as_bytes(path),
-            LocTypes::Folder(_) => None,
-            LocTypes::SimpleFile(path) => file_as_bytes(path),
-        }
+    if let Some(hash) = location.hash(rel_path)? {
+        return Ok(hash);
     }
+    let mut reader = HashReader::new(location.open_read(rel_path)?);
+    io::copy(&mut reader, &mut io::sink())?;
+    Ok(reader.hash())
 }
 
-impl ReadWrite for LocTypes {
-    fn write_file(&self, content: &[u8]) -> Result<()> {
-        match self {
-            LocTypes::Ftp(_, _, _, _) => Ok(()),
-            LocTypes::Folder(_) => Err(FileErrors::InvalidFileForWriting(
-                "A folder can't be written".to_string(),
-            )
-            .into()),
-            LocTypes::Zip(_) => {
-                Err(FileErrors::InvalidFileForWriting("ZIP file is read-only".to_string()).into())
-            }
-            LocTypes::SimpleFile(path) => Ok(paste_to_file(path, content)?),
-        }
-    }
-
-    fn delete_file(&self) -> Result<()> {
-        match self {
-            LocTypes::Ftp(user, pass, url, path) => Ok(delete_ftp_file(user, pass, url, path)?),
-            LocTypes::Folder(path) => Ok(delete(path)?),
-            LocTypes::Zip(_) => {
-                Err(FileErrors::InvalidFileForDelete("ZIP file is read-only".to_string()).into())
-            }
-            LocTypes::SimpleFile(path) => Ok(delete(path)?),
-        }
-    }
-
-    fn create_file(&self, path: &str, create_type: CreateType) -> Result<()> {
-        let result_path = format!("{}/{}", self, path);
-        match self {
-            LocTypes::Ftp(user, pass, url, ftp_path) => {
-                let result_path = format!("{}/{}", ftp_path, path);
-                Ok(create_ftp_file(user, pass, url, &result_path, create_type)?)
-            }
-            LocTypes::Folder(_) => Ok(create(&result_path, create_type)?),
-            LocTypes::Zip(_) => {
-                Err(FileErrors::InvalidFileForWriting("ZIP file is read-only".to_string()).into())
-            }
-            LocTypes::SimpleFile(_) => Ok(create(&result_path, create_type)?),
+// Prints the plan of a pair in dry-run mode
+fn print_plan(plan: &PairPlan) {
+    println!("[dry-run] Plan for {} <-> {}:", plan.loc_a, plan.loc_b);
+    for operation in &plan.operations {
+        for line in operation.to_string().lines() {
+            println!("[dry-run] {}", line);
         }
     }
 }
 
-// Function that duplicates the newer file to the locations that has the older file
-// in case a file with the same name and path is found
-fn duplicate_newer_file(
-    file_1: (String, (LocTypes, SystemTime, String)),
-    file_2: (String, (LocTypes, SystemTime, String)),
-) -> Result<()> {
-    let (newer_file, older_file) = {
-        match file_1.1 .1.cmp(&file_2.1 .1) {
-            Ordering::Greater => (file_1, file_2),
-            Ordering::Less => (file_2, file_1),
-            Ordering::Equal => return Ok(()), // Same file
-        }
-    };
-    let bytes = newer_file.1 .0.read_file();
-    let last_modif_time = FileTime::from_system_time(newer_file.1 .1);
-    match bytes {
-        Some(bytes) => {
-            older_file.1 .0.write_file(&bytes)?;
-        }
-        None => {
-            return Err(FileErrors::InvalidFileForReading("Couldn't read file".to_string()).into());
-        }
-    };
-    filetime::set_file_times(
-        older_file.1 .0.to_string(),
-        last_modif_time,
-        last_modif_time,
-    )?;
-
-    Ok(())
-}
-
-// Same thing as the above function but extracts the file from the zip only if the zip is newer
-fn duplicate_newer_file_from_zip(
-    newer_file: (String, (LocTypes, SystemTime, String)),
-    older_file: (String, (LocTypes, SystemTime, String)),
-) -> Result<()> {
-    let bytes = newer_file.1 .0.read_file();
-    let last_modif_time = FileTime::from_system_time(newer_file.1 .1);
-    match bytes {
-        Some(bytes) => {
-            older_file.1 .0.write_file(&bytes)?;
-        }
-        None => {
-            return Err(FileErrors::InvalidFileForReading("Couldn't read file".to_string()).into());
-        }
-    };
-    filetime::set_file_times(
-        older_file.1 .0.to_string(),
-        last_modif_time,
-        last_modif_time,
-    )?;
-
-    Ok(())
-}
-
 // Sync logic struct
 pub struct Synchronizer {
-    locations: Vec<LocTypes>,
-    prev_ftp_files: FtpServers,
+    locations: Vec<Box<dyn Location>>,
+    options: SyncOptions,
 }
 
 impl Synchronizer {
     // Retrieve new instance
-    pub fn new(locations: Vec<LocTypes>, option: FtpServers) -> Self {
-        Self {
-            locations,
-            prev_ftp_files: option,
-        }
+    pub fn new(locations: Vec<Box<dyn Location>>, options: SyncOptions) -> Self {
+        Self { locations, options }
     }
 
     // main function
     pub fn sync(&mut self) -> Result<()> {
-        self.initial_sync(SyncMode::Any)?;
+        if let Some(plan_in) = &self.options.plan_in {
+            // Replaying a reviewed plan, nothing is planned again
+            let plan = SyncPlan::load(plan_in)?;
+            for pair in &plan.pairs {
+                self.execute_pair(pair, None)?;
+            }
+            return Ok(());
+        }
+        self.initial_sync()?;
+        if self.options.dry_run || self.options.plan_out.is_some() {
+            // Only the plan was asked for
+            return Ok(());
+        }
         // Now all the locations should be synchronized
         loop {
             thread::spawn(|| match utils::perform_check() {
@@ -199,239 +115,243 @@ impl Synchronizer {
         }
     }
 
-    fn initial_sync(&self, mode: modes::SyncMode) -> Result<()> {
-        for loc1 in &self.locations {
-            for loc2 in &self.locations {
-                if loc2 == loc1 {
+    // Synchronizes every pair of locations, or only plans it (--dry-run, --plan-out)
+    fn initial_sync(&self) -> Result<()> {
+        let only_plan = self.options.dry_run || self.options.plan_out.is_some();
+        let mut plan = SyncPlan::default();
+        for (index, loc1) in self.locations.iter().enumerate() {
+            for loc2 in &self.locations[index + 1..] {
+                if loc2.to_string() == loc1.to_string() {
                     continue;
                 }
-                // Compare the 2 locations for intial sync
-                let files1 = loc1.list_files()?;
-                let files2 = loc2.list_files()?;
-                for file_1 in files1 {
-                    if files2.contains_key(&file_1.0) {
-                        // O(1) (hashmap)
-                        // If both locations contain a file, the newer version is copied
-                        let file_2 = files2.get_key_value(&file_1.0).unwrap();
-                        // This match has to do with edge-cases of different locations and syncing the newer file found
-                        match (file_1.1 .0.clone(), file_2.1 .0.clone()) {
-                            (LocTypes::SimpleFile(_), LocTypes::Ftp(user, pass, url, ftp_path)) => {
-                                // PUT the file into FTP server
-                                match file_1.1 .1.cmp(&file_2.1 .1) {
-                                    std::cmp::Ordering::Greater => {
-                                        // The SimpleFile is newer and I have to PUT it on the FTP server
-                                        let file_bytes =
-                                            file_1.1 .0.read_file().unwrap_or_default();
-                                        put_file(&file_bytes, &user, &pass, &url, &ftp_path)?;
-                                    }
-                                    std::cmp::Ordering::Less => {
-                                        // The FTP file is newer and needs to overwrite the local file
-                                        let bytes = file_2.1 .0.read_file().unwrap_or_default();
-                                        file_1.1 .0.write_file(&bytes)?;
-                                        let last_modif_time =
-                                            FileTime::from_system_time(file_2.1 .1);
-                                        filetime::set_file_times(
-                                            file_1.1 .0.to_string(),
-                                            last_modif_time,
-                                            last_modif_time,
-                                        )?;
-                                    }
-                                    std::cmp::Ordering::Equal => {
-                                        // same file
-                                    }
-                                }
-                            }
-                            (LocTypes::Zip(_), LocTypes::SimpleFile(_)) => {
-                                if file_1.1 .1 > file_2.1 .1 {
-                                    duplicate_newer_file_from_zip(
-                                        file_1,
-                                        (file_2.0.clone(), file_2.1.clone()),
-                                    )?;
-                                }
-                            }
-                            (LocTypes::SimpleFile(_), LocTypes::SimpleFile(_)) => {
-                                duplicate_newer_file(file_1, (file_2.0.clone(), file_2.1.clone()))?;
+                let (pair, listings) = self.plan_pair(loc1.as_ref(), loc2.as_ref())?;
+                if self.options.dry_run {
+                    print_plan(&pair);
+                }
+                if only_plan {
+                    plan.pairs.push(pair);
+                } else {
+                    self.execute_pair(&pair, Some(listings))?;
+                }
+            }
+        }
+        if let Some(plan_out) = &self.options.plan_out {
+            plan.save(plan_out)?;
+            println!("Plan saved to {}", plan_out.display());
+        }
+        Ok(())
+    }
+
+    // Lists both locations of a pair and plans what has to be done to sync them
+    // Operations on read-only locations are left out, they would do nothing
+    fn plan_pair(
+        &self,
+        loc1: &dyn Location,
+        loc2: &dyn Location,
+    ) -> Result<(PairPlan, (Listing, Listing))> {
+        // The state of a pair is always stored in the same order
+        let (loc_a, loc_b) = if loc1.to_string() <= loc2.to_string() {
+            (loc1, loc2)
+        } else {
+            (loc2, loc1)
+        };
+        let (strategy, loc1_is_source) = self
+            .options
+            .conflict_strategy(&loc1.to_string(), &loc2.to_string());
+        let state = SyncState::load(loc_a, loc_b)?;
+        let files_a = loc_a.list()?;
+        let files_b = loc_b.list()?;
+
+        let (name_a, name_b) = (loc_a.to_string(), loc_b.to_string());
+        let conflict_tag = format!(
+            "conflict-{}-{}",
+            utils::host_name(),
+            Local::now().format("%Y%m%d-%H%M%S")
+        );
+        let planner = Planner {
+            loc_a: &name_a,
+            loc_b: &name_b,
+            strategy,
+            source_is_a: loc1_is_source == (name_a == loc1.to_string()),
+            checksum: self.options.checksum,
+            conflict_tag: &conflict_tag,
+        };
+        let mut plan = planner.plan(
+            &files_a,
+            &files_b,
+            &state.files,
+            &state.partial,
+            &mut |side_a, rel_path, entry| {
+                let location = if side_a { loc_a } else { loc_b };
+                file_hash(location, rel_path, entry, state.files.get(rel_path), side_a)
+            },
+        )?;
+
+        let read_only = |name: &String| {
+            (name == &name_a && loc_a.read_only()) || (name == &name_b && loc_b.read_only())
+        };
+        plan.operations.retain(|operation| match operation {
+            Operation::Copy { target, .. } => !read_only(target),
+            Operation::Delete { location, .. } | Operation::KeepConflict { location, .. } => {
+                !read_only(location)
+            }
+            Operation::Conflict { .. } => true,
+        });
+        Ok((plan, (files_a, files_b)))
+    }
+
+    // Applies the plan of a pair and records what is now synced in the state of the pair
+    // The listings are the ones the plan was made from, they are reused if nothing was touched
+    fn execute_pair(&self, plan: &PairPlan, listings: Option<(Listing, Listing)>) -> Result<()> {
+        let loc_a = self.location(&plan.loc_a)?;
+        let loc_b = self.location(&plan.loc_b)?;
+        let executor = Executor::new(&[loc_a, loc_b], self.options.jobs);
+        let mut hashes = match executor.execute(plan) {
+            Ok(hashes) => hashes,
+            Err(e) => {
+                // What failed copies left is completed (not copied back) on the next sync
+                let partial = executor.partial_files();
+                if !partial.is_empty() {
+                    let mut state = SyncState::load(loc_a, loc_b)?;
+                    state.partial.extend(partial);
+                    state.save()?;
+                }
+                return Err(e);
+            }
+        };
+        hashes.extend(plan.hashes.clone());
+        // Copies are checked against the listing taken afterwards, one that did not arrive whole
+        // is not synced (in_a: the copy went to the first location)
+        let copies: HashMap<&String, (bool, &FileEntry)> = plan
+            .operations
+            .iter()
+            .filter_map(|operation| match operation {
+                Operation::Copy {
+                    target,
+                    rel_path,
+                    entry,
+                    ..
+                } if !entry.is_dir() => Some((rel_path, (*target == plan.loc_a, entry))),
+                _ => None,
+            })
+            .collect();
+
+        let conflicts = plan.conflicts();
+        let (files_a, files_b) = match listings {
+            Some(listings) if plan.operations.len() == conflicts.len() => listings,
+            _ => (loc_a.list()?, loc_b.list()?),
+        };
+        let mut state = SyncState::load(loc_a, loc_b)?;
+        // The new base is everything that is present in both locations
+        // Unresolved conflicts keep their old base, so they are still conflicts on the next sync
+        let mut synced = HashMap::new();
+        let mut partial = HashMap::new();
+        let mut incomplete = Vec::new();
+        for (rel_path, file_a) in &files_a {
+            if conflicts.contains(rel_path) {
+                if let Some(base) = state.files.get(rel_path) {
+                    synced.insert(rel_path.clone(), base.clone());
+                }
+                continue;
+            }
+            if let Some(file_b) = files_b.get(rel_path) {
+                if let Some(&(in_a, copied)) = copies.get(rel_path) {
+                    let (target, source) = if in_a {
+                        (file_a, file_b)
+                    } else {
+                        (file_b, file_a)
+                    };
+                    if target.size != source.size {
+                        // Completed on the next sync if the source is still what was copied
+                        let unchanged = source.mtime == copied.mtime && source.size == copied.size;
+                        match (target.size, source.size) {
+                            (Some(size), Some(source_size))
+                                if unchanged && size > 0 && size < source_size =>
+                            {
+                                partial.insert(
+                                    rel_path.clone(),
+                                    PartialFile {
+                                        in_a,
+                                        size,
+                                        source_mtime: unix_secs(source.mtime),
+                                        source_size,
+                                    },
+                                );
                             }
                             _ => {}
                         }
-                    } else {
-                        // If only a location contains the file, the file is duplicated to the other location
-                        // Also, the below matcher are dealing with different edge-cases where different locations are encountered
-                        // in different sync manner(delete, modify, create)
-                        match loc2 {
-                            LocTypes::Ftp(user, pass, url, ftp_path) => match file_1.1 .0 {
-                                LocTypes::Zip(_) | LocTypes::SimpleFile(_) => match mode {
-                                    SyncMode::Delete => {
-                                        // If only a location cotnains this file and SyncMode is set to delete
-                                        // it means that from the other location someone deleted a file and has
-                                        // to be deleted also from this location
-                                        if let LocTypes::SimpleFile(_) = file_1.1 .0 {
-                                            file_1.1 .0.delete_file()?;
-                                        } else {
-                                            self.initial_sync(SyncMode::Any)?;
-                                        }
-                                        // ZIP files are read-only so they can not be deleted
-                                    }
-                                    _ => {
-                                        loc2.create_file(&file_1.0.to_string(), CreateType::File)?;
-                                        let bytes = file_1.1 .0.read_file();
-                                        match bytes {
-                                            Some(bytes) => {
-                                                let new_ftp_file_path = format!(
-                                                    "{}/{}",
-                                                    ftp_path,
-                                                    &file_1.0.to_string()
-                                                );
-                                                put_file(
-                                                    &bytes,
-                                                    user,
-                                                    pass,
-                                                    url,
-                                                    &new_ftp_file_path,
-                                                )?;
-                                            }
-                                            None => {
-                                                return Err(FileErrors::InvalidFileForReading(
-                                                    "Couldn't read file".to_string(),
-                                                )
-                                                .into());
-                                            }
-                                        };
-                                    }
-                                },
-
-                                _ => {}
-                            },
-                            LocTypes::Folder(_) => match file_1.clone().1 .0 {
-                                LocTypes::Zip(_) | LocTypes::SimpleFile(_) => match mode {
-                                    SyncMode::Delete => {
-                                        // If only a location cotnains this file and SyncMode is set to delete
-                                        // it means that from the other location someone deleted a file and has
-                                        // to be deleted also from this location
-                                        if let LocTypes::SimpleFile(_) = file_1.1 .0 {
-                                            file_1.1 .0.delete_file()?;
-                                        } else {
-                                            self.initial_sync(SyncMode::Any)?;
-                                        }
-                                        // ZIP files are read-only so they can not be deleted
-                                    }
-                                    _ => {
-                                        loc2.create_file(&file_1.0.to_string(), CreateType::File)?;
-                                        let bytes = file_1.1 .0.read_file();
-                                        match bytes {
-                                            Some(bytes) => {
-                                                let new_file_path =
-                                                    format!("{}/{}", loc2, &file_1.0.to_string());
-                                                let new_file = LocTypes::SimpleFile(new_file_path);
-                                                new_file.write_file(&bytes)?;
-                                            }
-                                            None => {
-                                                return Err(FileErrors::InvalidFileForReading(
-                                                    "Couldn't read file".to_string(),
-                                                )
-                                                .into());
-                                            }
-                                        };
-                                    }
-                                },
-                                LocTypes::Ftp(_, _, _, _) => match mode {
-                                    SyncMode::Delete => {
-                                        file_1.clone().1 .0.delete_file()?;
-                                    }
-                                    _ => {
-                                        let path = file_1.clone().0.to_string();
-                                        let file_create =
-                                            loc2.create_file(&path, CreateType::Folder);
-                                        if file_create.is_err() {
-                                            loc2.create_file(
-                                                &file_1.0.to_string(),
-                                                CreateType::File,
-                                            )?;
-                                            let bytes = file_1.clone().1 .0.read_file();
-                                            match bytes {
-                                                Some(bytes) => {
-                                                    let new_file_path = format!(
-                                                        "{}/{}",
-                                                        loc2,
-                                                        &file_1.0.to_string()
-                                                    );
-                                                    let new_file =
-                                                        LocTypes::SimpleFile(new_file_path);
-                                                    new_file.write_file(&bytes)?;
-                                                }
-                                                None => {
-                                                    return Err(FileErrors::InvalidFileForReading(
-                                                        "Couldn't read file".to_string(),
-                                                    )
-                                                    .into());
-                                                }
-                                            };
-                                        }
-                                    }
-                                },
-                                LocTypes::Folder(_) => {
-                                    if let SyncMode::Delete = mode {
-                                        file_1.1 .0.delete_file()?;
-                                    } else {
-                                        loc2.create_file(
-                                            &file_1.0.to_string(),
-                                            CreateType::Folder,
-                                        )?;
-                                    }
-                                }
-                            },
-                            _ => {}
+                        if let Some(base) = state.files.get(rel_path) {
+                            synced.insert(rel_path.clone(), base.clone());
                         }
+                        incomplete.push(rel_path.clone());
+                        continue;
                     }
                 }
+                let mut synced_file = SyncedFile {
+                    mtime_a: unix_secs(file_a.mtime),
+                    size_a: file_a.size,
+                    mtime_b: unix_secs(file_b.mtime),
+                    size_b: file_b.size,
+                    hash: hashes.remove(rel_path),
+                    version_a: file_a.version.clone(),
+                    version_b: file_b.version.clone(),
+                };
+                if let Some(base) = state.files.get(rel_path) {
+                    if synced_file.hash.is_none() && !base.changed(true, file_a) {
+                        synced_file.hash = base.hash.clone();
+                    }
+                }
+                synced.insert(rel_path.clone(), synced_file);
             }
         }
+        state.files = synced;
+        state.partial = partial;
+        state.save()?;
+
+        if !incomplete.is_empty() {
+            incomplete.sort();
+            return Err(SyncErrors::IncompleteCopies(
+                plan.loc_a.clone(),
+                plan.loc_b.clone(),
+                incomplete.join(", "),
+            )
+            .into());
+        }
+
+        if !conflicts.is_empty() {
+            return Err(SyncErrors::Conflicts(
+                plan.loc_a.clone(),
+                plan.loc_b.clone(),
+                conflicts.join(", "),
+            )
+            .into());
+        }
         Ok(())
     }
 
+    // Locations of a plan are found by how they are displayed
+    fn location(&self, name: &str) -> Result<&dyn Location> {
+        self.locations
+            .iter()
+            .find(|location| location.to_string() == name)
+            .map(|location| location.as_ref())
+            .ok_or_else(|| anyhow!("The plan uses a location that is not configured: {}", name))
+    }
+
     // After initialization, this function performs a check to see if all the locations are synced
     // by creating a watcher for system files, and a X seconds GET for FTP servers
-    // and sync the locations found by calling the above function in the corespondent mode
+    // and sync the locations found by calling the above function
     fn continous_sync(&mut self) -> Result<()> {
         let (tx, rx) = mpsc::channel::<Result<notify::Event, notify::Error>>(); // Correct type
         let mut watchers = Vec::new();
         for loc in &self.locations {
-            if let LocTypes::Folder(path) = loc {
+            if let Some(path) = loc.watch_path() {
                 let mut watcher = notify::recommended_watcher(tx.clone())?;
-                watcher.watch(Path::new(path), RecursiveMode::Recursive)?;
+                watcher.watch(path, RecursiveMode::Recursive)?;
                 watchers.push(watcher);
             }
         }
         'result_loop: for res in rx {
-            for loc in &self.locations {
-                if let LocTypes::Ftp(_, _, url, _) = loc {
-                    if let Some(ftp_servers) = &self.prev_ftp_files {
-                        for sv in ftp_servers {
-                            if let LocTypes::Ftp(_, _, url2, _) = &sv.iter().next().unwrap().1 .0 {
-                                if url == url2 {
-                                    let files = loc.list_files()?;
-                                    if files.len() < sv.clone().len() {
-                                        self.initial_sync(SyncMode::Delete)?;
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-            self.prev_ftp_files = {
-                let mut ftps = Vec::new();
-                for loc in &self.locations {
-                    if let LocTypes::Ftp(_, _, _, _) = loc {
-                        ftps.push(loc.list_files()?);
-                    }
-                }
-                if ftps.is_empty() {
-                    None
-                } else {
-                    Some(ftps)
-                }
-            };
             match res {
                 Ok(event) => {
                     for path in &event.paths {
@@ -439,23 +359,22 @@ impl Synchronizer {
                             continue 'result_loop;
                         }
                     }
+                    // The sync state tells which location changed, so every
+                    // kind of change is handled by the same three-way sync
                     match event.kind {
-                        notify::EventKind::Create(_) => {
-                            self.initial_sync(SyncMode::Create)?;
+                        notify::EventKind::Create(_) | notify::EventKind::Remove(_) => {
+                            self.initial_sync()?;
                         }
                         notify::EventKind::Modify(modif_kind) => match modif_kind {
                             ModifyKind::Name(_) => {
-                                self.initial_sync(SyncMode::Delete)?;
+                                self.initial_sync()?;
                             }
                             ModifyKind::Data(_) => {
                                 println!("Modified: {:?}", event.paths);
-                                self.initial_sync(SyncMode::Modify)?;
+                                self.initial_sync()?;
                             }
                             _ => {}
                         },
-                        notify::EventKind::Remove(_) => {
-                            self.initial_sync(SyncMode::Delete)?;
-                        }
                         _ => {}
                     }
                 }
@@ -465,14 +384,3 @@ impl Synchronizer {
         Ok(())
     }
 }
-
-impl fmt::Display for LocTypes {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            LocTypes::Ftp(user, pass, url, path) => write!(f, "{}:{}@{}/{}", user, pass, url, path),
-            LocTypes::Zip(path) => write!(f, "{}", path),
-            LocTypes::Folder(path) => write!(f, "{}", path),
-            LocTypes::SimpleFile(path) => write!(f, "{}", path),
-        }
-    }
-}
@@ -1,14 +1,70 @@
-// Sync mode enum, helpful for knowing if the sync has to delete, modify, or create a file
-// in case a location has something in plus or minus from others
-pub enum SyncMode {
-    Create,
-    Modify,
-    Delete,
-    Any,
-}
-
-// What type of creation has to be done
-pub enum CreateType {
-    File,
-    Folder,
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::errors::ArgErrors;
+
+// What happens to a file that was changed in both locations of a pair since their last sync
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictStrategy {
+    #[default]
+    NewestWins, // the most recent modification time is kept
+    SourceWins, // the source location of the pair is kept
+    KeepBoth,   // the newest is kept, the other is renamed to file.conflict-<host>-<timestamp>.ext
+    Fail,       // nothing is touched, the conflict is reported and the sync fails
+}
+
+impl FromStr for ConflictStrategy {
+    type Err = ArgErrors;
+
+    fn from_str(strategy: &str) -> Result<Self, Self::Err> {
+        match strategy {
+            "newest-wins" => Ok(Self::NewestWins),
+            "source-wins" => Ok(Self::SourceWins),
+            "keep-both" => Ok(Self::KeepBoth),
+            "fail" => Ok(Self::Fail),
+            _ => Err(ArgErrors::InvalidConflictStrategy(strategy.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for ConflictStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NewestWins => write!(f, "newest-wins"),
+            Self::SourceWins => write!(f, "source-wins"),
+            Self::KeepBoth => write!(f, "keep-both"),
+            Self::Fail => write!(f, "fail"),
+        }
+    }
+}
+
+// How the synchronizer compares and copies files, set from the command line
+#[derive(Debug, Default, Clone)]
+pub struct SyncOptions {
+    pub checksum: bool, // compare size + content hash instead of trusting modification times
+    pub dry_run: bool,  // only print the planned operations
+    pub conflict: ConflictStrategy, // for the pairs that have no strategy of their own
+    pub pair_conflicts: HashMap<(String, String), ConflictStrategy>, // (source, other) -> strategy
+    pub plan_out: Option<PathBuf>, // save the plan as JSON instead of executing it
+    pub plan_in: Option<PathBuf>, // execute a saved plan instead of planning
+    pub jobs: usize,    // files copied in parallel
+    pub server: Option<PathBuf>, // serve this folder to an ssh: location instead of syncing
+}
+
+impl SyncOptions {
+    // Strategy of a pair, and whether the first location is it's source
+    // Without a strategy of it's own, the location listed first in the config file is the source
+    pub fn conflict_strategy(&self, loc1: &str, loc2: &str) -> (ConflictStrategy, bool) {
+        let pair = (loc1.to_string(), loc2.to_string());
+        if let Some(strategy) = self.pair_conflicts.get(&pair) {
+            return (*strategy, true);
+        }
+        let pair = (pair.1, pair.0);
+        match self.pair_conflicts.get(&pair) {
+            Some(strategy) => (*strategy, false),
+            None => (self.conflict, true),
+        }
+    }
 }
@@ -0,0 +1,865 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::sync::location::{FileEntry, Listing};
+use crate::sync::modes::ConflictStrategy;
+use crate::sync::state::{unix_secs, PartialFile, SyncedFile};
+use crate::utils;
+
+// What the target already has at the path of a copied file, so that only what changed is sent
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Existing {
+    pub size: u64,
+    pub synced_hash: Option<String>, // hash of the content if it did not change since the last sync
+    #[serde(default)]
+    pub partial: bool, // the start of this very content, left by an interrupted copy
+}
+
+// One step of a plan, locations are named by how they are displayed (never with a password)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    // Copies a file (or creates a folder) from source to target
+    Copy {
+        source: String,
+        target: String,
+        rel_path: String,
+        entry: FileEntry,
+        existing: Option<Existing>,
+        reason: String,
+    },
+    // Removes a file, or a folder with all of it's content
+    Delete {
+        location: String,
+        rel_path: String,
+        entry: FileEntry,
+        reason: String,
+    },
+    // Renames the losing version of a conflict and copies it to the other location
+    KeepConflict {
+        location: String,
+        other: String,
+        rel_path: String,
+        conflict_path: String,
+        entry: FileEntry,
+        reason: String,
+    },
+    // A conflict that is left to the user (fail strategy)
+    Conflict {
+        rel_path: String,
+        reason: String,
+    },
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::Copy {
+                target,
+                rel_path,
+                entry,
+                existing,
+                reason,
+                ..
+            } => {
+                let op = match existing {
+                    Some(existing) if existing.partial => "resume",
+                    Some(_) => "update",
+                    None => "create",
+                };
+                write_planned(f, op, target, rel_path, entry, reason)
+            }
+            Operation::Delete {
+                location,
+                rel_path,
+                entry,
+                reason,
+            } => write_planned(f, "delete", location, rel_path, entry, reason),
+            Operation::KeepConflict {
+                location,
+                other,
+                rel_path,
+                conflict_path,
+                entry,
+                reason,
+            } => {
+                writeln!(
+                    f,
+                    "rename {}/{} -> {} - {}",
+                    location, rel_path, conflict_path, reason
+                )?;
+                write_planned(
+                    f,
+                    "create",
+                    other,
+                    conflict_path,
+                    entry,
+                    "conflicting version",
+                )
+            }
+            Operation::Conflict { rel_path, reason } => {
+                write!(f, "conflict {} - {}", rel_path, reason)
+            }
+        }
+    }
+}
+
+// One line of a plan
+fn write_planned(
+    f: &mut fmt::Formatter<'_>,
+    op: &str,
+    location: &str,
+    rel_path: &str,
+    entry: &FileEntry,
+    reason: &str,
+) -> fmt::Result {
+    let size = match entry.size {
+        Some(size) => utils::human_size(size),
+        None => "folder".to_string(),
+    };
+    write!(
+        f,
+        "{:<6} {}/{} ({}) - {}",
+        op, location, rel_path, size, reason
+    )
+}
+
+// Operations of a location pair
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PairPlan {
+    pub loc_a: String,
+    pub loc_b: String,
+    pub operations: Vec<Operation>,
+    pub hashes: HashMap<String, String>, // hashes of files found identical, for the sync state
+}
+
+impl PairPlan {
+    // Paths of the conflicts that are left to the user
+    pub fn conflicts(&self) -> Vec<String> {
+        self.operations
+            .iter()
+            .filter_map(|operation| match operation {
+                Operation::Conflict { rel_path, .. } => Some(rel_path.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+// Everything a sync would do, can be reviewed and saved as JSON to be replayed later
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SyncPlan {
+    pub pairs: Vec<PairPlan>,
+}
+
+impl SyncPlan {
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+// Decides what has to be done for a location pair, from both listings and their last synced
+// state (three-way diff), so that deletes, creates and edits that happened while the daemon
+// was offline are told apart and propagated in the right direction
+// The only I/O is done by hash, which gives the content hash of a file when it is needed
+pub struct Planner<'a> {
+    pub loc_a: &'a str,
+    pub loc_b: &'a str,
+    pub strategy: ConflictStrategy,
+    pub source_is_a: bool,     // for source-wins
+    pub checksum: bool,        // compare by content hash instead of mtime
+    pub conflict_tag: &'a str, // conflict-<host>-<timestamp>, for keep-both
+}
+
+impl Planner<'_> {
+    pub fn plan(
+        &self,
+        files_a: &Listing,
+        files_b: &Listing,
+        base_files: &HashMap<String, SyncedFile>,
+        partial_files: &HashMap<String, PartialFile>,
+        hash: &mut dyn FnMut(bool, &str, &FileEntry) -> Result<String>,
+    ) -> Result<PairPlan> {
+        let (loc_a, loc_b) = (self.loc_a, self.loc_b);
+        let mut plan = PairPlan {
+            loc_a: loc_a.to_string(),
+            loc_b: loc_b.to_string(),
+            ..Default::default()
+        };
+
+        let mut rel_paths: Vec<&String> = files_a
+            .keys()
+            .chain(files_b.keys())
+            .chain(base_files.keys())
+            .collect();
+        // Sorted so that a folder is always handled before it's content
+        rel_paths.sort();
+        rel_paths.dedup();
+
+        let mut deleted_dirs: Vec<&String> = Vec::new();
+        for rel_path in rel_paths {
+            if deleted_dirs
+                .iter()
+                .any(|dir| rel_path.starts_with(&format!("{}/", dir)))
+            {
+                continue;
+            }
+            let base = base_files.get(rel_path);
+            let copy =
+                |source: &str, target: &str, entry: &FileEntry, existing, reason| Operation::Copy {
+                    source: source.to_string(),
+                    target: target.to_string(),
+                    rel_path: rel_path.clone(),
+                    entry: entry.clone(),
+                    existing,
+                    reason,
+                };
+            let delete = |location: &str, entry: &FileEntry, reason| Operation::Delete {
+                location: location.to_string(),
+                rel_path: rel_path.clone(),
+                entry: entry.clone(),
+                reason,
+            };
+            // What an interrupted copy left is not a version of the file: it's completed from the
+            // source, rewritten when the source changed since, or removed with the source
+            if let Some(partial) = partial_files.get(rel_path) {
+                let (source, target, source_files, target_files) = if partial.in_a {
+                    (loc_b, loc_a, files_b, files_a)
+                } else {
+                    (loc_a, loc_b, files_a, files_b)
+                };
+                let leftover = target_files
+                    .get(rel_path)
+                    .filter(|file| file.size == Some(partial.size));
+                if let Some(leftover) = leftover {
+                    match source_files.get(rel_path).filter(|file| !file.is_dir()) {
+                        Some(file) => {
+                            let resume = unix_secs(file.mtime) == partial.source_mtime
+                                && file.size == Some(partial.source_size);
+                            let reason = if resume {
+                                "interrupted copy".to_string()
+                            } else {
+                                format!("interrupted copy, changed in {} since", source)
+                            };
+                            let existing = Existing {
+                                size: partial.size,
+                                synced_hash: None,
+                                partial: resume,
+                            };
+                            plan.operations.push(copy(
+                                source,
+                                target,
+                                file,
+                                Some(existing),
+                                reason,
+                            ));
+                        }
+                        None => {
+                            let reason = format!("interrupted copy, deleted from {}", source);
+                            plan.operations.push(delete(target, leftover, reason));
+                        }
+                    }
+                    continue;
+                }
+            }
+            match (files_a.get(rel_path), files_b.get(rel_path)) {
+                (Some(file_a), Some(file_b)) => {
+                    if file_a.is_dir() || file_b.is_dir() {
+                        if file_a.is_dir() != file_b.is_dir() {
+                            println!("File and folder with the same path, skipping: {}", rel_path);
+                        }
+                        continue;
+                    }
+                    // Both exist: copy the side that changed since the last sync
+                    // If both changed (or the pair was never synced) it is a conflict
+                    // Files of different sizes differ, checksum mode only hashes the same sizes
+                    let same_size = file_a.size == file_b.size;
+                    let (changed_a, changed_b) = if self.checksum && same_size {
+                        let hash_a = hash(true, rel_path, file_a)?;
+                        let hash_b = hash(false, rel_path, file_b)?;
+                        if hash_a == hash_b {
+                            // Same content, whatever the timestamps say
+                            plan.hashes.insert(rel_path.clone(), hash_a);
+                            continue;
+                        }
+                        (
+                            changed_content(base, true, file_a, &hash_a),
+                            changed_content(base, false, file_b, &hash_b),
+                        )
+                    } else {
+                        (
+                            base.is_none_or(|base| base.changed(true, file_a)),
+                            base.is_none_or(|base| base.changed(false, file_b)),
+                        )
+                    };
+                    let conflict = format!("changed in both locations, {}", self.strategy);
+                    let (a_wins, reason) = match (changed_a, changed_b) {
+                        (true, false) => {
+                            (true, format!("changed in {} since the last sync", loc_a))
+                        }
+                        (false, true) => {
+                            (false, format!("changed in {} since the last sync", loc_b))
+                        }
+                        (false, false) => continue,
+                        (true, true) => match self.strategy {
+                            ConflictStrategy::NewestWins => match file_a.mtime.cmp(&file_b.mtime) {
+                                Ordering::Greater => (true, conflict),
+                                Ordering::Less => (false, conflict),
+                                // Different content with the same time can only be told by it's hash,
+                                // the first location of the pair wins then
+                                Ordering::Equal if self.checksum => (true, conflict),
+                                Ordering::Equal => continue, // Same file
+                            },
+                            ConflictStrategy::SourceWins => (self.source_is_a, conflict),
+                            ConflictStrategy::KeepBoth | ConflictStrategy::Fail => {
+                                // Equal contents are not a conflict (checksum mode already knows)
+                                if !self.checksum && same_size {
+                                    let hash_a = hash(true, rel_path, file_a)?;
+                                    let hash_b = hash(false, rel_path, file_b)?;
+                                    if hash_a == hash_b {
+                                        plan.hashes.insert(rel_path.clone(), hash_a);
+                                        continue;
+                                    }
+                                }
+                                if self.strategy == ConflictStrategy::Fail {
+                                    plan.operations.push(Operation::Conflict {
+                                        rel_path: rel_path.clone(),
+                                        reason: conflict,
+                                    });
+                                    continue;
+                                }
+                                // The newest version stays at the path, the other one is kept
+                                // next to it in both locations
+                                let a_wins = file_a.mtime >= file_b.mtime;
+                                let (location, other, entry) = if a_wins {
+                                    (loc_b, loc_a, file_b)
+                                } else {
+                                    (loc_a, loc_b, file_a)
+                                };
+                                plan.operations.push(Operation::KeepConflict {
+                                    location: location.to_string(),
+                                    other: other.to_string(),
+                                    rel_path: rel_path.clone(),
+                                    conflict_path: self.conflict_path(rel_path),
+                                    entry: entry.clone(),
+                                    reason: conflict.clone(),
+                                });
+                                (a_wins, conflict)
+                            }
+                        },
+                    };
+                    let existing = |file: &FileEntry, changed: bool| Existing {
+                        size: file.size.unwrap_or(0),
+                        synced_hash: base.filter(|_| !changed).and_then(|base| base.hash.clone()),
+                        partial: false,
+                    };
+                    plan.operations.push(if a_wins {
+                        copy(
+                            loc_a,
+                            loc_b,
+                            file_a,
+                            Some(existing(file_b, changed_b)),
+                            reason,
+                        )
+                    } else {
+                        copy(
+                            loc_b,
+                            loc_a,
+                            file_b,
+                            Some(existing(file_a, changed_a)),
+                            reason,
+                        )
+                    });
+                }
+                (Some(file_a), None) => match base {
+                    // It was synced before and is unchanged here, so it was deleted from B
+                    // A folder also needs all of it's content unchanged, otherwise it's kept and
+                    // what is new in it is copied back
+                    Some(base)
+                        if !base.changed(true, file_a)
+                            && unchanged_content(files_a, base_files, true, rel_path) =>
+                    {
+                        let reason = format!("deleted from {} since the last sync", loc_b);
+                        plan.operations.push(delete(loc_a, file_a, reason));
+                        deleted_dirs.push(rel_path);
+                    }
+                    // New in A, or modified in A after B deleted it (the edit is kept)
+                    _ => {
+                        let reason = new_file_reason(base.is_some(), loc_a, loc_b);
+                        plan.operations
+                            .push(copy(loc_a, loc_b, file_a, None, reason));
+                    }
+                },
+                (None, Some(file_b)) => match base {
+                    Some(base)
+                        if !base.changed(false, file_b)
+                            && unchanged_content(files_b, base_files, false, rel_path) =>
+                    {
+                        let reason = format!("deleted from {} since the last sync", loc_a);
+                        plan.operations.push(delete(loc_b, file_b, reason));
+                        deleted_dirs.push(rel_path);
+                    }
+                    _ => {
+                        let reason = new_file_reason(base.is_some(), loc_b, loc_a);
+                        plan.operations
+                            .push(copy(loc_b, loc_a, file_b, None, reason));
+                    }
+                },
+                // Deleted from both locations, it will be dropped from the state
+                (None, None) => continue,
+            }
+        }
+        Ok(plan)
+    }
+
+    // file.ext -> file.conflict-<host>-<timestamp>.ext
+    fn conflict_path(&self, rel_path: &str) -> String {
+        let (dir, name) = match rel_path.rsplit_once('/') {
+            Some((dir, name)) => (format!("{}/", dir), name),
+            None => (String::new(), rel_path),
+        };
+        match name.rsplit_once('.') {
+            Some((stem, ext)) if !stem.is_empty() => {
+                format!("{}{}.{}.{}", dir, stem, self.conflict_tag, ext)
+            }
+            _ => format!("{}{}.{}", dir, name, self.conflict_tag),
+        }
+    }
+}
+
+// Was the content of a file changed since the last sync (checksum mode)
+// Files synced before their hash was known fall back to size and mtime
+fn changed_content(base: Option<&SyncedFile>, side_a: bool, entry: &FileEntry, hash: &str) -> bool {
+    match base {
+        Some(base) => match &base.hash {
+            Some(base_hash) => base_hash != hash,
+            None => base.changed(side_a, entry),
+        },
+        None => true,
+    }
+}
+
+// Is everything inside a folder (nothing for a file) synced and unchanged since the last sync
+fn unchanged_content(
+    files: &Listing,
+    base_files: &HashMap<String, SyncedFile>,
+    side_a: bool,
+    rel_path: &str,
+) -> bool {
+    let prefix = format!("{}/", rel_path);
+    files
+        .iter()
+        .filter(|(path, _)| path.starts_with(&prefix))
+        .all(|(path, entry)| {
+            base_files
+                .get(path)
+                .is_some_and(|base| !base.changed(side_a, entry))
+        })
+}
+
+// Why a file found in only one location is copied to the other
+fn new_file_reason(synced_before: bool, source: &str, target: &str) -> String {
+    if synced_before {
+        format!("changed in {} after it was deleted from {}", source, target)
+    } else {
+        format!("new in {}", source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    const A: &str = "mem:a";
+    const B: &str = "mem:b";
+
+    fn file(secs: u64, size: u64) -> FileEntry {
+        let mtime = UNIX_EPOCH + Duration::from_secs(secs);
+        FileEntry {
+            mtime,
+            modified: utils::human_readable_time(mtime),
+            size: Some(size),
+            version: None,
+        }
+    }
+
+    fn dir() -> FileEntry {
+        FileEntry {
+            mtime: UNIX_EPOCH,
+            modified: "Unknown".to_string(),
+            size: None,
+            version: None,
+        }
+    }
+
+    fn listing(entries: &[(&str, FileEntry)]) -> Listing {
+        entries
+            .iter()
+            .map(|(rel_path, entry)| (rel_path.to_string(), entry.clone()))
+            .collect()
+    }
+
+    // What the last sync recorded for entries that were the same in both locations
+    fn base(entries: &[(&str, FileEntry)]) -> HashMap<String, SyncedFile> {
+        entries
+            .iter()
+            .map(|(rel_path, entry)| {
+                let synced = SyncedFile {
+                    mtime_a: unix_secs(entry.mtime),
+                    size_a: entry.size,
+                    mtime_b: unix_secs(entry.mtime),
+                    size_b: entry.size,
+                    hash: None,
+                    version_a: None,
+                    version_b: None,
+                };
+                (rel_path.to_string(), synced)
+            })
+            .collect()
+    }
+
+    fn planner(strategy: ConflictStrategy) -> Planner<'static> {
+        Planner {
+            loc_a: A,
+            loc_b: B,
+            strategy,
+            source_is_a: true,
+            checksum: false,
+            conflict_tag: "conflict-host-20240101-000000",
+        }
+    }
+
+    fn plan(
+        planner: &Planner,
+        files_a: &Listing,
+        files_b: &Listing,
+        base_files: &HashMap<String, SyncedFile>,
+    ) -> Vec<Operation> {
+        // Contents are told apart by size here
+        let mut hash = |_: bool, _: &str, entry: &FileEntry| Ok(format!("{:?}", entry.size));
+        let plan = planner
+            .plan(files_a, files_b, base_files, &HashMap::new(), &mut hash)
+            .unwrap();
+        plan.operations
+    }
+
+    // (op, location it changes, path) of every operation, in order
+    fn summary(operations: &[Operation]) -> Vec<(&'static str, String, String)> {
+        operations
+            .iter()
+            .map(|operation| match operation {
+                Operation::Copy {
+                    target, rel_path, ..
+                } => ("copy", target.clone(), rel_path.clone()),
+                Operation::Delete {
+                    location, rel_path, ..
+                } => ("delete", location.clone(), rel_path.clone()),
+                Operation::KeepConflict {
+                    location,
+                    conflict_path,
+                    ..
+                } => ("keep", location.clone(), conflict_path.clone()),
+                Operation::Conflict { rel_path, .. } => {
+                    ("conflict", String::new(), rel_path.clone())
+                }
+            })
+            .collect()
+    }
+
+    fn op(op: &'static str, location: &str, rel_path: &str) -> (&'static str, String, String) {
+        (op, location.to_string(), rel_path.to_string())
+    }
+
+    #[test]
+    fn new_files_are_copied_to_the_other_side() {
+        let files_a = listing(&[("d", dir()), ("d/x", file(100, 1))]);
+        let files_b = listing(&[("y", file(100, 2))]);
+        let operations = plan(
+            &planner(ConflictStrategy::NewestWins),
+            &files_a,
+            &files_b,
+            &HashMap::new(),
+        );
+        assert_eq!(
+            summary(&operations),
+            [op("copy", B, "d"), op("copy", B, "d/x"), op("copy", A, "y")]
+        );
+    }
+
+    #[test]
+    fn edit_is_copied_with_what_the_target_had() {
+        let synced = [("x", file(100, 1))];
+        let files_a = listing(&[("x", file(200, 3))]);
+        let files_b = listing(&synced);
+        let mut base_files = base(&synced);
+        base_files.get_mut("x").unwrap().hash = Some("old".to_string());
+        let operations = plan(
+            &planner(ConflictStrategy::NewestWins),
+            &files_a,
+            &files_b,
+            &base_files,
+        );
+        assert_eq!(summary(&operations), [op("copy", B, "x")]);
+        let Operation::Copy { existing, .. } = &operations[0] else {
+            unreachable!()
+        };
+        // b did not change, so it's content is the synced one
+        assert_eq!(
+            existing,
+            &Some(Existing {
+                size: 1,
+                synced_hash: Some("old".to_string()),
+                partial: false,
+            })
+        );
+    }
+
+    #[test]
+    fn unchanged_files_need_nothing() {
+        let synced = [("d", dir()), ("d/x", file(100, 1))];
+        let operations = plan(
+            &planner(ConflictStrategy::NewestWins),
+            &listing(&synced),
+            &listing(&synced),
+            &base(&synced),
+        );
+        assert!(operations.is_empty(), "{:?}", operations);
+    }
+
+    #[test]
+    fn deletes_are_propagated_unless_the_other_side_changed() {
+        let synced = [("x", file(100, 1)), ("y", file(100, 1))];
+        // x was deleted from b, y too but it was edited in a since
+        let files_a = listing(&[("x", file(100, 1)), ("y", file(200, 2))]);
+        let operations = plan(
+            &planner(ConflictStrategy::NewestWins),
+            &files_a,
+            &Listing::new(),
+            &base(&synced),
+        );
+        assert_eq!(
+            summary(&operations),
+            [op("delete", A, "x"), op("copy", B, "y")]
+        );
+    }
+
+    #[test]
+    fn deleted_folder_goes_with_everything_in_it() {
+        let synced = [("d", dir()), ("d/e", dir()), ("d/e/x", file(100, 1))];
+        let operations = plan(
+            &planner(ConflictStrategy::NewestWins),
+            &Listing::new(),
+            &listing(&synced),
+            &base(&synced),
+        );
+        assert_eq!(summary(&operations), [op("delete", B, "d")]);
+    }
+
+    #[test]
+    fn deleted_folder_with_a_new_child_is_recreated() {
+        let synced = [
+            ("d", dir()),
+            ("d/old", file(100, 1)),
+            ("d/edited", file(100, 1)),
+        ];
+        // d was deleted from b, meanwhile a got d/new and a new version of d/edited
+        let files_a = listing(&[
+            ("d", dir()),
+            ("d/old", file(100, 1)),
+            ("d/edited", file(200, 2)),
+            ("d/new", file(200, 1)),
+        ]);
+        let operations = plan(
+            &planner(ConflictStrategy::NewestWins),
+            &files_a,
+            &Listing::new(),
+            &base(&synced),
+        );
+        assert_eq!(
+            summary(&operations),
+            [
+                op("copy", B, "d"),
+                op("copy", B, "d/edited"),
+                op("copy", B, "d/new"),
+                op("delete", A, "d/old"),
+            ]
+        );
+    }
+
+    #[test]
+    fn deleted_folder_with_a_new_child_on_side_b_is_recreated() {
+        let synced = [("d", dir()), ("d/old", file(100, 1))];
+        let files_b = listing(&[
+            ("d", dir()),
+            ("d/old", file(100, 1)),
+            ("d/new", file(200, 1)),
+        ]);
+        let operations = plan(
+            &planner(ConflictStrategy::NewestWins),
+            &Listing::new(),
+            &files_b,
+            &base(&synced),
+        );
+        assert_eq!(
+            summary(&operations),
+            [
+                op("copy", A, "d"),
+                op("copy", A, "d/new"),
+                op("delete", B, "d/old"),
+            ]
+        );
+    }
+
+    // x changed in both locations, b's version is the newest
+    fn conflict(strategy: ConflictStrategy) -> Vec<Operation> {
+        let synced = [("x", file(100, 1))];
+        let files_a = listing(&[("x", file(200, 2))]);
+        let files_b = listing(&[("x", file(300, 3))]);
+        plan(&planner(strategy), &files_a, &files_b, &base(&synced))
+    }
+
+    #[test]
+    fn conflicts_follow_the_strategy() {
+        assert_eq!(
+            summary(&conflict(ConflictStrategy::NewestWins)),
+            [op("copy", A, "x")]
+        );
+        // a is the source
+        assert_eq!(
+            summary(&conflict(ConflictStrategy::SourceWins)),
+            [op("copy", B, "x")]
+        );
+        assert_eq!(
+            summary(&conflict(ConflictStrategy::KeepBoth)),
+            [
+                op("keep", A, "x.conflict-host-20240101-000000"),
+                op("copy", A, "x"),
+            ]
+        );
+        assert_eq!(
+            summary(&conflict(ConflictStrategy::Fail)),
+            [op("conflict", "", "x")]
+        );
+    }
+
+    #[test]
+    fn same_content_in_both_is_not_a_conflict() {
+        let synced = [("x", file(100, 1))];
+        let files_a = listing(&[("x", file(200, 2))]);
+        let files_b = listing(&[("x", file(300, 2))]);
+        let operations = plan(
+            &planner(ConflictStrategy::Fail),
+            &files_a,
+            &files_b,
+            &base(&synced),
+        );
+        assert!(operations.is_empty(), "{:?}", operations);
+    }
+
+    #[test]
+    fn interrupted_copy_is_resumed_rewritten_or_removed() {
+        let partial = |source_mtime| PartialFile {
+            in_a: false,
+            size: 10,
+            source_mtime,
+            source_size: 100,
+        };
+        let files_a = listing(&[("x", file(100, 100))]);
+        let files_b = listing(&[("x", file(500, 10))]);
+        let planner = planner(ConflictStrategy::NewestWins);
+        let mut hash = |_: bool, _: &str, _: &FileEntry| Ok(String::new());
+        let mut plan_with = |files_a: &Listing, partial| {
+            let partial_files = HashMap::from([("x".to_string(), partial)]);
+            planner
+                .plan(
+                    files_a,
+                    &files_b,
+                    &HashMap::new(),
+                    &partial_files,
+                    &mut hash,
+                )
+                .unwrap()
+                .operations
+        };
+
+        let operations = plan_with(&files_a, partial(100));
+        assert_eq!(summary(&operations), [op("copy", B, "x")]);
+        let Operation::Copy { existing, .. } = &operations[0] else {
+            unreachable!()
+        };
+        assert!(existing.as_ref().is_some_and(|existing| existing.partial));
+
+        // The source changed since, the copy starts over
+        let operations = plan_with(&files_a, partial(50));
+        let Operation::Copy { existing, .. } = &operations[0] else {
+            unreachable!()
+        };
+        assert!(existing.as_ref().is_some_and(|existing| !existing.partial));
+
+        // The source is gone, so is what was left of it
+        let operations = plan_with(&Listing::new(), partial(100));
+        assert_eq!(summary(&operations), [op("delete", B, "x")]);
+    }
+
+    #[test]
+    fn checksum_mode_does_not_hash_files_of_different_sizes() {
+        let planner = Planner {
+            checksum: true,
+            ..planner(ConflictStrategy::Fail)
+        };
+        let mut hash = |_: bool, rel_path: &str, _: &FileEntry| -> Result<String> {
+            panic!("{} hashed", rel_path)
+        };
+        let synced = [("x", file(100, 1))];
+        // x changed in a only, y was never synced
+        let files_a = listing(&[("x", file(200, 2)), ("y", file(100, 1))]);
+        let files_b = listing(&[("x", file(100, 1)), ("y", file(100, 2))]);
+        let plan = planner
+            .plan(
+                &files_a,
+                &files_b,
+                &base(&synced),
+                &HashMap::new(),
+                &mut hash,
+            )
+            .unwrap();
+        assert_eq!(
+            summary(&plan.operations),
+            [op("copy", B, "x"), op("conflict", "", "y")]
+        );
+    }
+
+    #[test]
+    fn checksum_mode_compares_contents() {
+        let synced = [("x", file(100, 1))];
+        let files_a = listing(&[("x", file(200, 1))]);
+        let files_b = listing(&[("x", file(300, 1))]);
+        let planner = Planner {
+            checksum: true,
+            ..planner(ConflictStrategy::Fail)
+        };
+        let mut hash = |_: bool, _: &str, _: &FileEntry| Ok("same".to_string());
+        let plan = planner
+            .plan(
+                &files_a,
+                &files_b,
+                &base(&synced),
+                &HashMap::new(),
+                &mut hash,
+            )
+            .unwrap();
+        assert!(plan.operations.is_empty());
+        assert_eq!(plan.hashes["x"], "same");
+    }
+}
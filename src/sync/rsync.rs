@@ -0,0 +1,1406 @@
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD_NO_PAD as BASE64;
+use base64::Engine;
+use md5::{Digest, Md5};
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::errors::FileErrors;
+use crate::sync::delta::MIN_SIZE;
+use crate::sync::location::{FileEntry, Listing, Location};
+use crate::utils::human_readable_time;
+
+const DEFAULT_PORT: u16 = 873;
+const TIMEOUT: Duration = Duration::from_secs(120);
+
+// rsync 3.0 and every daemon since speak protocol 30 (MD5 checksums, varint encodings)
+const PROTOCOL_VERSION: i32 = 30;
+
+// Compatibility flags we never ask for (no "-e." capabilities are sent), a daemon that sets them
+// does not speak the protocol the way we do
+const CF_INC_RECURSE: i32 = 1 << 0;
+const CF_VARINT_FLIST_FLAGS: i32 = 1 << 7;
+
+// Once the transfer starts both directions are multiplexed: [u32 LE (MPLEX_BASE + tag) << 24 | len]
+const MPLEX_BASE: u32 = 7;
+const MSG_DATA: u32 = 0;
+const MSG_ERROR_XFER: u32 = 1;
+const MSG_ERROR: u32 = 3;
+const MSG_WARNING: u32 = 4;
+const MSG_ERROR_SOCKET: u32 = 5;
+const MSG_ERROR_UTF8: u32 = 8;
+const MSG_IO_ERROR: u32 = 22;
+const MSG_ERROR_EXIT: u32 = 86;
+const MSG_NO_SEND: u32 = 102;
+const MAX_FRAME: usize = 32 * 1024 - 4;
+
+// Flags of a file list entry (without CF_VARINT_FLIST_FLAGS they take one or two bytes)
+const XMIT_TOP_DIR: u32 = 1 << 0;
+const XMIT_SAME_MODE: u32 = 1 << 1;
+const XMIT_EXTENDED_FLAGS: u32 = 1 << 2;
+const XMIT_SAME_NAME: u32 = 1 << 5;
+const XMIT_LONG_NAME: u32 = 1 << 6;
+const XMIT_SAME_TIME: u32 = 1 << 7;
+const XMIT_IO_ERROR_ENDLIST: u32 = 1 << 12;
+const XMIT_MOD_NSEC: u32 = 1 << 13;
+
+// File indexes, the negative ones mark the end of a phase
+const NDX_DONE: i32 = -1;
+
+// Item flags sent with a file index
+const ITEM_BASIS_TYPE_FOLLOWS: u16 = 1 << 11;
+const ITEM_XNAME_FOLLOWS: u16 = 1 << 12;
+const ITEM_TRANSFER: u16 = 1 << 15;
+
+// Literal data is sent in chunks of at most this size
+const CHUNK_SIZE: usize = 32 * 1024;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+// Module of an rsync daemon (rsyncd): rsync://[user[:password]@]host[:port]/module/path
+// Without a password in the location, RSYNC_PASSWORD is used when the module asks for one
+// Every operation is a run of the rsync protocol on a connection of it's own: listings pull the
+// file list, reads pull a single file, writes push one with the delta algorithm of rsync (only the
+// blocks the daemon does not have travel), deletions push an empty folder with --delete
+pub struct RsyncLocation {
+    host: String, // host[:port]
+    module: String,
+    path: String, // inside the module, without leading or trailing '/'
+    user: Option<String>,
+    password: Option<String>,
+}
+
+// One entry of a file list
+struct Entry {
+    name: String,
+    size: u64,
+    mtime: i64,
+    mode: u32,
+}
+
+// Block checksums of the daemon's version of a file (the basis of a delta)
+struct Sums {
+    count: i32,
+    blength: i32,
+    s2length: i32,
+    remainder: i32,
+    blocks: Vec<(u32, Vec<u8>)>, // rolling checksum, start of the MD5 of the block
+}
+
+// A run of the protocol with the daemon
+struct Session {
+    input: Demux,
+    output: Mux,
+    seed: i32, // checksum seed chosen by the daemon
+    ndx_in: Ndx,
+    ndx_out: Ndx,
+}
+
+// File indexes are sent as differences from the previous one in the same direction
+struct Ndx {
+    positive: i32,
+    negative: i32,
+}
+
+// What we send, in MSG_DATA frames
+struct Mux {
+    output: BufWriter<TcpStream>,
+    buffer: Vec<u8>,
+}
+
+// What the daemon sends: the data of it's MSG_DATA frames, it's other messages are kept aside
+struct Demux {
+    input: BufReader<TcpStream>,
+    remaining: usize, // data left in the current frame
+    messages: Vec<String>,
+    io_error: bool, // the daemon could not read everything, it's file list is not complete
+}
+
+// Copy of a stream in a temporary file, the file list of a push needs the size of a file
+struct Spool {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+impl RsyncLocation {
+    pub fn open(_scheme: &str, spec: &str) -> Result<Box<dyn Location>> {
+        let spec = spec
+            .strip_prefix("//")
+            .ok_or_else(|| anyhow!("expected rsync://host/module/path"))?;
+        let (credentials, host_path) = match spec.rsplit_once('@') {
+            Some((credentials, host_path)) => (Some(credentials), host_path),
+            None => (None, spec),
+        };
+        let (host, module_path) = host_path.split_once('/').unwrap_or((host_path, ""));
+        let module_path = module_path.trim_matches('/');
+        let (module, path) = module_path.split_once('/').unwrap_or((module_path, ""));
+        if host.is_empty() || module.is_empty() {
+            return Err(anyhow!("expected rsync://host/module/path"));
+        }
+        let (user, password) = match credentials {
+            Some(credentials) => match credentials.split_once(':') {
+                Some((user, password)) => (Some(user.to_string()), Some(password.to_string())),
+                None => (Some(credentials.to_string()), None),
+            },
+            None => (None, None),
+        };
+        Ok(Box::new(Self {
+            host: host.to_string(),
+            module: module.to_string(),
+            path: path.trim_matches('/').to_string(),
+            user,
+            password,
+        }))
+    }
+
+    // Path given to the daemon: module/path/rel_path
+    fn remote(&self, rel_path: &str) -> String {
+        [self.module.as_str(), self.path.as_str(), rel_path]
+            .iter()
+            .filter(|part| !part.is_empty())
+            .copied()
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    // Connects to the module and starts the daemon's side of a run with the given arguments
+    fn start(&self, args: &[&str]) -> Result<Session> {
+        let address = if self.host.contains(':') {
+            self.host.clone()
+        } else {
+            format!("{}:{}", self.host, DEFAULT_PORT)
+        };
+        let stream = TcpStream::connect(&address)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let mut input = BufReader::new(stream.try_clone()?);
+        let mut output = BufWriter::new(stream);
+
+        writeln!(output, "@RSYNCD: {}.0", PROTOCOL_VERSION)?;
+        output.flush()?;
+        let greeting = read_line(&mut input)?;
+        let version = greeting
+            .strip_prefix("@RSYNCD: ")
+            .and_then(|rest| rest.split('.').next())
+            .and_then(|version| version.trim().parse::<i32>().ok())
+            .ok_or_else(|| anyhow!("{} is not an rsync daemon: {}", self.host, greeting))?;
+        if version < PROTOCOL_VERSION {
+            return Err(anyhow!(
+                "the rsync daemon of {} speaks protocol {}, {} or newer is needed",
+                self.host,
+                version,
+                PROTOCOL_VERSION
+            ));
+        }
+
+        writeln!(output, "{}", self.module)?;
+        output.flush()?;
+        loop {
+            let line = read_line(&mut input)?;
+            if line == "@RSYNCD: OK" {
+                break;
+            }
+            if let Some(challenge) = line.strip_prefix("@RSYNCD: AUTHREQD ") {
+                self.authenticate(&mut output, challenge)?;
+            } else if line.starts_with("@ERROR") || line == "@RSYNCD: EXIT" {
+                return Err(anyhow!("rsync daemon {}: {}", self.host, line));
+            }
+            // Anything else is the message of the day
+        }
+
+        for arg in args {
+            output.write_all(arg.as_bytes())?;
+            output.write_all(&[0])?;
+        }
+        output.write_all(&[0])?;
+        output.flush()?;
+
+        // Arguments the daemon refuses are answered with an error line instead of the flags
+        if input.fill_buf()?.first() == Some(&b'@') {
+            return Err(anyhow!(
+                "rsync daemon {}: {}",
+                self.host,
+                read_line(&mut input)?
+            ));
+        }
+        let compat_flags = read_varint(&mut input)?;
+        if compat_flags & (CF_INC_RECURSE | CF_VARINT_FLIST_FLAGS) != 0 {
+            return Err(anyhow!(
+                "the rsync daemon of {} turned on protocol features we did not ask for",
+                self.host
+            ));
+        }
+        let seed = read_int(&mut input)?;
+
+        Ok(Session {
+            input: Demux {
+                input,
+                remaining: 0,
+                messages: Vec::new(),
+                io_error: false,
+            },
+            output: Mux {
+                output,
+                buffer: Vec::new(),
+            },
+            seed,
+            ndx_in: Ndx::new(),
+            ndx_out: Ndx::new(),
+        })
+    }
+
+    // Answers the challenge with base64(MD5(password + challenge))
+    fn authenticate(&self, output: &mut impl Write, challenge: &str) -> Result<()> {
+        let user = match &self.user {
+            Some(user) => user.clone(),
+            None => env::var("USER").unwrap_or_else(|_| "nobody".to_string()),
+        };
+        let password = match &self.password {
+            Some(password) => password.clone(),
+            None => env::var("RSYNC_PASSWORD").map_err(|_| {
+                anyhow!(
+                    "module {} of {} needs a password (in the location or RSYNC_PASSWORD)",
+                    self.module,
+                    self.host
+                )
+            })?,
+        };
+        let mut md5 = Md5::new();
+        md5.update(password.as_bytes());
+        md5.update(challenge.as_bytes());
+        writeln!(output, "{} {}", user, BASE64.encode(md5.finalize()))?;
+        output.flush()?;
+        Ok(())
+    }
+
+    // File list of the daemon for a path (the daemon is the sender and we ask for no file)
+    // The error is set when the daemon could not read everything
+    fn file_list(
+        &self,
+        options: &str,
+        remote: &str,
+    ) -> Result<(Vec<Entry>, Option<anyhow::Error>)> {
+        let mut session = self.start(&["--server", "--sender", options, ".", remote])?;
+        session.write_int(0)?; // no filter rules
+        session.flush()?;
+        let entries = session.recv_file_list()?;
+        session.write_ndx(NDX_DONE)?;
+        session.finish_receiver()?;
+        let io_error = session
+            .input
+            .io_error
+            .then(|| session.failure(format!("{}: the daemon could not read every file", self)));
+        Ok((entries, io_error))
+    }
+
+    // Sends a file list to the daemon and answers it's requests for the last entry with delta
+    // data made from content, returns how many bytes the daemon took from it's own version
+    // rules are receiver filter rules, sent when the push deletes (--delete)
+    fn push(
+        &self,
+        dest: &str,
+        options: &[&str],
+        rules: &[String],
+        entries: &[Entry],
+        mut content: Option<&mut File>,
+    ) -> Result<u64> {
+        let dest = format!("{}/", self.remote(dest));
+        let mut args = vec!["--server"];
+        args.extend_from_slice(options);
+        args.extend_from_slice(&[".", &dest]);
+        let mut session = self.start(&args)?;
+
+        if options.contains(&"--delete") {
+            for rule in rules {
+                session.write_int(rule.len() as i32)?;
+                session.output.write_all(rule.as_bytes())?;
+            }
+            session.write_int(0)?;
+        }
+        for (index, entry) in entries.iter().enumerate() {
+            let top_dir = index == 0 && entry.name == ".";
+            session.send_entry(entry, if top_dir { XMIT_TOP_DIR } else { 0 })?;
+        }
+        session.output.write_all(&[0])?;
+        session.flush()?;
+
+        // The daemon asks for files, then ends each of it's phases (the second one redoes the
+        // files whose checksum did not match)
+        let mut reused = 0;
+        let mut phase = 0;
+        loop {
+            let ndx = session.read_ndx()?;
+            if ndx == NDX_DONE {
+                phase += 1;
+                if phase > 2 {
+                    break;
+                }
+                session.write_ndx(NDX_DONE)?;
+                session.flush()?;
+                continue;
+            }
+            let (iflags, extra) = session.read_item()?;
+            if iflags & ITEM_TRANSFER == 0 {
+                continue;
+            }
+            let sums = session.read_sums()?;
+            let file = match content.as_deref_mut() {
+                Some(file) if ndx as usize == entries.len() - 1 => file,
+                _ => return Err(session.failure(format!("unexpected request for entry {}", ndx))),
+            };
+            session.write_ndx(ndx)?;
+            session.write_item(iflags, &extra)?;
+            session.write_sum_head(&sums)?;
+            reused = session.send_delta(&sums, file)?;
+            session.flush()?;
+        }
+        session.write_ndx(NDX_DONE)?;
+        session.flush()?;
+        // Final goodbye of the daemon
+        if session.read_ndx()? != NDX_DONE {
+            return Err(session.failure("the run did not end properly".to_string()));
+        }
+        Ok(reused)
+    }
+
+    // Pushes a file (and the folders above it) with the content of the spool, the file list
+    // carries it's modification time so the daemon sets it along with the content
+    fn push_file(&self, rel_path: &str, spool: &mut Spool, mtime: SystemTime) -> Result<u64> {
+        let mut entries = folders(rel_path, unix_now());
+        entries.push(Entry {
+            name: rel_path.to_string(),
+            size: spool.size,
+            mtime: unix_secs(mtime),
+            mode: S_IFREG | 0o644,
+        });
+        // -I: the daemon's copy is rewritten even when it has the same size and time
+        self.push("", &["-rtOI"], &[], &entries, Some(&mut spool.file))
+    }
+
+    // Pushes the folder above rel_path with nothing in it but --delete, and filter rules that
+    // protect everything but rel_path
+    fn delete(&self, rel_path: &str) -> Result<()> {
+        let (parent, name) = rel_path.rsplit_once('/').unwrap_or(("", rel_path));
+        let name = escape_pattern(name);
+        let rules = [
+            format!("R /{}", name),
+            format!("R /{}/**", name),
+            "P *".to_string(),
+        ];
+        let top = Entry {
+            name: ".".to_string(),
+            size: 0,
+            mtime: unix_now(),
+            mode: S_IFDIR | 0o755,
+        };
+        self.push(parent, &["-r", "--delete"], &rules, &[top], None)?;
+        Ok(())
+    }
+}
+
+impl Location for RsyncLocation {
+    fn list(&self) -> Result<Listing> {
+        let (entries, io_error) = self.file_list("-r", &format!("{}/", self.remote("")))?;
+        // Like rsync itself, an incomplete list is not trusted (missing files would be deleted)
+        if let Some(e) = io_error {
+            return Err(e);
+        }
+        Ok(entries
+            .iter()
+            .filter(|entry| entry.name != "." && !entry.name.contains(".DS"))
+            .filter_map(|entry| Some((entry.name.clone(), entry.file_entry()?)))
+            .collect())
+    }
+
+    // -d sends a folder as an entry of it's own instead of it's content
+    fn stat(&self, rel_path: &str) -> Result<Option<FileEntry>> {
+        let (entries, _) = self.file_list("-d", &self.remote(rel_path))?;
+        Ok(entries.first().and_then(Entry::file_entry))
+    }
+
+    // The file is asked for without a basis, so all of it comes as literal data
+    fn open_read(&self, rel_path: &str) -> Result<Box<dyn Read + '_>> {
+        let mut session = self.start(&["--server", "--sender", ".", &self.remote(rel_path)])?;
+        session.write_int(0)?; // no filter rules
+        session.flush()?;
+        let entries = session.recv_file_list()?;
+        if entries.len() != 1 || entries[0].mode & S_IFMT != S_IFREG {
+            session.write_ndx(NDX_DONE)?;
+            session.finish_receiver()?;
+            return Err(FileErrors::InvalidFileForReading(format!("{}/{}", self, rel_path)).into());
+        }
+        session.write_ndx(0)?;
+        session.write_item(ITEM_TRANSFER, &[])?;
+        session.write_sum_head(&Sums::empty())?;
+        session.write_ndx(NDX_DONE)?;
+        session.flush()?;
+
+        if session.read_ndx()? != 0 {
+            return Err(session.failure(format!("{}/{} could not be sent", self, rel_path)));
+        }
+        session.read_item()?;
+        session.read_sums()?;
+        Ok(Box::new(Download {
+            session,
+            left: 0,
+            md5: Md5::new(),
+            done: false,
+        }))
+    }
+
+    fn write(&self, rel_path: &str, content: &mut dyn Read) -> Result<()> {
+        self.write_with_mtime(rel_path, content, SystemTime::now())
+    }
+
+    fn write_with_mtime(
+        &self,
+        rel_path: &str,
+        content: &mut dyn Read,
+        mtime: SystemTime,
+    ) -> Result<()> {
+        let mut spool = Spool::new(content)?;
+        self.push_file(rel_path, &mut spool, mtime)?;
+        Ok(())
+    }
+
+    // The daemon sends the block checksums of it's version with every request, so any write is a
+    // delta transfer; this only tells the executor how much was reused (small files are left to
+    // write, like everywhere else)
+    fn write_delta(
+        &self,
+        rel_path: &str,
+        content: &mut dyn Read,
+        mtime: SystemTime,
+    ) -> Result<Option<u64>> {
+        let size = self.stat(rel_path)?.and_then(|entry| entry.size);
+        if size.is_none_or(|size| size < MIN_SIZE) {
+            return Ok(None);
+        }
+        let mut spool = Spool::new(content)?;
+        Ok(Some(self.push_file(rel_path, &mut spool, mtime)?))
+    }
+
+    fn mkdir(&self, rel_path: &str) -> Result<()> {
+        let mut entries = folders(rel_path, unix_now());
+        entries.push(Entry {
+            name: rel_path.to_string(),
+            size: 0,
+            mtime: unix_now(),
+            mode: S_IFDIR | 0o755,
+        });
+        self.push("", &["-r"], &[], &entries, None)?;
+        println!("Created: {}/{}", self, rel_path);
+        Ok(())
+    }
+
+    fn remove(&self, rel_path: &str) -> Result<()> {
+        self.delete(rel_path)?;
+        println!("Deleted: {}/{}", self, rel_path);
+        Ok(())
+    }
+
+    // The protocol can't rename, the file is copied to it's new name and deleted
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let entry = self
+            .stat(from)?
+            .ok_or_else(|| FileErrors::InvalidFileForReading(format!("{}/{}", self, from)))?;
+        if entry.is_dir() {
+            return Err(anyhow!("{}: folders can't be renamed over rsync", self));
+        }
+        let mut spool = Spool::new(&mut self.open_read(from)?)?;
+        self.push_file(to, &mut spool, entry.mtime)?;
+        self.delete(from)
+    }
+
+    // The entry is pushed again with the new time, --size-only makes the daemon keep the content
+    // and only update the time
+    fn set_mtime(&self, rel_path: &str, mtime: SystemTime) -> Result<()> {
+        let Some(size) = self.stat(rel_path)?.and_then(|entry| entry.size) else {
+            return Ok(());
+        };
+        let mtime = unix_secs(mtime);
+        let mut entries = folders(rel_path, mtime);
+        entries.push(Entry {
+            name: rel_path.to_string(),
+            size,
+            mtime,
+            mode: S_IFREG | 0o644,
+        });
+        self.push("", &["-rtO", "--size-only"], &[], &entries, None)?;
+        Ok(())
+    }
+}
+
+// The password is never shown (locations are printed in logs)
+impl fmt::Display for RsyncLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rsync://")?;
+        if let Some(user) = &self.user {
+            write!(f, "{}@", user)?;
+        }
+        write!(f, "{}/{}", self.host, self.remote(""))
+    }
+}
+
+impl Entry {
+    // Only files and folders, symbolic links and devices are not sent without -l and -D anyway
+    fn file_entry(&self) -> Option<FileEntry> {
+        let mtime = UNIX_EPOCH + Duration::from_secs(self.mtime.max(0) as u64);
+        let size = match self.mode & S_IFMT {
+            S_IFDIR => None,
+            S_IFREG => Some(self.size),
+            _ => return None,
+        };
+        Some(FileEntry {
+            mtime,
+            modified: human_readable_time(mtime),
+            size,
+            version: None,
+        })
+    }
+}
+
+impl Sums {
+    // Header of a request without a basis: the whole file is sent
+    fn empty() -> Self {
+        Self {
+            count: 0,
+            blength: 0,
+            s2length: 0,
+            remainder: 0,
+            blocks: Vec::new(),
+        }
+    }
+
+    fn block_len(&self, index: usize) -> usize {
+        if index + 1 == self.count as usize && self.remainder != 0 {
+            self.remainder as usize
+        } else {
+            self.blength as usize
+        }
+    }
+}
+
+impl Ndx {
+    fn new() -> Self {
+        Self {
+            positive: -1,
+            negative: 1,
+        }
+    }
+}
+
+impl Session {
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+
+    // An error of the run, with what the daemon said about it
+    fn failure(&self, what: String) -> anyhow::Error {
+        match self.input.messages.is_empty() {
+            true => anyhow!("{}", what),
+            false => anyhow!("{} ({})", what, self.input.messages.join("; ")),
+        }
+    }
+
+    fn read_u8(&mut self) -> io::Result<u8> {
+        read_u8(&mut self.input)
+    }
+
+    fn read_int(&mut self) -> io::Result<i32> {
+        read_int(&mut self.input)
+    }
+
+    fn read_shortint(&mut self) -> io::Result<u16> {
+        let mut bytes = [0; 2];
+        self.input.read_exact(&mut bytes)?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    fn read_bytes(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        let mut bytes = vec![0; len];
+        self.input.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn write_int(&mut self, value: i32) -> io::Result<()> {
+        self.output.write_all(&value.to_le_bytes())
+    }
+
+    fn read_ndx(&mut self) -> io::Result<i32> {
+        let mut byte = self.read_u8()?;
+        let negative = byte == 0xFF;
+        if negative {
+            byte = self.read_u8()?;
+        } else if byte == 0 {
+            return Ok(NDX_DONE);
+        }
+        let prev = if negative {
+            self.ndx_in.negative
+        } else {
+            self.ndx_in.positive
+        };
+        let num = if byte == 0xFE {
+            let high = self.read_bytes(2)?;
+            if high[0] & 0x80 != 0 {
+                let low = self.read_bytes(2)?;
+                i32::from_le_bytes([high[1], low[0], low[1], high[0] & 0x7F])
+            } else {
+                ((high[0] as i32) << 8) + high[1] as i32 + prev
+            }
+        } else {
+            byte as i32 + prev
+        };
+        if negative {
+            self.ndx_in.negative = num;
+            Ok(-num)
+        } else {
+            self.ndx_in.positive = num;
+            Ok(num)
+        }
+    }
+
+    fn write_ndx(&mut self, ndx: i32) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(6);
+        let (num, diff) = if ndx >= 0 {
+            let diff = ndx - self.ndx_out.positive;
+            self.ndx_out.positive = ndx;
+            (ndx, diff)
+        } else if ndx == NDX_DONE {
+            return self.output.write_all(&[0]);
+        } else {
+            bytes.push(0xFF);
+            let num = -ndx;
+            let diff = num - self.ndx_out.negative;
+            self.ndx_out.negative = num;
+            (num, diff)
+        };
+        if diff > 0 && diff < 0xFE {
+            bytes.push(diff as u8);
+        } else if !(0..=0x7FFF).contains(&diff) {
+            bytes.push(0xFE);
+            bytes.push((num >> 24) as u8 | 0x80);
+            bytes.push(num as u8);
+            bytes.push((num >> 8) as u8);
+            bytes.push((num >> 16) as u8);
+        } else {
+            bytes.push(0xFE);
+            bytes.push((diff >> 8) as u8);
+            bytes.push(diff as u8);
+        }
+        self.output.write_all(&bytes)
+    }
+
+    // Item flags following a file index, and the bytes that come with some of them (kept as they
+    // are, the sender sends them back)
+    fn read_item(&mut self) -> io::Result<(u16, Vec<u8>)> {
+        let iflags = self.read_shortint()?;
+        let mut extra = Vec::new();
+        if iflags & ITEM_BASIS_TYPE_FOLLOWS != 0 {
+            extra.push(self.read_u8()?);
+        }
+        if iflags & ITEM_XNAME_FOLLOWS != 0 {
+            let mut len = self.read_u8()? as usize;
+            extra.push(len as u8);
+            if len & 0x80 != 0 {
+                let low = self.read_u8()?;
+                extra.push(low);
+                len = (len & 0x7F) * 256 + low as usize;
+            }
+            extra.extend(self.read_bytes(len)?);
+        }
+        Ok((iflags, extra))
+    }
+
+    fn write_item(&mut self, iflags: u16, extra: &[u8]) -> io::Result<()> {
+        self.output.write_all(&iflags.to_le_bytes())?;
+        self.output.write_all(extra)
+    }
+
+    fn read_sums(&mut self) -> io::Result<Sums> {
+        let mut sums = Sums::empty();
+        sums.count = self.read_int()?;
+        sums.blength = self.read_int()?;
+        sums.s2length = self.read_int()?;
+        sums.remainder = self.read_int()?;
+        if sums.count < 0 || sums.blength < 0 || !(0..=16).contains(&sums.s2length) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid checksum header",
+            ));
+        }
+        for _ in 0..sums.count {
+            let sum1 = self.read_int()? as u32;
+            let sum2 = self.read_bytes(sums.s2length as usize)?;
+            sums.blocks.push((sum1, sum2));
+        }
+        Ok(sums)
+    }
+
+    fn write_sum_head(&mut self, sums: &Sums) -> io::Result<()> {
+        self.write_int(sums.count)?;
+        self.write_int(sums.blength)?;
+        self.write_int(sums.s2length)?;
+        self.write_int(sums.remainder)
+    }
+
+    fn recv_file_list(&mut self) -> Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        let mut name: Vec<u8> = Vec::new();
+        let (mut mtime, mut mode) = (0, 0);
+        loop {
+            let mut flags = self.read_u8()? as u32;
+            if flags == 0 {
+                break;
+            }
+            if flags & XMIT_EXTENDED_FLAGS != 0 {
+                flags |= (self.read_u8()? as u32) << 8;
+                if flags == XMIT_EXTENDED_FLAGS | XMIT_IO_ERROR_ENDLIST {
+                    read_varint(&mut self.input)?;
+                    self.input.io_error = true;
+                    break;
+                }
+            }
+            let keep = match flags & XMIT_SAME_NAME {
+                0 => 0,
+                _ => self.read_u8()? as usize,
+            };
+            let len = match flags & XMIT_LONG_NAME {
+                0 => self.read_u8()? as usize,
+                _ => read_varint(&mut self.input)? as usize,
+            };
+            if keep > name.len() {
+                return Err(self.failure("invalid file list".to_string()));
+            }
+            name.truncate(keep);
+            name.extend(self.read_bytes(len)?);
+            let size = read_varlong(&mut self.input, 3)?;
+            if flags & XMIT_SAME_TIME == 0 {
+                mtime = read_varlong(&mut self.input, 4)?;
+            }
+            if flags & XMIT_MOD_NSEC != 0 {
+                read_varint(&mut self.input)?;
+            }
+            if flags & XMIT_SAME_MODE == 0 {
+                mode = self.read_int()? as u32;
+            }
+            entries.push(Entry {
+                name: String::from_utf8_lossy(&name).into_owned(),
+                size: size.max(0) as u64,
+                mtime,
+                mode,
+            });
+        }
+        Ok(entries)
+    }
+
+    // Every entry is sent whole, without reusing anything of the previous one
+    fn send_entry(&mut self, entry: &Entry, flags: u32) -> io::Result<()> {
+        self.output.write_all(&[(flags | XMIT_LONG_NAME) as u8])?;
+        write_varint(&mut self.output, entry.name.len() as i32)?;
+        self.output.write_all(entry.name.as_bytes())?;
+        write_varlong(&mut self.output, entry.size as i64, 3)?;
+        write_varlong(&mut self.output, entry.mtime, 4)?;
+        self.write_int(entry.mode as i32)
+    }
+
+    // End of a run where the daemon is the sender, after our last request (and NDX_DONE):
+    // each of it's phases is answered, then come it's statistics and our goodbye
+    fn finish_receiver(&mut self) -> Result<()> {
+        self.flush()?;
+        for _ in 0..2 {
+            self.expect_done()?;
+            self.write_ndx(NDX_DONE)?;
+            self.flush()?;
+        }
+        self.expect_done()?;
+        // Bytes read, written, total size, file list build and transfer times
+        for _ in 0..5 {
+            read_varlong(&mut self.input, 3)?;
+        }
+        self.write_ndx(NDX_DONE)?;
+        self.flush()?;
+        Ok(())
+    }
+
+    fn expect_done(&mut self) -> Result<()> {
+        match self.read_ndx()? {
+            NDX_DONE => Ok(()),
+            ndx => Err(self.failure(format!("unexpected file index {}", ndx))),
+        }
+    }
+
+    // Sends content as tokens: literal data, or references to the blocks of the daemon's version
+    // (rsync's rolling checksum finds them at any offset), then the MD5 of the whole content
+    fn send_delta(&mut self, sums: &Sums, file: &mut File) -> Result<u64> {
+        file.seek(SeekFrom::Start(0))?;
+        let mut table: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (index, (sum1, _)) in sums.blocks.iter().enumerate() {
+            table.entry(*sum1).or_default().push(index);
+        }
+        let blength = sums.blength as usize;
+        let mut md5 = Md5::new();
+        let mut reused = 0;
+
+        // Without blocks (a new file) everything is literal
+        if sums.count == 0 {
+            let mut chunk = vec![0; CHUNK_SIZE];
+            loop {
+                let read = file.read(&mut chunk)?;
+                if read == 0 {
+                    break;
+                }
+                md5.update(&chunk[..read]);
+                self.send_literal(&chunk[..read])?;
+            }
+            self.write_int(0)?;
+            self.output.write_all(&md5.finalize())?;
+            return Ok(0);
+        }
+
+        let mut buffer: Vec<u8> = Vec::new();
+        let mut start = 0; // window start
+        let mut literal = 0; // first byte not sent yet
+        let mut rolling: Option<(u32, u32)> = None;
+        let mut eof = false;
+        loop {
+            // One byte more than a block, so the window can be rolled
+            while !eof && buffer.len() < start + blength + 1 {
+                let len = buffer.len();
+                buffer.resize(len + CHUNK_SIZE.max(blength), 0);
+                let read = file.read(&mut buffer[len..])?;
+                buffer.truncate(len + read);
+                md5.update(&buffer[len..]);
+                eof = read == 0;
+            }
+            let window = blength.min(buffer.len() - start);
+            if window == 0 {
+                break;
+            }
+
+            let (s1, s2) =
+                *rolling.get_or_insert_with(|| checksum1(&buffer[start..start + window]));
+            let found = table
+                .get(&((s1 & 0xFFFF) | (s2 << 16)))
+                .and_then(|candidates| {
+                    let data = &buffer[start..start + window];
+                    let mut strong = None;
+                    candidates.iter().copied().find(|&index| {
+                        sums.block_len(index) == window && {
+                            let strong = strong.get_or_insert_with(|| checksum2(data, self.seed));
+                            sums.blocks[index].1[..] == strong[..sums.s2length as usize]
+                        }
+                    })
+                });
+            if let Some(index) = found {
+                self.send_literal(&buffer[literal..start])?;
+                self.write_int(-(index as i32 + 1))?;
+                reused += window as u64;
+                start += window;
+                literal = start;
+                rolling = None;
+            } else {
+                let out = signed(buffer[start]);
+                let (mut s1, mut s2) = (
+                    s1.wrapping_sub(out),
+                    s2.wrapping_sub((window as u32).wrapping_mul(out)),
+                );
+                // At the end of the content the window shrinks instead
+                if start + window < buffer.len() {
+                    s1 = s1.wrapping_add(signed(buffer[start + window]));
+                    s2 = s2.wrapping_add(s1);
+                }
+                rolling = Some((s1, s2));
+                start += 1;
+                if start - literal >= CHUNK_SIZE {
+                    self.send_literal(&buffer[literal..start])?;
+                    literal = start;
+                }
+            }
+
+            // Forget what was already sent
+            if literal >= CHUNK_SIZE {
+                buffer.drain(..literal);
+                start -= literal;
+                literal = 0;
+            }
+        }
+        self.send_literal(&buffer[literal..])?;
+        self.write_int(0)?;
+        self.output.write_all(&md5.finalize())?;
+        Ok(reused)
+    }
+
+    fn send_literal(&mut self, data: &[u8]) -> io::Result<()> {
+        for chunk in data.chunks(CHUNK_SIZE) {
+            self.write_int(chunk.len() as i32)?;
+            self.output.write_all(chunk)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for Mux {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= MAX_FRAME {
+            self.send_frames(false)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_frames(true)?;
+        self.output.flush()
+    }
+}
+
+impl Mux {
+    // Full frames, and the rest of the buffer too when flushing
+    fn send_frames(&mut self, all: bool) -> io::Result<()> {
+        let mut sent = 0;
+        while self.buffer.len() - sent >= MAX_FRAME || (all && sent < self.buffer.len()) {
+            let len = MAX_FRAME.min(self.buffer.len() - sent);
+            let header = ((MPLEX_BASE + MSG_DATA) << 24) | len as u32;
+            self.output.write_all(&header.to_le_bytes())?;
+            self.output.write_all(&self.buffer[sent..sent + len])?;
+            sent += len;
+        }
+        self.buffer.drain(..sent);
+        Ok(())
+    }
+}
+
+impl Read for Demux {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.remaining == 0 {
+            let mut header = [0; 4];
+            if let Err(e) = self.input.read_exact(&mut header) {
+                return Err(self.explained(e));
+            }
+            let header = u32::from_le_bytes(header);
+            let tag = (header >> 24).wrapping_sub(MPLEX_BASE);
+            let len = (header & 0xFF_FFFF) as usize;
+            if tag == MSG_DATA {
+                self.remaining = len;
+                continue;
+            }
+            let mut payload = vec![0; len];
+            self.input.read_exact(&mut payload)?;
+            let text = || String::from_utf8_lossy(&payload).trim_end().to_string();
+            match tag {
+                MSG_ERROR_XFER | MSG_ERROR | MSG_WARNING | MSG_ERROR_SOCKET | MSG_ERROR_UTF8 => {
+                    self.messages.push(text())
+                }
+                MSG_IO_ERROR => self.io_error = true,
+                MSG_NO_SEND => self
+                    .messages
+                    .push("the daemon could not open the file".to_string()),
+                MSG_ERROR_EXIT => {
+                    let e = io::Error::other("the daemon stopped the transfer");
+                    return Err(self.explained(e));
+                }
+                // Information and log lines, keep-alives...
+                _ => {}
+            }
+        }
+        let len = buf.len().min(self.remaining);
+        let read = self.input.read(&mut buf[..len])?;
+        if read == 0 {
+            return Err(self.explained(io::ErrorKind::UnexpectedEof.into()));
+        }
+        self.remaining -= read;
+        Ok(read)
+    }
+}
+
+impl Demux {
+    // The daemon usually says why it stopped before it does
+    fn explained(&self, e: io::Error) -> io::Error {
+        match self.messages.is_empty() {
+            true => e,
+            false => io::Error::other(format!("{} ({})", e, self.messages.join("; "))),
+        }
+    }
+}
+
+// A file pulled from the daemon, it's MD5 is checked and the run is ended once it was all read
+struct Download {
+    session: Session,
+    left: usize, // bytes left in the current literal token
+    md5: Md5,
+    done: bool,
+}
+
+impl Read for Download {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done {
+            return Ok(0);
+        }
+        while self.left == 0 {
+            let token = self.session.read_int()?;
+            if token < 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "block reference without a basis",
+                ));
+            }
+            if token == 0 {
+                self.done = true;
+                let expected = self.session.read_bytes(16)?;
+                if expected[..] != self.md5.finalize_reset()[..] {
+                    return Err(io::Error::other(
+                        "the file changed while it was sent (MD5 mismatch)",
+                    ));
+                }
+                self.session.finish_receiver().map_err(io::Error::other)?;
+                return Ok(0);
+            }
+            self.left = token as usize;
+        }
+        let len = buf.len().min(self.left);
+        let read = self.session.input.read(&mut buf[..len])?;
+        self.md5.update(&buf[..read]);
+        self.left -= read;
+        Ok(read)
+    }
+}
+
+impl Spool {
+    fn new(content: &mut dyn Read) -> Result<Self> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!(
+            "adv_rsync-{}-{}.spool",
+            process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        let mut spool = Self {
+            path,
+            file: file.try_clone()?,
+            size: 0,
+        };
+        spool.size = io::copy(content, &mut file)?;
+        Ok(spool)
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Folders above rel_path, parents first
+fn folders(rel_path: &str, mtime: i64) -> Vec<Entry> {
+    rel_path
+        .match_indices('/')
+        .map(|(end, _)| Entry {
+            name: rel_path[..end].to_string(),
+            size: 0,
+            mtime,
+            mode: S_IFDIR | 0o755,
+        })
+        .collect()
+}
+
+fn unix_now() -> i64 {
+    unix_secs(SystemTime::now())
+}
+
+fn unix_secs(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() as i64)
+}
+
+// A name in a filter rule, wildcards in it are escaped
+fn escape_pattern(name: &str) -> String {
+    if !name.contains(['*', '?', '[']) {
+        return name.to_string();
+    }
+    let mut escaped = String::new();
+    for c in name.chars() {
+        if matches!(c, '*' | '?' | '[' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// Rolling checksum of rsync (bytes are signed), as it's two halves
+fn checksum1(data: &[u8]) -> (u32, u32) {
+    let (mut s1, mut s2) = (0u32, 0u32);
+    for &byte in data {
+        s1 = s1.wrapping_add(signed(byte));
+        s2 = s2.wrapping_add(s1);
+    }
+    (s1, s2)
+}
+
+// Strong checksum of a block: MD5 of the block and the seed (protocol 30 without CF_CHKSUM_SEED_FIX)
+fn checksum2(data: &[u8], seed: i32) -> [u8; 16] {
+    let mut md5 = Md5::new();
+    md5.update(data);
+    if seed != 0 {
+        md5.update(seed.to_le_bytes());
+    }
+    md5.finalize().into()
+}
+
+fn signed(byte: u8) -> u32 {
+    byte as i8 as i32 as u32
+}
+
+fn read_line(input: &mut impl BufRead) -> Result<String> {
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Err(anyhow!("the rsync daemon closed the connection"));
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn read_u8(input: &mut impl Read) -> io::Result<u8> {
+    let mut byte = [0];
+    input.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_int(input: &mut impl Read) -> io::Result<i32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(i32::from_le_bytes(bytes))
+}
+
+// Variable length integers: the leading one bits of the first byte tell how many bytes follow
+fn read_varint(input: &mut impl Read) -> io::Result<i32> {
+    let first = read_u8(input)?;
+    let extra = (first.leading_ones() as usize).min(6);
+    let mut bytes = [0u8; 5];
+    if extra == 0 {
+        bytes[0] = first;
+    } else {
+        if extra >= bytes.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid varint"));
+        }
+        input.read_exact(&mut bytes[..extra])?;
+        bytes[extra] = first & ((1u8 << (8 - extra)) - 1);
+    }
+    Ok(i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn write_varint(output: &mut impl Write, value: i32) -> io::Result<()> {
+    let mut bytes = [0u8; 5];
+    bytes[1..].copy_from_slice(&value.to_le_bytes());
+    let mut count = 4;
+    while count > 1 && bytes[count] == 0 {
+        count -= 1;
+    }
+    let bit = 1u8 << (8 - count);
+    if bytes[count] >= bit {
+        count += 1;
+        bytes[0] = !(bit - 1);
+    } else if count > 1 {
+        bytes[0] = bytes[count] | !(bit * 2 - 1);
+    } else {
+        bytes[0] = bytes[count];
+    }
+    output.write_all(&bytes[..count])
+}
+
+// Same, with at least min_bytes bytes
+fn read_varlong(input: &mut impl Read, min_bytes: usize) -> io::Result<i64> {
+    let mut head = [0u8; 8];
+    input.read_exact(&mut head[..min_bytes])?;
+    let mut bytes = [0u8; 9];
+    bytes[..min_bytes - 1].copy_from_slice(&head[1..min_bytes]);
+    let extra = (head[0].leading_ones() as usize).min(6);
+    if extra == 0 {
+        bytes[min_bytes - 1] = head[0];
+    } else {
+        if min_bytes + extra > bytes.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid varlong",
+            ));
+        }
+        input.read_exact(&mut bytes[min_bytes - 1..min_bytes - 1 + extra])?;
+        bytes[min_bytes + extra - 1] = head[0] & ((1u8 << (8 - extra)) - 1);
+    }
+    let mut value = [0u8; 8];
+    value.copy_from_slice(&bytes[..8]);
+    Ok(i64::from_le_bytes(value))
+}
+
+fn write_varlong(output: &mut impl Write, value: i64, min_bytes: usize) -> io::Result<()> {
+    let mut bytes = [0u8; 9];
+    bytes[1..].copy_from_slice(&value.to_le_bytes());
+    let mut count = 8;
+    while count > min_bytes && bytes[count] == 0 {
+        count -= 1;
+    }
+    let bit = 1u8 << (7 - count + min_bytes);
+    if bytes[count] >= bit {
+        count += 1;
+        bytes[0] = !(bit - 1);
+    } else if count > min_bytes {
+        bytes[0] = bytes[count] | !(bit * 2 - 1);
+    } else {
+        bytes[0] = bytes[count];
+    }
+    output.write_all(&bytes[..count])
+}
+
+// Runs against a local `rsync --daemon` (skipped when rsync is not installed), or against the
+// module given in ADV_RSYNC_TEST_DAEMON (host:port/module)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::os::unix::fs::MetadataExt;
+    use std::process::{Child, Command};
+    use std::thread;
+
+    struct Daemon {
+        child: Option<Child>,
+        dir: Option<PathBuf>,
+        module: String, // host:port/module
+    }
+
+    impl Drop for Daemon {
+        fn drop(&mut self) {
+            if let Some(child) = self.child.as_mut() {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+            if let Some(dir) = &self.dir {
+                let _ = fs::remove_dir_all(dir);
+            }
+        }
+    }
+
+    fn daemon(name: &str) -> Option<Daemon> {
+        if let Ok(module) = env::var("ADV_RSYNC_TEST_DAEMON") {
+            return Some(Daemon {
+                child: None,
+                dir: None,
+                module,
+            });
+        }
+        let dir = env::temp_dir().join(format!("adv_rsync-test-{}-{}", name, process::id()));
+        fs::create_dir_all(dir.join("data")).unwrap();
+        let owner = fs::metadata(&dir).unwrap();
+        let config = dir.join("rsyncd.conf");
+        fs::write(
+            &config,
+            format!(
+                "use chroot = false\npid file = {}\nlog file = {}\n[data]\npath = {}\nread only = false\nuid = {}\ngid = {}\n",
+                dir.join("rsyncd.pid").display(),
+                dir.join("rsyncd.log").display(),
+                dir.join("data").display(),
+                owner.uid(),
+                owner.gid()
+            ),
+        )
+        .unwrap();
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let child = Command::new("rsync")
+            .arg("--daemon")
+            .arg("--no-detach")
+            .arg(format!("--config={}", config.display()))
+            .arg(format!("--port={}", port))
+            .arg("--address=127.0.0.1")
+            .spawn();
+        let daemon = Daemon {
+            child: child.ok(),
+            dir: Some(dir),
+            module: format!("127.0.0.1:{}/data", port),
+        };
+        daemon.child.as_ref()?;
+        for _ in 0..50 {
+            if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+                return Some(daemon);
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!("rsync --daemon did not start");
+    }
+
+    // Same bytes on every run, no two blocks alike
+    fn content(size: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..size)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    fn read_all(location: &dyn Location, rel_path: &str) -> Vec<u8> {
+        let mut data = Vec::new();
+        location
+            .open_read(rel_path)
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn new_and_changed_files_arrive_whole() {
+        let Some(daemon) = daemon("files") else {
+            eprintln!("rsync is not installed, skipped");
+            return;
+        };
+        let location = RsyncLocation::open("rsync", &format!("//{}/test", daemon.module)).unwrap();
+
+        // A new file has no basis, it's sent as literal data (far more than one read)
+        let mut data = content(300_000, 1);
+        location.write("dir/new.bin", &mut &data[..]).unwrap();
+        let listing = location.list().unwrap();
+        assert_eq!(listing["dir/new.bin"].size, Some(300_000));
+        assert!(listing["dir"].is_dir());
+        assert_eq!(read_all(location.as_ref(), "dir/new.bin"), data);
+
+        // A change in the middle and a grown end only send what the daemon does not have, the
+        // time of the source comes with them
+        data[150_000..155_000].fill(b'x');
+        data.extend(content(10_000, 2));
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let reused = location
+            .write_delta("dir/new.bin", &mut &data[..], mtime)
+            .unwrap()
+            .unwrap();
+        assert!(reused > 250_000, "only {} bytes reused", reused);
+        assert_eq!(read_all(location.as_ref(), "dir/new.bin"), data);
+        assert_eq!(location.stat("dir/new.bin").unwrap().unwrap().mtime, mtime);
+
+        location.rename("dir/new.bin", "dir/moved.bin").unwrap();
+        let listing = location.list().unwrap();
+        assert!(!listing.contains_key("dir/new.bin"));
+        assert_eq!(listing["dir/moved.bin"].mtime, mtime);
+
+        location.remove("dir").unwrap();
+        assert!(location.list().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,912 @@
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fmt;
+use std::io::Read;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::sync::location::{FileEntry, Listing, Location};
+use crate::sync::xml;
+use crate::utils::{human_readable_time, uri_encode};
+
+// Files bigger than this are uploaded in parts, of this size for the first 1000 ones
+const PART_SIZE: u64 = 8 * 1024 * 1024;
+// S3 allows 10000 parts and the size of a file is only known once it has been read, so the part
+// size doubles every 1000 parts: 8 GB in 8 MB parts, then 16 MB parts... up to 4 GB parts, which
+// is enough for the 5 TB an object can have
+const PARTS_PER_SIZE: u64 = 1000;
+
+// DeleteObjects removes at most this many keys per request
+const DELETE_BATCH: usize = 1000;
+
+// S3 (or S3-compatible) bucket: s3://[access_key:secret_key@]bucket[/prefix][?options]
+// Credentials default to AWS_ACCESS_KEY_ID / AWS_SECRET_ACCESS_KEY (and AWS_SESSION_TOKEN)
+pub struct S3Location {
+    bucket: String,
+    prefix: String, // without leading or trailing '/', "" for the whole bucket
+    region: String,
+    endpoint: Option<String>, // custom endpoint (MinIO...), None for AWS
+    path_style: bool,         // http://endpoint/bucket/key instead of http://bucket.endpoint/key
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+    agent: ureq::Agent,
+}
+
+// One object of a listing
+struct Object {
+    key: String,
+    size: u64,
+    last_modified: String,
+    etag: Option<String>, // changes with the content, even within the second of LastModified
+}
+
+impl S3Location {
+    pub fn open(_scheme: &str, spec: &str) -> Result<Box<dyn Location>> {
+        let spec = spec
+            .strip_prefix("//")
+            .ok_or_else(|| anyhow!("expected s3://bucket/prefix"))?;
+        let (spec, query) = spec.split_once('?').unwrap_or((spec, ""));
+        let (credentials, bucket_prefix) = match spec.rsplit_once('@') {
+            Some((credentials, bucket_prefix)) => (credentials.split_once(':'), bucket_prefix),
+            None => (None, spec),
+        };
+        let (bucket, prefix) = bucket_prefix.split_once('/').unwrap_or((bucket_prefix, ""));
+        if bucket.is_empty() {
+            return Err(anyhow!("expected s3://bucket/prefix"));
+        }
+
+        let mut region = env::var("AWS_REGION")
+            .or_else(|_| env::var("AWS_DEFAULT_REGION"))
+            .unwrap_or_else(|_| "us-east-1".to_string());
+        let mut endpoint = env::var("AWS_ENDPOINT_URL").ok();
+        let mut path_style = None;
+        for option in query.split('&').filter(|option| !option.is_empty()) {
+            match option.split_once('=') {
+                Some(("region", value)) => region = value.to_string(),
+                Some(("endpoint", value)) => endpoint = Some(value.to_string()),
+                Some(("path_style", value)) => path_style = Some(value == "true"),
+                _ => return Err(anyhow!("unrecognized S3 option: {}", option)),
+            }
+        }
+        let (access_key, secret_key) = match credentials {
+            Some((access_key, secret_key)) => (access_key.to_string(), secret_key.to_string()),
+            None => (
+                env::var("AWS_ACCESS_KEY_ID").unwrap_or_default(),
+                env::var("AWS_SECRET_ACCESS_KEY").unwrap_or_default(),
+            ),
+        };
+
+        Ok(Box::new(Self {
+            bucket: bucket.to_string(),
+            prefix: prefix.trim_matches('/').to_string(),
+            region,
+            // Custom endpoints (MinIO, tests) rarely have a DNS name for every bucket
+            path_style: path_style.unwrap_or(endpoint.is_some()),
+            endpoint: endpoint.map(|endpoint| endpoint.trim_end_matches('/').to_string()),
+            access_key,
+            secret_key,
+            session_token: env::var("AWS_SESSION_TOKEN").ok(),
+            agent: ureq::AgentBuilder::new().build(),
+        }))
+    }
+
+    fn key(&self, rel_path: &str) -> String {
+        if self.prefix.is_empty() {
+            rel_path.to_string()
+        } else {
+            format!("{}/{}", self.prefix, rel_path)
+        }
+    }
+
+    // (url, host, canonical uri) of a key of the bucket
+    fn url(&self, key: &str) -> (String, String, String) {
+        let endpoint = match &self.endpoint {
+            Some(endpoint) => endpoint.clone(),
+            None => format!("https://s3.{}.amazonaws.com", self.region),
+        };
+        let (scheme, host) = endpoint.split_once("://").unwrap_or(("https", &endpoint));
+        let (host, path) = if self.path_style {
+            (host.to_string(), format!("/{}/{}", self.bucket, key))
+        } else {
+            (format!("{}.{}", self.bucket, host), format!("/{}", key))
+        };
+        let path = uri_encode(&path, false);
+        (format!("{}://{}{}", scheme, host, path), host, path)
+    }
+
+    // Sends a request signed with AWS Signature Version 4
+    // Error statuses are turned into errors, except the ones listed in allowed
+    fn send(
+        &self,
+        method: &str,
+        key: &str,
+        query: &[(&str, &str)],
+        headers: &[(&str, String)],
+        body: &[u8],
+        allowed: &[u16],
+    ) -> Result<ureq::Response> {
+        let (url, host, path) = self.url(key);
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex::encode(Sha256::digest(body));
+
+        let mut query: Vec<(String, String)> = query
+            .iter()
+            .map(|(name, value)| (uri_encode(name, true), uri_encode(value, true)))
+            .collect();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let mut headers: Vec<(String, String)> = headers
+            .iter()
+            .map(|(name, value)| (name.to_lowercase(), value.trim().to_string()))
+            .collect();
+        headers.push(("host".to_string(), host));
+        headers.push(("x-amz-content-sha256".to_string(), payload_hash.clone()));
+        headers.push(("x-amz-date".to_string(), amz_date.clone()));
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token".to_string(), token.clone()));
+        }
+        headers.sort();
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value))
+            .collect();
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method, path, query, canonical_headers, signed_headers, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request))
+        );
+        let mut signing_key = hmac(format!("AWS4{}", self.secret_key).as_bytes(), &date);
+        for part in [self.region.as_str(), "s3", "aws4_request"] {
+            signing_key = hmac(&signing_key, part);
+        }
+        let signature = hex::encode(hmac(&signing_key, &string_to_sign));
+
+        let url = if query.is_empty() {
+            url
+        } else {
+            format!("{}?{}", url, query)
+        };
+        let mut request = self.agent.request(method, &url).set(
+            "Authorization",
+            &format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.access_key, scope, signed_headers, signature
+            ),
+        );
+        for (name, value) in headers.iter().filter(|(name, _)| name != "host") {
+            request = request.set(name, value);
+        }
+        match request.send_bytes(body) {
+            Ok(response) => Ok(response),
+            Err(ureq::Error::Status(status, response)) if allowed.contains(&status) => Ok(response),
+            Err(ureq::Error::Status(status, response)) => {
+                let body = response.into_string().unwrap_or_default();
+                let code = xml::value(&body, "Code").unwrap_or_default();
+                Err(anyhow!(
+                    "S3 {} {}/{} failed: {} {}",
+                    method,
+                    self.bucket,
+                    key,
+                    status,
+                    code
+                ))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Every object whose key starts with prefix (ListObjectsV2, one page of 1000 at a time)
+    fn list_objects(&self, prefix: &str, max_keys: Option<&str>) -> Result<Vec<Object>> {
+        let mut objects = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", prefix)];
+            if let Some(max_keys) = max_keys {
+                query.push(("max-keys", max_keys));
+            }
+            if let Some(token) = &token {
+                query.push(("continuation-token", token.as_str()));
+            }
+            let body = self.send("GET", "", &query, &[], &[], &[])?.into_string()?;
+            for contents in xml::blocks(&body, "Contents") {
+                objects.push(Object {
+                    key: xml::value(contents, "Key").unwrap_or_default(),
+                    size: xml::value(contents, "Size")
+                        .and_then(|size| size.parse().ok())
+                        .unwrap_or(0),
+                    last_modified: xml::value(contents, "LastModified").unwrap_or_default(),
+                    etag: xml::value(contents, "ETag"),
+                });
+            }
+            token = xml::value(&body, "NextContinuationToken");
+            if max_keys.is_some() || xml::value(&body, "IsTruncated").as_deref() != Some("true") {
+                break;
+            }
+            if token.is_none() {
+                break;
+            }
+        }
+        Ok(objects)
+    }
+
+    // Keys of an object, or of everything under a "folder"
+    fn keys_under(&self, key: &str) -> Result<Vec<String>> {
+        let mut keys: Vec<String> = self
+            .list_objects(&format!("{}/", key), None)?
+            .into_iter()
+            .map(|object| object.key)
+            .collect();
+        if self.send("HEAD", key, &[], &[], &[], &[404])?.status() == 200 {
+            keys.push(key.to_string());
+        }
+        Ok(keys)
+    }
+
+    // DeleteObjects, in batches
+    fn delete_keys(&self, keys: &[String]) -> Result<()> {
+        for batch in keys.chunks(DELETE_BATCH) {
+            let mut body = String::from("<Delete><Quiet>true</Quiet>");
+            for key in batch {
+                body.push_str(&format!("<Object><Key>{}</Key></Object>", xml::escape(key)));
+            }
+            body.push_str("</Delete>");
+            let content_md5 = BASE64.encode(Md5::digest(body.as_bytes()));
+            let response = self
+                .send(
+                    "POST",
+                    "",
+                    &[("delete", "")],
+                    &[("Content-MD5", content_md5)],
+                    body.as_bytes(),
+                    &[],
+                )?
+                .into_string()?;
+            if let Some(error) = xml::blocks(&response, "Error").first() {
+                return Err(anyhow!(
+                    "S3 could not delete {}: {}",
+                    xml::value(error, "Key").unwrap_or_default(),
+                    xml::value(error, "Code").unwrap_or_default()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Uploads a big file part by part, the upload is aborted if any part fails
+    fn multipart_upload(&self, key: &str, first: Vec<u8>, content: &mut dyn Read) -> Result<()> {
+        let response = self
+            .send("POST", key, &[("uploads", "")], &[], &[], &[])?
+            .into_string()?;
+        let upload_id = xml::value(&response, "UploadId")
+            .ok_or_else(|| anyhow!("S3 did not start the upload of {}", key))?;
+
+        let result = (|| -> Result<()> {
+            let mut parts = String::new();
+            let mut part = first;
+            for number in 1.. {
+                let next_size = part_size(number + 1);
+                let number = number.to_string();
+                let response = self.send(
+                    "PUT",
+                    key,
+                    &[
+                        ("partNumber", number.as_str()),
+                        ("uploadId", upload_id.as_str()),
+                    ],
+                    &[],
+                    &part,
+                    &[],
+                )?;
+                let etag = response.header("ETag").unwrap_or_default();
+                parts.push_str(&format!(
+                    "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                    number,
+                    xml::escape(etag)
+                ));
+                part.clear();
+                content.take(next_size).read_to_end(&mut part)?;
+                if part.is_empty() {
+                    break;
+                }
+            }
+            let body = format!(
+                "<CompleteMultipartUpload>{}</CompleteMultipartUpload>",
+                parts
+            );
+            let response = self
+                .send(
+                    "POST",
+                    key,
+                    &[("uploadId", upload_id.as_str())],
+                    &[],
+                    body.as_bytes(),
+                    &[],
+                )?
+                .into_string()?;
+            // The request can succeed and the completion still fail
+            if response.contains("<Error>") {
+                return Err(anyhow!(
+                    "S3 could not complete the upload of {}: {}",
+                    key,
+                    xml::value(&response, "Code").unwrap_or_default()
+                ));
+            }
+            Ok(())
+        })();
+        if result.is_err() {
+            let _ = self.send(
+                "DELETE",
+                key,
+                &[("uploadId", upload_id.as_str())],
+                &[],
+                &[],
+                &[],
+            );
+        }
+        result
+    }
+}
+
+impl Location for S3Location {
+    // Objects become files, keys ending with '/' (and the prefixes of keys) become folders
+    fn list(&self) -> Result<Listing> {
+        let list_prefix = if self.prefix.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", self.prefix)
+        };
+        let mut files = Listing::new();
+        for object in self.list_objects(&list_prefix, None)? {
+            let Some(rel_path) = object.key.strip_prefix(&list_prefix) else {
+                continue;
+            };
+            let is_dir = rel_path.ends_with('/');
+            let rel_path = rel_path.trim_end_matches('/');
+            if rel_path.is_empty() || rel_path.contains(".DS") {
+                continue;
+            }
+            let mut parent = rel_path;
+            while let Some((dir, _)) = parent.rsplit_once('/') {
+                files.entry(dir.to_string()).or_insert_with(dir_entry);
+                parent = dir;
+            }
+            let entry = if is_dir {
+                dir_entry()
+            } else {
+                let mtime = DateTime::parse_from_rfc3339(&object.last_modified)
+                    .map(SystemTime::from)
+                    .unwrap_or(UNIX_EPOCH);
+                FileEntry {
+                    mtime,
+                    modified: human_readable_time(mtime),
+                    size: Some(object.size),
+                    version: object.etag,
+                }
+            };
+            files.insert(rel_path.to_string(), entry);
+        }
+        Ok(files)
+    }
+
+    fn stat(&self, rel_path: &str) -> Result<Option<FileEntry>> {
+        let key = self.key(rel_path);
+        let response = self.send("HEAD", &key, &[], &[], &[], &[404])?;
+        if response.status() == 200 {
+            let mtime = response
+                .header("Last-Modified")
+                .and_then(|time| DateTime::parse_from_rfc2822(time).ok())
+                .map(SystemTime::from)
+                .unwrap_or(UNIX_EPOCH);
+            let size = response
+                .header("Content-Length")
+                .and_then(|size| size.parse().ok());
+            return Ok(Some(FileEntry {
+                mtime,
+                modified: human_readable_time(mtime),
+                size,
+                version: response.header("ETag").map(str::to_string),
+            }));
+        }
+        // Folders only exist as the prefix of other keys
+        let is_dir = !self
+            .list_objects(&format!("{}/", key), Some("1"))?
+            .is_empty();
+        Ok(is_dir.then(dir_entry))
+    }
+
+    fn open_read(&self, rel_path: &str) -> Result<Box<dyn Read + '_>> {
+        let response = self.send("GET", &self.key(rel_path), &[], &[], &[], &[])?;
+        Ok(Box::new(response.into_reader()))
+    }
+
+    // Small files are sent in one request, unless the object already has the same content
+    // (the ETag of an object uploaded in one part is the MD5 of it's content)
+    // Big ones use a multipart upload
+    fn write(&self, rel_path: &str, content: &mut dyn Read) -> Result<()> {
+        let key = self.key(rel_path);
+        let mut first = Vec::new();
+        content.take(PART_SIZE).read_to_end(&mut first)?;
+        if first.len() as u64 == PART_SIZE {
+            return self.multipart_upload(&key, first, content);
+        }
+
+        let md5 = Md5::digest(&first);
+        let head = self.send("HEAD", &key, &[], &[], &[], &[404])?;
+        let same_size = head.header("Content-Length") == Some(&first.len().to_string());
+        let etag = head.header("ETag").unwrap_or_default().trim_matches('"');
+        if head.status() == 200 && same_size && etag == hex::encode(md5) {
+            println!("Unchanged on S3, not uploaded: {}/{}", self, rel_path);
+            return Ok(());
+        }
+        self.send(
+            "PUT",
+            &key,
+            &[],
+            &[("Content-MD5", BASE64.encode(md5))],
+            &first,
+            &[],
+        )?;
+        Ok(())
+    }
+
+    // An empty "folder/" object, like the S3 console creates
+    fn mkdir(&self, rel_path: &str) -> Result<()> {
+        self.send(
+            "PUT",
+            &format!("{}/", self.key(rel_path)),
+            &[],
+            &[],
+            &[],
+            &[],
+        )?;
+        println!("Created: {}/{}", self, rel_path);
+        Ok(())
+    }
+
+    fn remove(&self, rel_path: &str) -> Result<()> {
+        let keys = self.keys_under(&self.key(rel_path))?;
+        self.delete_keys(&keys)?;
+        println!("Deleted: {}/{}", self, rel_path);
+        Ok(())
+    }
+
+    // S3 can't rename, every object is copied to it's new key and the old ones are deleted
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let from = self.key(from);
+        let to = self.key(to);
+        let keys = self.keys_under(&from)?;
+        for key in &keys {
+            let new_key = format!("{}{}", to, &key[from.len()..]);
+            let source = uri_encode(&format!("/{}/{}", self.bucket, key), false);
+            self.send(
+                "PUT",
+                &new_key,
+                &[],
+                &[("x-amz-copy-source", source)],
+                &[],
+                &[],
+            )?;
+        }
+        self.delete_keys(&keys)
+    }
+}
+
+// The credentials are never shown (locations are printed in logs)
+impl fmt::Display for S3Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "s3://{}/{}", self.bucket, self.prefix)
+    }
+}
+
+// Size of a part of a multipart upload, from 1
+fn part_size(number: u64) -> u64 {
+    PART_SIZE << ((number - 1) / PARTS_PER_SIZE).min(9)
+}
+
+fn dir_entry() -> FileEntry {
+    FileEntry {
+        mtime: UNIX_EPOCH,
+        modified: "Unknown".to_string(),
+        size: None,
+        version: None,
+    }
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+// Runs against a mock S3 server in a thread of the test: just enough of the API for this
+// location (ListObjectsV2, HEAD/GET/PUT, multipart uploads, DeleteObjects), in path style
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::uri_decode;
+    use std::collections::{BTreeMap, HashMap};
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    const LAST_MODIFIED: &str = "2024-01-02T03:04:05.000Z";
+
+    // What the server holds, and what it was asked
+    #[derive(Default)]
+    struct Bucket {
+        objects: BTreeMap<String, Vec<u8>>,
+        uploads: HashMap<String, BTreeMap<u32, Vec<u8>>>,
+        requests: Vec<String>,      // "METHOD key?query names"
+        delete_batches: Vec<usize>, // keys of each DeleteObjects
+    }
+
+    // (status, headers, body)
+    type Response = (u16, Vec<(&'static str, String)>, Vec<u8>);
+
+    // A location on a new mock server, with the server's bucket
+    fn location() -> (Box<dyn Location>, Arc<Mutex<Bucket>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let bucket = Arc::new(Mutex::new(Bucket::default()));
+        let shared = bucket.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let bucket = shared.clone();
+                thread::spawn(move || connection(stream, &bucket));
+            }
+        });
+        let spec = format!("//key:secret@bucket/prefix?endpoint={}", endpoint);
+        (S3Location::open("s3", &spec).unwrap(), bucket)
+    }
+
+    // Requests of a keep-alive connection, until the client closes it
+    fn connection(stream: TcpStream, bucket: &Mutex<Bucket>) -> io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut stream = stream;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let mut request_line = line.split_whitespace();
+            let method = request_line.next().unwrap_or_default().to_string();
+            let target = request_line.next().unwrap_or_default().to_string();
+            let mut headers = HashMap::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line)?;
+                let Some((name, value)) = line.trim_end().split_once(':') else {
+                    break;
+                };
+                headers.insert(name.to_lowercase(), value.trim().to_string());
+            }
+            let length = headers
+                .get("content-length")
+                .and_then(|length| length.parse().ok())
+                .unwrap_or(0);
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body)?;
+
+            let (status, response_headers, response_body) = handle(
+                &mut bucket.lock().unwrap(),
+                &method,
+                &target,
+                &headers,
+                &body,
+            );
+            let mut response = format!("HTTP/1.1 {} Mock\r\n", status);
+            if !response_headers
+                .iter()
+                .any(|(name, _)| *name == "Content-Length")
+            {
+                response.push_str(&format!("Content-Length: {}\r\n", response_body.len()));
+            }
+            for (name, value) in response_headers {
+                response.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            response.push_str("\r\n");
+            stream.write_all(response.as_bytes())?;
+            stream.write_all(&response_body)?;
+        }
+    }
+
+    fn etag(content: &[u8]) -> String {
+        format!("\"{}\"", hex::encode(Md5::digest(content)))
+    }
+
+    fn handle(
+        bucket: &mut Bucket,
+        method: &str,
+        target: &str,
+        headers: &HashMap<String, String>,
+        body: &[u8],
+    ) -> Response {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let query: HashMap<String, String> = query
+            .split('&')
+            .filter(|option| !option.is_empty())
+            .map(|option| {
+                let (name, value) = option.split_once('=').unwrap_or((option, ""));
+                (uri_decode(name), uri_decode(value))
+            })
+            .collect();
+        // /bucket/key
+        let path = uri_decode(path);
+        let key = path[1..].split_once('/').map_or("", |(_, key)| key);
+        let mut names: Vec<&str> = query.keys().map(String::as_str).collect();
+        names.sort();
+        bucket
+            .requests
+            .push(format!("{} {}?{}", method, key, names.join("&")));
+        let text = String::from_utf8_lossy(body);
+        let ok = |body: String| (200, vec![], body.into_bytes());
+        let not_found = (
+            404,
+            vec![],
+            b"<Error><Code>NoSuchKey</Code></Error>".to_vec(),
+        );
+
+        match method {
+            "GET" if key.is_empty() => {
+                let prefix = query.get("prefix").cloned().unwrap_or_default();
+                let max_keys = query
+                    .get("max-keys")
+                    .and_then(|max_keys| max_keys.parse().ok())
+                    .unwrap_or(1000);
+                let after = query.get("continuation-token");
+                let keys: Vec<(&String, &Vec<u8>)> = bucket
+                    .objects
+                    .iter()
+                    .filter(|(key, _)| key.starts_with(&prefix))
+                    .filter(|(key, _)| after.is_none_or(|after| *key > after))
+                    .collect();
+                let page = &keys[..keys.len().min(max_keys)];
+                let mut xml = String::from("<ListBucketResult>");
+                for (key, content) in page {
+                    xml.push_str(&format!(
+                        "<Contents><Key>{}</Key><Size>{}</Size><LastModified>{}</LastModified><ETag>{}</ETag></Contents>",
+                        xml::escape(key),
+                        content.len(),
+                        LAST_MODIFIED,
+                        xml::escape(&etag(content))
+                    ));
+                }
+                let truncated = page.len() < keys.len();
+                xml.push_str(&format!("<IsTruncated>{}</IsTruncated>", truncated));
+                if let (true, Some((last, _))) = (truncated, page.last()) {
+                    let token = xml::escape(last);
+                    xml.push_str(&format!(
+                        "<NextContinuationToken>{}</NextContinuationToken>",
+                        token
+                    ));
+                }
+                ok(xml + "</ListBucketResult>")
+            }
+            "POST" if key.is_empty() && query.contains_key("delete") => {
+                let keys: Vec<String> = xml::blocks(&text, "Key")
+                    .iter()
+                    .map(|key| xml::unescape(key))
+                    .collect();
+                bucket.delete_batches.push(keys.len());
+                for key in keys {
+                    bucket.objects.remove(&key);
+                }
+                ok("<DeleteResult></DeleteResult>".to_string())
+            }
+            "HEAD" => match bucket.objects.get(key) {
+                Some(content) => {
+                    let headers = vec![
+                        ("Content-Length", content.len().to_string()),
+                        ("ETag", etag(content)),
+                        ("Last-Modified", "Tue, 02 Jan 2024 03:04:05 GMT".to_string()),
+                    ];
+                    (200, headers, vec![])
+                }
+                None => (404, vec![], vec![]),
+            },
+            "GET" => match bucket.objects.get(key) {
+                Some(content) => (200, vec![], content.clone()),
+                None => not_found,
+            },
+            "PUT" if query.contains_key("partNumber") => {
+                let Some(parts) = bucket.uploads.get_mut(&query["uploadId"]) else {
+                    return not_found;
+                };
+                parts.insert(query["partNumber"].parse().unwrap(), body.to_vec());
+                (200, vec![("ETag", etag(body))], vec![])
+            }
+            "PUT" => {
+                let content = match headers.get("x-amz-copy-source") {
+                    Some(source) => {
+                        let source = uri_decode(source);
+                        let source = source.trim_start_matches("/bucket/");
+                        match bucket.objects.get(source) {
+                            Some(content) => content.clone(),
+                            None => return not_found,
+                        }
+                    }
+                    None => body.to_vec(),
+                };
+                let etag = etag(&content);
+                bucket.objects.insert(key.to_string(), content);
+                (200, vec![("ETag", etag)], vec![])
+            }
+            "POST" if query.contains_key("uploads") => {
+                let upload_id = format!("upload-{}", bucket.requests.len());
+                bucket.uploads.insert(upload_id.clone(), BTreeMap::new());
+                ok(format!(
+                    "<InitiateMultipartUploadResult><UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+                    upload_id
+                ))
+            }
+            "POST" if query.contains_key("uploadId") => {
+                let Some(mut parts) = bucket.uploads.remove(&query["uploadId"]) else {
+                    return not_found;
+                };
+                let mut content = Vec::new();
+                for part in xml::blocks(&text, "Part") {
+                    let number: u32 = xml::value(part, "PartNumber").unwrap().parse().unwrap();
+                    let data = parts.remove(&number).unwrap();
+                    assert_eq!(xml::value(part, "ETag"), Some(etag(&data)));
+                    content.extend(data);
+                }
+                bucket.objects.insert(key.to_string(), content);
+                ok("<CompleteMultipartUploadResult></CompleteMultipartUploadResult>".to_string())
+            }
+            "DELETE" if query.contains_key("uploadId") => {
+                bucket.uploads.remove(&query["uploadId"]);
+                (204, vec![], vec![])
+            }
+            "DELETE" => {
+                bucket.objects.remove(key);
+                (204, vec![], vec![])
+            }
+            _ => (400, vec![], vec![]),
+        }
+    }
+
+    fn read(location: &dyn Location, rel_path: &str) -> Vec<u8> {
+        let mut content = Vec::new();
+        location
+            .open_read(rel_path)
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        content
+    }
+
+    #[test]
+    fn part_sizes_grow_so_5_tb_fit_in_10000_parts() {
+        assert_eq!(part_size(1), PART_SIZE);
+        assert_eq!(part_size(1000), PART_SIZE);
+        assert_eq!(part_size(1001), 2 * PART_SIZE);
+        assert_eq!(part_size(2001), 4 * PART_SIZE);
+        assert_eq!(part_size(10000), PART_SIZE << 9);
+        // Parts are at most 5 GB
+        assert!(part_size(10000) <= 5 << 30);
+        let total: u64 = (1..=10000).map(part_size).sum();
+        assert!(total >= 5 << 40);
+    }
+
+    #[test]
+    fn small_files_are_listed_read_and_replaced() {
+        let (location, bucket) = location();
+        location.write("a/b.txt", &mut &b"first"[..]).unwrap();
+        location.mkdir("empty").unwrap();
+        assert!(bucket
+            .lock()
+            .unwrap()
+            .objects
+            .contains_key("prefix/a/b.txt"));
+
+        let files = location.list().unwrap();
+        let mut paths: Vec<&str> = files.keys().map(String::as_str).collect();
+        paths.sort();
+        assert_eq!(paths, ["a", "a/b.txt", "empty"]);
+        assert!(files["a"].is_dir() && files["empty"].is_dir());
+        assert_eq!(files["a/b.txt"].size, Some(5));
+        assert_eq!(files["a/b.txt"].version, Some(etag(b"first")));
+        assert_eq!(
+            location.stat("a/b.txt").unwrap(),
+            Some(files["a/b.txt"].clone())
+        );
+        assert!(location.stat("a").unwrap().unwrap().is_dir());
+        assert_eq!(location.stat("missing").unwrap(), None);
+        assert_eq!(read(&*location, "a/b.txt"), b"first");
+
+        // Same content: nothing is sent, same size: a new version
+        location.write("a/b.txt", &mut &b"first"[..]).unwrap();
+        let puts = |bucket: &Bucket| {
+            bucket
+                .requests
+                .iter()
+                .filter(|request| request.starts_with("PUT prefix/a/b.txt"))
+                .count()
+        };
+        assert_eq!(puts(&bucket.lock().unwrap()), 1);
+        location.write("a/b.txt", &mut &b"other"[..]).unwrap();
+        assert_eq!(puts(&bucket.lock().unwrap()), 2);
+        let entry = location.stat("a/b.txt").unwrap().unwrap();
+        assert_eq!(entry.size, Some(5));
+        assert_eq!(entry.version, Some(etag(b"other")));
+
+        location.rename("a", "c").unwrap();
+        let objects: Vec<String> = bucket.lock().unwrap().objects.keys().cloned().collect();
+        assert_eq!(objects, ["prefix/c/b.txt", "prefix/empty/"]);
+    }
+
+    #[test]
+    fn big_files_are_uploaded_in_parts() {
+        let (location, bucket) = location();
+        let size = 2 * PART_SIZE as usize + 123;
+        let content: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        location.write("big.bin", &mut &content[..]).unwrap();
+
+        let bucket = bucket.lock().unwrap();
+        assert!(bucket.objects["prefix/big.bin"] == content);
+        assert!(bucket.uploads.is_empty());
+        let requests: Vec<&str> = bucket.requests.iter().map(String::as_str).collect();
+        assert_eq!(
+            requests,
+            [
+                "POST prefix/big.bin?uploads",
+                "PUT prefix/big.bin?partNumber&uploadId",
+                "PUT prefix/big.bin?partNumber&uploadId",
+                "PUT prefix/big.bin?partNumber&uploadId",
+                "POST prefix/big.bin?uploadId",
+            ]
+        );
+    }
+
+    #[test]
+    fn folders_are_deleted_1000_keys_at_a_time() {
+        let (location, bucket) = location();
+        {
+            let mut bucket = bucket.lock().unwrap();
+            for i in 0..2500 {
+                let key = format!("prefix/dir/sub {}/file.txt", i);
+                bucket.objects.insert(key, b"x".to_vec());
+            }
+            bucket
+                .objects
+                .insert("prefix/keep.txt".to_string(), b"x".to_vec());
+        }
+        // dir, it's 2500 folders and files and keep.txt, listed in 3 pages
+        assert_eq!(location.list().unwrap().len(), 1 + 2 * 2500 + 1);
+
+        location.remove("dir").unwrap();
+        let bucket = bucket.lock().unwrap();
+        assert_eq!(bucket.delete_batches, [1000, 1000, 500]);
+        let objects: Vec<&String> = bucket.objects.keys().collect();
+        assert_eq!(objects, ["prefix/keep.txt"]);
+    }
+}
@@ -0,0 +1,507 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local};
+use ssh2::{
+    CheckResult, ErrorCode, FileStat, KnownHostFileKind, OpenFlags, OpenType, Session, Sftp,
+};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::sync::location::{FileEntry, Listing, Location};
+
+// Private keys that are tried (in this order) when no password is given and the ssh-agent
+// could not authenticate us, the same defaults OpenSSH uses
+const DEFAULT_KEYS: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+
+// LIBSSH2_FX_NO_SUCH_FILE, the status of a SFTP request on a path that does not exist
+const NO_SUCH_FILE: i32 = 2;
+
+// Idle connections are checked before being reused after this long, servers drop quiet SSH
+// connections
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+// Sessions are opened in parallel, the first one adds a new host and the others find it
+static KNOWN_HOSTS: Mutex<()> = Mutex::new(());
+
+// SFTP server (sftp:user[:password]@host[:port]/path[?accept_new=true])
+// Authenticated sessions are kept and reused, so syncing many small files does not cost a
+// connection, a handshake and an authentication each
+pub struct SftpLocation {
+    user: String,
+    pass: String,
+    url: String,
+    path: PathBuf,
+    accept_new: bool, // a host missing from ~/.ssh/known_hosts is added instead of refused
+    idle: Mutex<Vec<Connection>>, // files are copied in parallel, each on a connection of it's own
+}
+
+// Authenticated SSH session and it's SFTP channel
+struct Connection {
+    sftp: Sftp,
+    _session: Session,
+    last_used: Instant,
+}
+
+impl SftpLocation {
+    pub fn open(_scheme: &str, spec: &str) -> Result<Box<dyn Location>> {
+        Ok(Box::new(Self::new(spec)?))
+    }
+
+    // The password is optional, without it key-based auth is used
+    fn new(spec: &str) -> Result<Self> {
+        let (spec, query) = spec.split_once('?').unwrap_or((spec, ""));
+        let mut accept_new = false;
+        for option in query.split('&').filter(|option| !option.is_empty()) {
+            match option.split_once('=') {
+                Some(("accept_new", "true")) => accept_new = true,
+                Some(("accept_new", "false")) => accept_new = false,
+                _ => return Err(anyhow!("unrecognized SFTP option: {}", option)),
+            }
+        }
+        let (user_pass, url_path) = spec.split_once("@").unwrap_or_default();
+        let user_pass = user_pass.split_once(":").unwrap_or((user_pass, ""));
+        let (host, path) = url_path.split_once("/").unwrap_or_default();
+        let host = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{}:22", host)
+        };
+        Ok(Self {
+            user: user_pass.0.to_string(),
+            pass: user_pass.1.to_string(),
+            url: host,
+            path: PathBuf::from(path),
+            accept_new,
+            idle: Mutex::new(Vec::new()),
+        })
+    }
+
+    // An idle connection (checked when it was quiet for a while), or a new one
+    fn connection(&self) -> Result<Connection> {
+        loop {
+            let connection = self.idle.lock().unwrap().pop();
+            let Some(connection) = connection else {
+                break;
+            };
+            if connection.last_used.elapsed() < KEEPALIVE
+                || connection.sftp.realpath(Path::new(".")).is_ok()
+            {
+                return Ok(connection);
+            }
+            // The server closed it
+        }
+        let session = open_session(&self.user, &self.pass, &self.url, self.accept_new)?;
+        let sftp = session.sftp()?;
+        Ok(Connection {
+            sftp,
+            _session: session,
+            last_used: Instant::now(),
+        })
+    }
+
+    fn release(&self, mut connection: Connection) {
+        connection.last_used = Instant::now();
+        self.idle.lock().unwrap().push(connection);
+    }
+
+    // Runs an operation on a connection, which is given back unless the operation failed with
+    // something else than a SFTP status (the session may be broken then)
+    fn with_sftp<T>(&self, operation: impl FnOnce(&Sftp) -> Result<T>) -> Result<T> {
+        let connection = self.connection()?;
+        let result = operation(&connection.sftp);
+        if result.as_ref().map_or_else(sftp_status, |_| true) {
+            self.release(connection);
+        }
+        result
+    }
+
+    fn full_path(&self, rel_path: &str) -> PathBuf {
+        self.path.join(rel_path)
+    }
+}
+
+// Opens an SSH session to host:port and authenticates it
+// An empty password means key-based auth (ssh-agent first, then the default keys from ~/.ssh)
+fn open_session(user: &str, pass: &str, url: &str, accept_new: bool) -> Result<Session> {
+    let tcp = TcpStream::connect(url)?;
+    let mut session = Session::new()?;
+    session.set_tcp_stream(tcp);
+    session.handshake()?;
+    check_known_host(&session, url, accept_new)?;
+
+    if !pass.is_empty() {
+        session.userauth_password(user, pass)?;
+    } else if session.userauth_agent(user).is_err() {
+        let ssh_dir = dirs_next::home_dir().unwrap_or_default().join(".ssh");
+        for key in DEFAULT_KEYS {
+            let key_path = ssh_dir.join(key);
+            if key_path.exists()
+                && session
+                    .userauth_pubkey_file(user, None, &key_path, None)
+                    .is_ok()
+            {
+                break;
+            }
+        }
+    }
+
+    if !session.authenticated() {
+        return Err(anyhow!("SFTP authentication failed for {}@{}", user, url));
+    }
+    Ok(session)
+}
+
+// Refuse to talk to a server whose host key does not match ~/.ssh/known_hosts
+// Unknown hosts are refused too, unless accept_new is set: their key is then appended to the file
+// (like OpenSSH's StrictHostKeyChecking=accept-new) and checked on every later connection
+fn check_known_host(session: &Session, url: &str, accept_new: bool) -> Result<()> {
+    let _checking = KNOWN_HOSTS.lock().unwrap();
+    let known_hosts_file = dirs_next::home_dir()
+        .unwrap_or_default()
+        .join(".ssh/known_hosts");
+    let (host, port) = url.rsplit_once(':').unwrap_or((url, "22"));
+    let port: u16 = port.parse().unwrap_or(22);
+    let (key, key_type) = session
+        .host_key()
+        .ok_or_else(|| anyhow!("SFTP server {} did not send a host key", url))?;
+
+    let mut known_hosts = session.known_hosts()?;
+    if known_hosts_file.exists() {
+        known_hosts.read_file(&known_hosts_file, KnownHostFileKind::OpenSSH)?;
+    }
+    match known_hosts.check_port(host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(anyhow!(
+            "Host key for {} does not match ~/.ssh/known_hosts, refusing to connect",
+            url
+        )),
+        CheckResult::NotFound if accept_new => {
+            // OpenSSH writes hosts on another port than 22 as [host]:port
+            let name = if port == 22 {
+                host.to_string()
+            } else {
+                format!("[{}]:{}", host, port)
+            };
+            // The line is made by a set of it's own, so the rest of the file is left as it is
+            let mut new_host = session.known_hosts()?;
+            new_host.add(&name, key, "", key_type.into())?;
+            let entry = new_host
+                .hosts()?
+                .pop()
+                .ok_or_else(|| anyhow!("{}: no key", url))?;
+            let line = new_host.write_string(&entry, KnownHostFileKind::OpenSSH)?;
+            if let Some(dir) = known_hosts_file.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&known_hosts_file)?;
+            writeln!(file, "{}", line.trim_end())?;
+            println!("Added the host key of {} to ~/.ssh/known_hosts", url);
+            Ok(())
+        }
+        CheckResult::NotFound => Err(anyhow!(
+            "Host {} is not in ~/.ssh/known_hosts, add it (ssh-keyscan) or use ?accept_new=true",
+            url
+        )),
+        CheckResult::Failure => Err(anyhow!("Host key for {} could not be checked", url)),
+    }
+}
+
+// A file being read, it's connection goes back to the location once the file is closed
+struct SftpReader<'a> {
+    location: &'a SftpLocation,
+    file: Option<ssh2::File>,
+    connection: Option<Connection>,
+    broken: bool,
+}
+
+impl Read for SftpReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = self.file.as_mut().expect("open until dropped").read(buf);
+        self.broken |= result.is_err();
+        result
+    }
+}
+
+impl Drop for SftpReader<'_> {
+    fn drop(&mut self) {
+        // The file is closed first, on the connection that is given back
+        drop(self.file.take());
+        if let Some(connection) = self.connection.take() {
+            if !self.broken {
+                self.location.release(connection);
+            }
+        }
+    }
+}
+
+impl Location for SftpLocation {
+    // Connects to the SFTP server and lists all the files in the given location
+    // and stores them exactly as the system files
+    fn list(&self) -> Result<Listing> {
+        self.with_sftp(|sftp| {
+            let mut files = Listing::new();
+            recursive_list(&self.path, "".to_string(), sftp, &mut files)?;
+            Ok(files)
+        })
+    }
+
+    fn stat(&self, rel_path: &str) -> Result<Option<FileEntry>> {
+        self.with_sftp(|sftp| {
+            // Any other error (permission denied, connection lost...) says nothing about the file
+            match sftp.stat(&self.full_path(rel_path)) {
+                Ok(stat) => Ok(Some(sftp_entry(&stat))),
+                Err(e) if e.code() == ErrorCode::SFTP(NO_SUCH_FILE) => Ok(None),
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+
+    fn open_read(&self, rel_path: &str) -> Result<Box<dyn Read + '_>> {
+        let connection = self.connection()?;
+        let file = match connection.sftp.open(self.full_path(rel_path)) {
+            Ok(file) => file,
+            Err(e) => {
+                if matches!(e.code(), ErrorCode::SFTP(_)) {
+                    self.release(connection);
+                }
+                return Err(e.into());
+            }
+        };
+        Ok(Box::new(SftpReader {
+            location: self,
+            file: Some(file),
+            connection: Some(connection),
+            broken: false,
+        }))
+    }
+
+    // Uploads the content into the given SFTP path, overwriting it (parents are created)
+    fn write(&self, rel_path: &str, content: &mut dyn Read) -> Result<()> {
+        self.with_sftp(|sftp| {
+            let path = self.full_path(rel_path);
+            if let Some(parent) = path.parent() {
+                make_dirs(sftp, parent)?;
+            }
+            let mut file = sftp.create(&path)?;
+            io::copy(content, &mut file)?;
+            Ok(())
+        })
+    }
+
+    fn append(&self, rel_path: &str, offset: u64, content: &mut dyn Read) -> Result<bool> {
+        self.with_sftp(|sftp| {
+            let path = self.full_path(rel_path);
+            if sftp.stat(&path).ok().and_then(|stat| stat.size) != Some(offset) {
+                return Ok(false);
+            }
+            let mut file = sftp.open_mode(
+                &path,
+                OpenFlags::WRITE | OpenFlags::APPEND,
+                0o644,
+                OpenType::File,
+            )?;
+            io::copy(content, &mut file)?;
+            Ok(true)
+        })
+    }
+
+    fn mkdir(&self, rel_path: &str) -> Result<()> {
+        self.with_sftp(|sftp| make_dirs(sftp, &self.full_path(rel_path)))?;
+        println!("Created: {}/{}", self, rel_path);
+        Ok(())
+    }
+
+    // Deleting a file and all of it's subdirs recursively
+    fn remove(&self, rel_path: &str) -> Result<()> {
+        self.with_sftp(|sftp| recursive_delete(sftp, &self.full_path(rel_path)))?;
+        println!("Deleted: {}/{}", self, rel_path);
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.with_sftp(|sftp| {
+            let to = self.full_path(to);
+            if let Some(parent) = to.parent() {
+                make_dirs(sftp, parent)?;
+            }
+            sftp.rename(&self.full_path(from), &to, None)?;
+            Ok(())
+        })
+    }
+
+    // SFTP keeps the modification time we give it, so the next listing matches the source
+    fn set_mtime(&self, rel_path: &str, mtime: SystemTime) -> Result<()> {
+        let secs = mtime
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.with_sftp(|sftp| {
+            sftp.setstat(
+                &self.full_path(rel_path),
+                FileStat {
+                    size: None,
+                    uid: None,
+                    gid: None,
+                    perm: None,
+                    atime: Some(secs),
+                    mtime: Some(secs),
+                },
+            )?;
+            Ok(())
+        })
+    }
+}
+
+// The password is never shown (locations are printed in logs)
+impl fmt::Display for SftpLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sftp:{}@{}/{}", self.user, self.url, self.path.display())
+    }
+}
+
+// Creates a dir and all of it's parents if necessary
+fn make_dirs(sftp: &Sftp, dir: &Path) -> Result<()> {
+    let mut current = PathBuf::new();
+    for part in dir.iter() {
+        current.push(part);
+        if sftp.stat(&current).is_err() {
+            // Dir does not exist, create it
+            sftp.mkdir(&current, 0o755)?;
+        }
+    }
+    Ok(())
+}
+
+// Helping the list function
+fn recursive_list(dir: &Path, rel_path: String, sftp: &Sftp, hash_map: &mut Listing) -> Result<()> {
+    for (entry_path, stat) in sftp.readdir(dir)? {
+        let entry = entry_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        if entry.contains(".DS") {
+            continue;
+        }
+        let rel_path = format!("{}/{}", rel_path, entry)
+            .trim_start_matches("/")
+            .to_string();
+        hash_map.insert(rel_path.clone(), sftp_entry(&stat));
+        if stat.is_dir() {
+            recursive_list(&entry_path, rel_path, sftp, hash_map)?;
+        }
+    }
+    Ok(())
+}
+
+// (unix epoch - human readable time - size) from the attributes of a SFTP file
+fn sftp_entry(stat: &FileStat) -> FileEntry {
+    let (mtime, modified) = match stat.mtime {
+        Some(secs) => {
+            let system_time = UNIX_EPOCH + Duration::from_secs(secs);
+            let datetime: DateTime<Local> = system_time.into();
+            (
+                system_time,
+                datetime.format("%Y-%m-%d %H:%M:%S").to_string(),
+            )
+        }
+        None => (UNIX_EPOCH, "Unknown".to_string()),
+    };
+    FileEntry {
+        mtime,
+        modified,
+        size: if stat.is_dir() { None } else { stat.size },
+        version: None,
+    }
+}
+
+// A SFTP server answered the request with an error status, the session itself is fine
+fn sftp_status(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<ssh2::Error>()
+        .is_some_and(|e| matches!(e.code(), ErrorCode::SFTP(_)))
+}
+
+// help for the remove function
+// lstat does not follow links: a link to a folder is unlinked, what it points to is left alone
+fn recursive_delete(sftp: &Sftp, path: &Path) -> Result<()> {
+    if sftp.lstat(path)?.is_dir() {
+        for (item_path, _) in sftp.readdir(path)? {
+            recursive_delete(sftp, &item_path)?;
+        }
+        sftp.rmdir(path)?;
+    } else {
+        sftp.unlink(path)?;
+    }
+    Ok(())
+}
+
+// Runs against the SFTP server given in ADV_RSYNC_TEST_SFTP (user[:password]@host[:port]/path,
+// whose host key is known or ?accept_new=true), skipped when it's not set
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn location() -> Option<SftpLocation> {
+        let Ok(spec) = env::var("ADV_RSYNC_TEST_SFTP") else {
+            println!("ADV_RSYNC_TEST_SFTP is not set, skipped");
+            return None;
+        };
+        Some(SftpLocation::new(&spec).unwrap())
+    }
+
+    fn read(location: &SftpLocation, rel_path: &str) -> String {
+        let mut content = String::new();
+        location
+            .open_read(rel_path)
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        content
+    }
+
+    #[test]
+    fn files_are_written_read_and_removed_on_one_session() {
+        let Some(location) = location() else {
+            return;
+        };
+        let dir = format!("adv_rsync-test-{}", process::id());
+        let file = format!("{}/sub/file.txt", dir);
+        location.write(&file, &mut &b"hello"[..]).unwrap();
+        assert_eq!(read(&location, &file), "hello");
+        assert!(location.append(&file, 5, &mut &b" world"[..]).unwrap());
+        // Not the size we expect, nothing is appended
+        assert!(!location.append(&file, 5, &mut &b"!"[..]).unwrap());
+        assert_eq!(read(&location, &file), "hello world");
+
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        location.set_mtime(&file, mtime).unwrap();
+        let entry = location.stat(&file).unwrap().unwrap();
+        assert_eq!((entry.mtime, entry.size), (mtime, Some(11)));
+        assert_eq!(location.list().unwrap()[&file], entry);
+        assert!(location
+            .stat(&format!("{}/sub", dir))
+            .unwrap()
+            .unwrap()
+            .is_dir());
+        assert_eq!(location.stat(&format!("{}/missing", dir)).unwrap(), None);
+
+        let moved = format!("{}/moved/file.txt", dir);
+        location.rename(&file, &moved).unwrap();
+        assert_eq!(read(&location, &moved), "hello world");
+        location.mkdir(&format!("{}/empty/folder", dir)).unwrap();
+        location.remove(&dir).unwrap();
+        assert_eq!(location.stat(&dir).unwrap(), None);
+
+        // Everything ran one after the other, on the same session
+        assert_eq!(location.idle.lock().unwrap().len(), 1);
+    }
+}
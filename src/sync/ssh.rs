@@ -0,0 +1,842 @@
+use anyhow::{anyhow, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::sync::delta::{self, Op, Signature};
+use crate::sync::folder::{write_part, FolderLocation};
+use crate::sync::location::{FileEntry, Listing, Location};
+use crate::sync::state::HashReader;
+
+// Both sides have to speak the same version of the protocol
+const PROTOCOL_VERSION: u32 = 1;
+
+// Files travel in frames of at most this size
+const FRAME_SIZE: usize = 64 * 1024;
+
+// Kinds of frames: [kind][u32 length][payload]
+const DATA: u8 = b'D';
+const COPY: u8 = b'C'; // block of the old file in a delta, the payload is it's index (u64)
+const END: u8 = b'E';
+const ABORT: u8 = b'X'; // the sender could not go on, the payload says why
+
+// Folder on another machine, served by adv_rsync itself started there over ssh (--server)
+// ssh:[user@]host[:port]/path[?command=<remote adv_rsync>&ssh=<ssh client>]
+// The path is relative to the home folder ("host//abs/path" for an absolute one)
+// Listing, hashing and delta signatures are computed over there, only their results travel
+pub struct SshLocation {
+    destination: String, // [user@]host, given to the ssh client
+    port: Option<String>,
+    path: String,
+    command: String,
+    ssh: String,
+    idle: Mutex<Vec<Connection>>, // files are copied in parallel, each on a connection of it's own
+}
+
+// What the location asks the server, one JSON line each
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Request {
+    List,
+    Stat { path: String },
+    Hash { path: String },
+    Read { path: String },  // answered with the content, in frames
+    Write { path: String }, // followed by the content, in frames
+    Signature { path: String },
+    Patch { path: String, block_size: usize }, // followed by the delta, in frames
+    Mkdir { path: String },
+    Remove { path: String },
+    Rename { from: String, to: String },
+    SetMtime { path: String, mtime: SystemTime },
+}
+
+// Answer of the server, one JSON line each, only the fields of the request are set
+#[derive(Default, Serialize, Deserialize)]
+struct Response {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    listing: Option<Listing>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    entry: Option<FileEntry>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<Signature>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reused: Option<u64>,
+}
+
+// First line of the server, so a missing or different adv_rsync is found out right away
+#[derive(Serialize, Deserialize)]
+struct Hello {
+    version: u32,
+}
+
+// A running server, stopped when the connection is dropped
+struct Connection {
+    child: Child,
+    input: BufWriter<ChildStdin>,
+    output: BufReader<ChildStdout>,
+}
+
+impl SshLocation {
+    pub fn open(_scheme: &str, spec: &str) -> Result<Box<dyn Location>> {
+        let (spec, query) = spec.split_once('?').unwrap_or((spec, ""));
+        let (host, path) = spec.split_once('/').unwrap_or((spec, ""));
+        let (destination, port) = match host.rsplit_once(':') {
+            Some((destination, port)) => (destination, Some(port.to_string())),
+            None => (host, None),
+        };
+        if destination.is_empty() || destination.ends_with('@') {
+            return Err(anyhow!("expected ssh:[user@]host[:port]/path"));
+        }
+        let mut command = "adv_rsync".to_string();
+        let mut ssh = "ssh".to_string();
+        for option in query.split('&').filter(|option| !option.is_empty()) {
+            match option.split_once('=') {
+                Some(("command", value)) => command = value.to_string(),
+                Some(("ssh", value)) => ssh = value.to_string(),
+                _ => return Err(anyhow!("unrecognized ssh option: {}", option)),
+            }
+        }
+        Ok(Box::new(Self {
+            destination: destination.to_string(),
+            port,
+            path: path.to_string(),
+            command,
+            ssh,
+            idle: Mutex::new(Vec::new()),
+        }))
+    }
+
+    // Starts the server on the remote host: ssh [-p port] destination '<command> --server <path>'
+    fn start(&self) -> Result<Connection> {
+        let path = if self.path.is_empty() {
+            "."
+        } else {
+            &self.path
+        };
+        let mut command = Command::new(&self.ssh);
+        if let Some(port) = &self.port {
+            command.arg("-p").arg(port);
+        }
+        // The remote shell splits the command again, so the path is quoted for it
+        command
+            .arg(&self.destination)
+            .arg(format!("{} --server {}", self.command, shell_quote(path)))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped());
+        let mut child = command
+            .spawn()
+            .map_err(|e| anyhow!("Could not start {}: {}", self.ssh, e))?;
+        let mut connection = Connection {
+            input: BufWriter::new(child.stdin.take().expect("stdin is piped")),
+            output: BufReader::new(child.stdout.take().expect("stdout is piped")),
+            child,
+        };
+        let hello: Hello = read_message(&mut connection.output).map_err(|_| {
+            anyhow!(
+                "{} --server did not start on {} (is it installed there?)",
+                self.command,
+                self.destination
+            )
+        })?;
+        if hello.version != PROTOCOL_VERSION {
+            return Err(anyhow!(
+                "{} on {} speaks version {} of the protocol, not {}",
+                self.command,
+                self.destination,
+                hello.version,
+                PROTOCOL_VERSION
+            ));
+        }
+        Ok(connection)
+    }
+
+    // An idle connection, or a new one
+    fn connection(&self) -> Result<Connection> {
+        match self.idle.lock().unwrap().pop() {
+            Some(connection) => Ok(connection),
+            None => self.start(),
+        }
+    }
+
+    // Only connections that are between two requests are given back, any other one is dropped
+    fn release(&self, connection: Connection) {
+        self.idle.lock().unwrap().push(connection);
+    }
+
+    fn call(&self, request: &Request) -> Result<Response> {
+        let mut connection = self.connection()?;
+        write_message(&mut connection.input, request)?;
+        let response: Response = read_message(&mut connection.output)?;
+        self.release(connection);
+        self.result(response)
+    }
+
+    fn result(&self, response: Response) -> Result<Response> {
+        match response.error {
+            Some(error) => Err(anyhow!("{}: {}", self, error)),
+            None => Ok(response),
+        }
+    }
+}
+
+impl Location for SshLocation {
+    fn list(&self) -> Result<Listing> {
+        Ok(self.call(&Request::List)?.listing.unwrap_or_default())
+    }
+
+    fn stat(&self, rel_path: &str) -> Result<Option<FileEntry>> {
+        let path = rel_path.to_string();
+        Ok(self.call(&Request::Stat { path })?.entry)
+    }
+
+    // The content is streamed on a connection of it's own, given back once it was read to the end
+    fn open_read(&self, rel_path: &str) -> Result<Box<dyn Read + '_>> {
+        let mut connection = self.connection()?;
+        let path = rel_path.to_string();
+        write_message(&mut connection.input, &Request::Read { path })?;
+        let response: Response = read_message(&mut connection.output)?;
+        if let Some(error) = response.error {
+            self.release(connection);
+            return Err(anyhow!("{}: {}", self, error));
+        }
+        Ok(Box::new(RemoteFile {
+            location: self,
+            frames: Some(Frames::new(connection)),
+        }))
+    }
+
+    fn write(&self, rel_path: &str, content: &mut dyn Read) -> Result<()> {
+        let mut connection = self.connection()?;
+        let path = rel_path.to_string();
+        write_message(&mut connection.input, &Request::Write { path })?;
+        let failed = send_stream(&mut connection.input, content)?;
+        let response: Response = read_message(&mut connection.output)?;
+        self.release(connection);
+        if let Some(e) = failed {
+            return Err(e.into());
+        }
+        self.result(response)?;
+        Ok(())
+    }
+
+    // The server sends the signature of it's old file, the delta against it is computed here and
+    // only the blocks it does not have travel back
+    fn write_delta(
+        &self,
+        rel_path: &str,
+        content: &mut dyn Read,
+        mtime: SystemTime,
+    ) -> Result<Option<u64>> {
+        let path = rel_path.to_string();
+        let mut connection = self.connection()?;
+        write_message(
+            &mut connection.input,
+            &Request::Signature { path: path.clone() },
+        )?;
+        let response: Response = read_message(&mut connection.output)?;
+        let signature = match self.result(response) {
+            Ok(Response {
+                signature: Some(signature),
+                ..
+            }) => signature,
+            result => {
+                self.release(connection);
+                return result.map(|_| None);
+            }
+        };
+
+        let block_size = signature.block_size();
+        write_message(&mut connection.input, &Request::Patch { path, block_size })?;
+        let input = &mut connection.input;
+        let diffed = delta::diff(&signature, content, |op| {
+            match op {
+                Op::Copy(index) => write_frame(input, COPY, &(index as u64).to_be_bytes())?,
+                Op::Data(bytes) => {
+                    for chunk in bytes.chunks(FRAME_SIZE) {
+                        write_frame(input, DATA, chunk)?;
+                    }
+                }
+            }
+            Ok(())
+        });
+        match &diffed {
+            Ok(()) => write_frame(input, END, &[])?,
+            Err(e) => write_frame(input, ABORT, e.to_string().as_bytes())?,
+        }
+        input.flush()?;
+        let response: Response = read_message(&mut connection.output)?;
+        self.release(connection);
+        diffed?;
+        let reused = self.result(response)?.reused;
+        if reused.is_some() {
+            self.set_mtime(rel_path, mtime)?;
+        }
+        Ok(reused)
+    }
+
+    fn mkdir(&self, rel_path: &str) -> Result<()> {
+        let path = rel_path.to_string();
+        self.call(&Request::Mkdir { path })?;
+        println!("Created: {}/{}", self, rel_path);
+        Ok(())
+    }
+
+    fn remove(&self, rel_path: &str) -> Result<()> {
+        let path = rel_path.to_string();
+        self.call(&Request::Remove { path })?;
+        println!("Deleted: {}/{}", self, rel_path);
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.call(&Request::Rename {
+            from: from.to_string(),
+            to: to.to_string(),
+        })?;
+        Ok(())
+    }
+
+    fn set_mtime(&self, rel_path: &str, mtime: SystemTime) -> Result<()> {
+        let path = rel_path.to_string();
+        self.call(&Request::SetMtime { path, mtime })?;
+        Ok(())
+    }
+
+    fn hash(&self, rel_path: &str) -> Result<Option<String>> {
+        let path = rel_path.to_string();
+        Ok(self.call(&Request::Hash { path })?.hash)
+    }
+}
+
+impl fmt::Display for SshLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ssh:{}", self.destination)?;
+        if let Some(port) = &self.port {
+            write!(f, ":{}", port)?;
+        }
+        write!(f, "/{}", self.path)
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.output.read(buf)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// A file read from the server, it's connection goes back to the location once it was all read
+struct RemoteFile<'a> {
+    location: &'a SshLocation,
+    frames: Option<Frames<Connection>>,
+}
+
+impl Read for RemoteFile<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(frames) = self.frames.as_mut() else {
+            return Ok(0);
+        };
+        let result = frames.read(buf);
+        // Done with the stream (END, or ABORT from the server): the connection can be used again
+        // Broken in the middle of it: it is dropped
+        if frames.done || result.is_err() {
+            if let Some(frames) = self.frames.take() {
+                if frames.done {
+                    self.location.release(frames.input);
+                }
+            }
+        }
+        result
+    }
+}
+
+// Content sent in frames, read up to it's END frame
+struct Frames<R: Read> {
+    input: R,
+    chunk: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: Read> Frames<R> {
+    fn new(input: R) -> Self {
+        Self {
+            input,
+            chunk: Vec::new(),
+            pos: 0,
+            done: false,
+        }
+    }
+
+    // Next frame, None after the END one; ABORT becomes an error
+    fn frame(&mut self) -> io::Result<Option<(u8, Vec<u8>)>> {
+        if self.done {
+            return Ok(None);
+        }
+        let (kind, payload) = read_frame(&mut self.input)?;
+        match kind {
+            END => {
+                self.done = true;
+                Ok(None)
+            }
+            ABORT => {
+                self.done = true;
+                Err(io::Error::other(String::from_utf8_lossy(&payload)))
+            }
+            _ => Ok(Some((kind, payload))),
+        }
+    }
+
+    // Reads what is left of the stream, so the next message can be read
+    // An ABORT only means the content is not complete, the channel itself is fine
+    fn drain(&mut self) -> io::Result<()> {
+        while !self.done {
+            if let Err(e) = self.frame() {
+                if !self.done {
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Frames<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.frame()? {
+                Some((DATA, payload)) => {
+                    self.chunk = payload;
+                    self.pos = 0;
+                }
+                Some(_) => return Err(io::Error::from(io::ErrorKind::InvalidData)),
+                None => return Ok(0),
+            }
+        }
+        let read = buf.len().min(self.chunk.len() - self.pos);
+        buf[..read].copy_from_slice(&self.chunk[self.pos..self.pos + read]);
+        self.pos += read;
+        Ok(read)
+    }
+}
+
+// Serves the folder at root over stdin/stdout until stdin is closed (adv_rsync --server <root>)
+// Started by an ssh: location on the other side, stdout is it's channel so nothing else goes there
+pub fn serve(root: &Path) -> Result<()> {
+    serve_on(root, io::stdin().lock(), io::stdout().lock())
+}
+
+// Serves the folder at root over any channel, until it's input is closed
+fn serve_on(root: &Path, input: impl Read, output: impl Write) -> Result<()> {
+    let folder = FolderLocation::quiet(PathBuf::from(root));
+    let mut input = BufReader::new(input);
+    let mut output = BufWriter::new(output);
+    write_message(
+        &mut output,
+        &Hello {
+            version: PROTOCOL_VERSION,
+        },
+    )?;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let request: Request = serde_json::from_str(&line)?;
+        let response = match request {
+            Request::Read { path } => {
+                match folder.open_read(&path) {
+                    Ok(mut file) => {
+                        write_message(&mut output, &Response::default())?;
+                        send_stream(&mut output, &mut file)?;
+                    }
+                    Err(e) => write_message(&mut output, &error(e))?,
+                }
+                continue;
+            }
+            Request::Write { path } => {
+                let mut frames = Frames::new(&mut input);
+                let written = folder.write(&path, &mut frames);
+                frames.drain()?;
+                written.map(|_| Response::default())
+            }
+            Request::Patch { path, block_size } => {
+                let mut frames = Frames::new(&mut input);
+                let patched = patch(&folder.path(&path), block_size, &mut frames);
+                frames.drain()?;
+                patched.map(|reused| Response {
+                    reused: Some(reused),
+                    ..Default::default()
+                })
+            }
+            request => handle(&folder, request),
+        };
+        write_message(&mut output, &response.unwrap_or_else(error))?;
+    }
+}
+
+// Requests that are answered with a single message
+fn handle(folder: &FolderLocation, request: Request) -> Result<Response> {
+    let mut response = Response::default();
+    match request {
+        Request::List => response.listing = Some(folder.list()?),
+        Request::Stat { path } => response.entry = folder.stat(&path)?,
+        Request::Hash { path } => {
+            let mut reader = HashReader::new(folder.open_read(&path)?);
+            io::copy(&mut reader, &mut io::sink())?;
+            response.hash = Some(reader.hash());
+        }
+        // Small files are sent whole, see delta::MIN_SIZE
+        Request::Signature { path } => {
+            let path = folder.path(&path);
+            response.signature = match fs::metadata(&path) {
+                Ok(metadata) if metadata.is_file() && metadata.len() >= delta::MIN_SIZE => {
+                    Some(delta::signature(&path)?)
+                }
+                _ => None,
+            };
+        }
+        Request::Mkdir { path } => folder.mkdir(&path)?,
+        Request::Remove { path } => folder.remove(&path)?,
+        Request::Rename { from, to } => folder.rename(&from, &to)?,
+        Request::SetMtime { path, mtime } => folder.set_mtime(&path, mtime)?,
+        Request::Read { .. } | Request::Write { .. } | Request::Patch { .. } => {
+            return Err(anyhow!("streamed requests are served by serve"))
+        }
+    }
+    Ok(response)
+}
+
+// Rebuilds a file from the blocks of it's old version and the bytes in between
+fn patch<R: Read>(path: &Path, block_size: usize, frames: &mut Frames<R>) -> Result<u64> {
+    let mut reused = 0;
+    write_part(path, |file| {
+        let mut old = File::open(path)?;
+        let mut out = BufWriter::new(file);
+        while let Some((kind, payload)) = frames.frame()? {
+            match kind {
+                COPY => {
+                    let index = u64::from_be_bytes(
+                        payload
+                            .try_into()
+                            .map_err(|_| anyhow!("invalid block index"))?,
+                    );
+                    reused += delta::copy_block(&mut old, block_size, index as usize, &mut out)?;
+                }
+                DATA => out.write_all(&payload)?,
+                _ => return Err(anyhow!("unexpected frame in a delta")),
+            }
+        }
+        out.flush()?;
+        Ok(())
+    })?;
+    Ok(reused)
+}
+
+fn error(e: anyhow::Error) -> Response {
+    Response {
+        error: Some(e.to_string()),
+        ..Default::default()
+    }
+}
+
+fn write_message(out: &mut impl Write, message: &impl Serialize) -> Result<()> {
+    serde_json::to_writer(&mut *out, message)?;
+    out.write_all(b"\n")?;
+    out.flush()?;
+    Ok(())
+}
+
+fn read_message<T: DeserializeOwned>(input: &mut impl BufRead) -> Result<T> {
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Err(anyhow!("the connection was closed"));
+    }
+    Ok(serde_json::from_str(&line)?)
+}
+
+fn write_frame(out: &mut impl Write, kind: u8, payload: &[u8]) -> io::Result<()> {
+    out.write_all(&[kind])?;
+    out.write_all(&(payload.len() as u32).to_be_bytes())?;
+    out.write_all(payload)
+}
+
+fn read_frame(input: &mut impl Read) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0; 5];
+    input.read_exact(&mut header)?;
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    if len > FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame bigger than FRAME_SIZE",
+        ));
+    }
+    let mut payload = vec![0; len];
+    input.read_exact(&mut payload)?;
+    Ok((header[0], payload))
+}
+
+// Sends content as DATA frames and an END one, or an ABORT one if it can't be read to the end
+// Errors of the channel are returned, the one of the content comes back as Some(error) once the
+// other side knows about it
+fn send_stream(out: &mut impl Write, content: &mut dyn Read) -> io::Result<Option<io::Error>> {
+    let mut buffer = vec![0; FRAME_SIZE];
+    loop {
+        match content.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => write_frame(out, DATA, &buffer[..read])?,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => {
+                write_frame(out, ABORT, e.to_string().as_bytes())?;
+                out.flush()?;
+                return Ok(Some(e));
+            }
+        }
+    }
+    write_frame(out, END, &[])?;
+    out.flush()?;
+    Ok(None)
+}
+
+// Single quotes for a POSIX shell, a quote inside becomes '\''
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+// Drives serve_on running in a thread over a socket pair, like a ssh: location does over the
+// pipes of ssh
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::net::Shutdown;
+    use std::os::unix::net::UnixStream;
+    use std::process;
+    use std::thread::{self, JoinHandle};
+
+    // The location's side of the channel, the server serves a temporary folder
+    struct Client {
+        input: BufWriter<UnixStream>,
+        output: BufReader<UnixStream>,
+        root: PathBuf,
+        server: Option<JoinHandle<Result<()>>>,
+    }
+
+    impl Client {
+        fn new(name: &str) -> Self {
+            let root = env::temp_dir().join(format!("adv_rsync-serve-{}-{}", name, process::id()));
+            fs::create_dir_all(&root).unwrap();
+            let (client, server) = UnixStream::pair().unwrap();
+            let server_root = root.clone();
+            let server = thread::spawn(move || serve_on(&server_root, server.try_clone()?, server));
+            let mut client = Self {
+                input: BufWriter::new(client.try_clone().unwrap()),
+                output: BufReader::new(client),
+                root,
+                server: Some(server),
+            };
+            let hello: Hello = read_message(&mut client.output).unwrap();
+            assert_eq!(hello.version, PROTOCOL_VERSION);
+            client
+        }
+
+        fn call(&mut self, request: &Request) -> Response {
+            write_message(&mut self.input, request).unwrap();
+            read_message(&mut self.output).unwrap()
+        }
+
+        fn write(&mut self, path: &str, content: &mut dyn Read) -> Response {
+            let path = path.to_string();
+            write_message(&mut self.input, &Request::Write { path }).unwrap();
+            send_stream(&mut self.input, content).unwrap();
+            read_message(&mut self.output).unwrap()
+        }
+
+        fn read(&mut self, path: &str) -> String {
+            let response = self.call(&Request::Read {
+                path: path.to_string(),
+            });
+            assert_eq!(response.error, None);
+            let mut content = String::new();
+            Frames::new(&mut self.output)
+                .read_to_string(&mut content)
+                .unwrap();
+            content
+        }
+
+        // Everything in the served folder, part files included
+        fn files(&self) -> Vec<String> {
+            let mut files: Vec<String> = walkdir::WalkDir::new(&self.root)
+                .min_depth(1)
+                .into_iter()
+                .map(|entry| {
+                    let entry = entry.unwrap();
+                    let path = entry.path().strip_prefix(&self.root).unwrap();
+                    path.to_string_lossy().to_string()
+                })
+                .collect();
+            files.sort();
+            files
+        }
+    }
+
+    // Closing the channel stops the server
+    impl Drop for Client {
+        fn drop(&mut self) {
+            let _ = self.input.get_ref().shutdown(Shutdown::Write);
+            if let Some(server) = self.server.take() {
+                let stopped = server.join();
+                if !thread::panicking() {
+                    stopped.unwrap().unwrap();
+                }
+            }
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    // Gives size bytes, then fails
+    struct Failing {
+        size: usize,
+    }
+
+    impl Read for Failing {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.size == 0 {
+                return Err(io::Error::other("the disk is on fire"));
+            }
+            let read = buf.len().min(self.size);
+            buf[..read].fill(b'x');
+            self.size -= read;
+            Ok(read)
+        }
+    }
+
+    fn random(len: usize) -> Vec<u8> {
+        let mut state: u64 = 42;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 33) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn files_are_written_read_and_removed() {
+        let mut client = Client::new("write");
+        let response = client.write("dir/a.txt", &mut &b"hello"[..]);
+        assert_eq!(response.error, None);
+        assert_eq!(client.files(), ["dir", "dir/a.txt"]);
+        assert_eq!(client.read("dir/a.txt"), "hello");
+
+        let path = "dir/a.txt".to_string();
+        let entry = client.call(&Request::Stat { path: path.clone() }).entry;
+        assert_eq!(entry.and_then(|entry| entry.size), Some(5));
+        let mut hash = HashReader::new(&b"hello"[..]);
+        io::copy(&mut hash, &mut io::sink()).unwrap();
+        let response = client.call(&Request::Hash { path: path.clone() });
+        assert_eq!(response.hash, Some(hash.hash()));
+        let listing = client.call(&Request::List).listing.unwrap();
+        assert!(listing["dir"].is_dir() && listing.contains_key("dir/a.txt"));
+
+        let response = client.call(&Request::Remove {
+            path: "dir".to_string(),
+        });
+        assert_eq!(response.error, None);
+        assert!(client.files().is_empty());
+        assert!(client.call(&Request::Stat { path }).entry.is_none());
+        // Errors are answered, the server goes on
+        let path = "missing".to_string();
+        assert!(client.call(&Request::Remove { path }).error.is_some());
+        assert!(client.call(&Request::List).listing.unwrap().is_empty());
+    }
+
+    #[test]
+    fn aborted_write_keeps_the_old_file() {
+        let mut client = Client::new("abort");
+        client.write("a.txt", &mut &b"old"[..]);
+        let response = client.write(
+            "a.txt",
+            &mut Failing {
+                size: 3 * FRAME_SIZE,
+            },
+        );
+        assert!(response.error.unwrap().contains("the disk is on fire"));
+        // No part file is left behind
+        assert_eq!(client.files(), ["a.txt"]);
+        assert_eq!(client.read("a.txt"), "old");
+    }
+
+    #[test]
+    fn delta_sends_only_the_new_blocks() {
+        let mut client = Client::new("delta");
+        let old = random(200_000);
+        fs::write(client.root.join("big.bin"), &old).unwrap();
+        fs::write(client.root.join("small.txt"), "small").unwrap();
+        let new = [&old[..50_000], b"inserted", &old[50_000..]].concat();
+
+        // Files smaller than delta::MIN_SIZE are sent whole
+        let path = "small.txt".to_string();
+        assert!(client
+            .call(&Request::Signature { path })
+            .signature
+            .is_none());
+
+        let path = "big.bin".to_string();
+        let signature = client
+            .call(&Request::Signature { path: path.clone() })
+            .signature
+            .unwrap();
+        let block_size = signature.block_size();
+        write_message(&mut client.input, &Request::Patch { path, block_size }).unwrap();
+        let mut sent = 0;
+        let input = &mut client.input;
+        delta::diff(&signature, &mut &new[..], |op| {
+            match op {
+                Op::Copy(index) => write_frame(input, COPY, &(index as u64).to_be_bytes())?,
+                Op::Data(bytes) => {
+                    sent += bytes.len();
+                    write_frame(input, DATA, bytes)?;
+                }
+            }
+            Ok(())
+        })
+        .unwrap();
+        write_frame(input, END, &[]).unwrap();
+        input.flush().unwrap();
+        let response: Response = read_message(&mut client.output).unwrap();
+        assert_eq!(response.error, None);
+        assert_eq!(response.reused.unwrap() as usize + sent, new.len());
+        assert!(sent < 3 * block_size);
+        assert!(fs::read(client.root.join("big.bin")).unwrap() == new);
+
+        // A delta cut off by the sender leaves the file as it was
+        let (path, block_size) = ("big.bin".to_string(), signature.block_size());
+        write_message(&mut client.input, &Request::Patch { path, block_size }).unwrap();
+        write_frame(&mut client.input, COPY, &0u64.to_be_bytes()).unwrap();
+        write_frame(&mut client.input, ABORT, b"source vanished").unwrap();
+        client.input.flush().unwrap();
+        let response: Response = read_message(&mut client.output).unwrap();
+        assert!(response.error.unwrap().contains("source vanished"));
+        assert_eq!(client.files(), ["big.bin", "small.txt"]);
+        assert!(fs::read(client.root.join("big.bin")).unwrap() == new);
+    }
+}
@@ -0,0 +1,174 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::sync::{LazyLock, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::sync::location::{FileEntry, Location};
+
+// What both locations of a pair looked like the last time a relative path was synced
+// Every side keeps it's own mtime because a remote stamps uploads with the upload time
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyncedFile {
+    pub mtime_a: u64,         // unix seconds in the first location of the pair
+    pub size_a: Option<u64>,  // None for folders
+    pub mtime_b: u64,         // unix seconds in the second location of the pair
+    pub size_b: Option<u64>,  // None for folders
+    pub hash: Option<String>, // SHA-256 of the content, when it went through us
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version_a: Option<String>, // see FileEntry::version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version_b: Option<String>,
+}
+
+impl SyncedFile {
+    // Was the file changed in the first (side_a = true) or second location since the last sync
+    pub fn changed(&self, side_a: bool, entry: &FileEntry) -> bool {
+        let (base_mtime, base_size, base_version) = if side_a {
+            (self.mtime_a, self.size_a, &self.version_a)
+        } else {
+            (self.mtime_b, self.size_b, &self.version_b)
+        };
+        // A version only counts when both are known (states saved before it have none)
+        let new_version = match (base_version, &entry.version) {
+            (Some(base), Some(version)) => base != version,
+            _ => false,
+        };
+        // Only files can be changed, a folder's mtime moves with it's content
+        entry.size.is_some()
+            && (base_mtime != unix_secs(entry.mtime) || base_size != entry.size || new_version)
+    }
+}
+
+// What a copy that was cut off left in it's target: the first size bytes of the source file as it
+// was then. The next sync completes it (APPE) when the source did not change, instead of taking
+// it for a newer version of the file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PartialFile {
+    pub in_a: bool, // the partial file is in the first location of the pair
+    pub size: u64,
+    pub source_mtime: u64, // unix seconds
+    pub source_size: u64,
+}
+
+// How a state is stored, states saved before partial files were tracked only have the files
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum StateFile<'a> {
+    Current {
+        files: Cow<'a, HashMap<String, SyncedFile>>,
+        partial: Cow<'a, HashMap<String, PartialFile>>,
+    },
+    Files(HashMap<String, SyncedFile>),
+}
+
+// States of the pairs with a location that does not outlive the run (mem:), by path
+// On disk they would make the next run see every file as deleted from it
+type InMemory = HashMap<PathBuf, (HashMap<String, SyncedFile>, HashMap<String, PartialFile>)>;
+static IN_MEMORY: LazyLock<Mutex<InMemory>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// Last synced state of a location pair, stored in ~/.adv_rsync/state/<pair id>.json
+// It is the base of the three-way diff (base vs. A vs. B) done on every sync
+pub struct SyncState {
+    path: PathBuf,
+    persistent: bool, // false to keep it in memory only
+    pub files: HashMap<String, SyncedFile>,
+    pub partial: HashMap<String, PartialFile>, // interrupted copies, by path
+}
+
+impl SyncState {
+    // Loads the state of the pair (an empty state if the pair was never synced)
+    // The pair has to be given in the same order every time, see Synchronizer::plan_pair
+    pub fn load(loc_a: &dyn Location, loc_b: &dyn Location) -> Result<Self> {
+        let home_dir = dirs_next::home_dir().unwrap_or_default();
+        // The id is hashed so that it is a valid file name whatever the locations are
+        let pair_id = hex::encode(Sha256::digest(format!("{}\n{}", loc_a, loc_b)));
+        let path = home_dir
+            .join(".adv_rsync/state")
+            .join(format!("{}.json", pair_id));
+
+        let persistent = loc_a.persistent() && loc_b.persistent();
+        let (files, partial) = if !persistent {
+            IN_MEMORY
+                .lock()
+                .unwrap()
+                .get(&path)
+                .cloned()
+                .unwrap_or_default()
+        } else if path.exists() {
+            match serde_json::from_str(&fs::read_to_string(&path)?)? {
+                StateFile::Current { files, partial } => (files.into_owned(), partial.into_owned()),
+                StateFile::Files(files) => (files, HashMap::new()),
+            }
+        } else {
+            Default::default()
+        };
+        Ok(Self {
+            path,
+            persistent,
+            files,
+            partial,
+        })
+    }
+
+    // Writes the state into a temporary file first so a crash never leaves a truncated state
+    pub fn save(&self) -> Result<()> {
+        if !self.persistent {
+            IN_MEMORY.lock().unwrap().insert(
+                self.path.clone(),
+                (self.files.clone(), self.partial.clone()),
+            );
+            return Ok(());
+        }
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp_path = self.path.with_extension("json.tmp");
+        let state = StateFile::Current {
+            files: Cow::Borrowed(&self.files),
+            partial: Cow::Borrowed(&self.partial),
+        };
+        fs::write(&tmp_path, serde_json::to_string(&state)?)?;
+        fs::rename(tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+pub fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// Hashes the content of a file while it is being copied, so it is read only once
+pub struct HashReader<R: Read> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> HashReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    // SHA-256 of everything that was read
+    pub fn hash(self) -> String {
+        hex::encode(self.hasher.finalize())
+    }
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
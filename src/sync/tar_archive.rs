@@ -0,0 +1,576 @@
+use anyhow::Result;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tar::{Archive, Builder, EntryType, Header};
+use xz2::read::XzDecoder;
+use xz2::write::XzEncoder;
+
+use crate::errors::FileErrors;
+use crate::sync::archive_path::ArchivePath;
+use crate::sync::folder::write_part;
+use crate::sync::location::{FileEntry, Listing, Location};
+use crate::sync::state::unix_secs;
+use crate::utils::{human_readable_time, pipe_reader};
+
+// Written content is spooled into <archive>.adv_rsync-entry-<n> first, a tar header needs the
+// size before the data (n tells apart the files that are copied in parallel)
+const ENTRY_SUFFIX: &str = ".adv_rsync-entry";
+static SPOOLED: AtomicUsize = AtomicUsize::new(0);
+
+// tar archive, plain or compressed with gzip, zstd or xz (found from it's first bytes)
+// The location can be a folder inside it, it is a read-only source (tar:) unless it is opened as
+// writable (tar+rw:)
+pub struct TarLocation {
+    path: ArchivePath,
+    writable: bool,
+    rewriting: Mutex<()>, // files are copied in parallel, but the archive is rewritten by one at a time
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl Compression {
+    // From the magic bytes of the archive
+    fn detect(start: &[u8]) -> Self {
+        if start.starts_with(&[0x1f, 0x8b]) {
+            Self::Gzip
+        } else if start.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Self::Zstd
+        } else if start.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Self::Xz
+        } else {
+            Self::None
+        }
+    }
+
+    // For an archive that does not exist yet
+    fn from_name(path: &Path) -> Self {
+        let name = path.to_string_lossy();
+        if name.ends_with(".gz") || name.ends_with(".tgz") {
+            Self::Gzip
+        } else if name.ends_with(".zst") || name.ends_with(".tzst") {
+            Self::Zstd
+        } else if name.ends_with(".xz") || name.ends_with(".txz") {
+            Self::Xz
+        } else {
+            Self::None
+        }
+    }
+}
+
+// Compressing writer that has to be finished to write it's last bytes
+enum Encoder<W: Write> {
+    None(W),
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+    Xz(XzEncoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    fn new(compression: Compression, writer: W) -> Result<Self> {
+        Ok(match compression {
+            Compression::None => Self::None(writer),
+            Compression::Gzip => Self::Gzip(GzEncoder::new(writer, flate2::Compression::default())),
+            Compression::Zstd => Self::Zstd(zstd::Encoder::new(writer, 0)?),
+            Compression::Xz => Self::Xz(XzEncoder::new(writer, 6)),
+        })
+    }
+
+    fn finish(self) -> Result<W> {
+        Ok(match self {
+            Self::None(writer) => writer,
+            Self::Gzip(encoder) => encoder.finish()?,
+            Self::Zstd(encoder) => encoder.finish()?,
+            Self::Xz(encoder) => encoder.finish()?,
+        })
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::None(writer) => writer.write(buf),
+            Self::Gzip(encoder) => encoder.write(buf),
+            Self::Zstd(encoder) => encoder.write(buf),
+            Self::Xz(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::None(writer) => writer.flush(),
+            Self::Gzip(encoder) => encoder.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+            Self::Xz(encoder) => encoder.flush(),
+        }
+    }
+}
+
+// What happens to an entry of the old archive when it is rewritten
+enum Keep {
+    Copy,
+    Drop,
+    Rename(String),
+    Touch(SystemTime),
+}
+
+impl TarLocation {
+    // tar:/path/to/archive.tar[.gz|.zst|.xz][!/dir] or tar+rw:..., see ArchivePath
+    pub fn open(scheme: &str, spec: &str) -> Result<Box<dyn Location>> {
+        let path = ArchivePath::parse(spec);
+        let writable = scheme == "tar+rw";
+        if writable && path.is_nested() {
+            return Err(FileErrors::InvalidFileForWriting(
+                "archives inside archives are read-only".to_string(),
+            )
+            .into());
+        }
+        Ok(Box::new(Self {
+            path,
+            writable,
+            rewriting: Mutex::new(()),
+        }))
+    }
+
+    fn read_only_error(&self) -> anyhow::Error {
+        FileErrors::InvalidFileForWriting("tar file is read-only".to_string()).into()
+    }
+
+    // A tar archive can't be changed in place (and it's compression covers all of it), so it is
+    // written again in a part file next to the old one, which is then renamed over it
+    // keep is only asked about the entries inside the folder of the location, by their relative path
+    fn rewrite(
+        &self,
+        keep: impl Fn(&str) -> Keep,
+        add: impl FnOnce(&mut Builder<Encoder<&mut File>>) -> Result<()>,
+    ) -> Result<()> {
+        if !self.writable {
+            return Err(self.read_only_error());
+        }
+        let _rewriting = self.rewriting.lock().unwrap();
+        let outer = self.path.outer();
+        let exists = outer.exists();
+        let compression = if exists {
+            let mut start = [0; 6];
+            let read = File::open(outer)?.read(&mut start)?;
+            Compression::detect(&start[..read])
+        } else {
+            Compression::from_name(outer)
+        };
+        write_part(outer, |file| {
+            let mut builder = Builder::new(Encoder::new(compression, file)?);
+            if exists {
+                let mut archive = archive(outer)?;
+                for entry in archive.entries()? {
+                    let mut entry = entry?;
+                    // Kept entries keep their path as it was written ("./dir/file" stays so)
+                    let path = entry.path()?.into_owned();
+                    let name = entry_name(&path);
+                    if name.is_empty() {
+                        continue;
+                    }
+                    let mut header = entry.header().clone();
+                    // The size may only be in a pax record
+                    header.set_size(entry.size());
+                    let link_name = entry.link_name()?.map(|target| target.into_owned());
+                    let mut extensions = pax_extensions(&mut entry)?;
+                    let keep = match self.path.rel_path(&name) {
+                        Some(rel_path) => keep(rel_path),
+                        None => Keep::Copy,
+                    };
+                    let path = match keep {
+                        Keep::Copy => path,
+                        Keep::Drop => continue,
+                        Keep::Rename(rel_path) => PathBuf::from(self.path.entry(&rel_path)),
+                        Keep::Touch(mtime) => {
+                            header.set_mtime(unix_secs(mtime));
+                            extensions.retain(|(key, _)| key != "mtime");
+                            path
+                        }
+                    };
+                    builder.append_pax_extensions(
+                        extensions
+                            .iter()
+                            .map(|(key, value)| (key.as_str(), value.as_slice())),
+                    )?;
+                    // Paths and link names too long for the header get a GNU record of their own
+                    match link_name {
+                        Some(target) => builder.append_link(&mut header, path, target)?,
+                        None => builder.append_data(&mut header, path, entry)?,
+                    }
+                }
+            }
+            add(&mut builder)?;
+            builder.into_inner()?.finish()?;
+            Ok(())
+        })
+    }
+}
+
+impl Location for TarLocation {
+    // Every entry of the archive, folders included (even the ones only implied by a path)
+    fn list(&self) -> Result<Listing> {
+        let mut files = Listing::new();
+        if self.writable && !self.path.outer().exists() {
+            // Created on the first write
+            return Ok(files);
+        }
+        let mut archive = archive(&self.path.file()?)?;
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let name = match self.path.rel_path(&entry_name(&entry.path()?)) {
+                Some(name) if !name.contains(".DS") => name.to_string(),
+                _ => continue,
+            };
+            let mut parent = name.as_str();
+            while let Some((dir, _)) = parent.rsplit_once('/') {
+                files.entry(dir.to_string()).or_insert_with(|| FileEntry {
+                    mtime: UNIX_EPOCH,
+                    modified: "Unknown".to_string(),
+                    size: None,
+                    version: None,
+                });
+                parent = dir;
+            }
+            let header = entry.header();
+            let size = match header.entry_type() {
+                EntryType::Directory => None,
+                EntryType::Regular | EntryType::Continuous | EntryType::GNUSparse => {
+                    Some(entry.size())
+                }
+                // Links, devices... have no content of their own to sync
+                _ => continue,
+            };
+            let mtime = UNIX_EPOCH + Duration::from_secs(header.mtime().unwrap_or(0));
+            files.insert(
+                name,
+                FileEntry {
+                    mtime,
+                    modified: human_readable_time(mtime),
+                    size,
+                    version: None,
+                },
+            );
+        }
+        Ok(files)
+    }
+
+    fn stat(&self, rel_path: &str) -> Result<Option<FileEntry>> {
+        Ok(self.list()?.remove(rel_path))
+    }
+
+    // The archive is read from it's start until the entry is found, on it's own thread
+    fn open_read(&self, rel_path: &str) -> Result<Box<dyn Read + '_>> {
+        // Missing entries are reported here rather than on the first read
+        match self.stat(rel_path)? {
+            Some(entry) if !entry.is_dir() => {}
+            _ => return Err(FileErrors::InvalidFileForReading(rel_path.to_string()).into()),
+        }
+
+        let path = self.path.file()?;
+        let name = self.path.entry(rel_path);
+        Ok(pipe_reader(move |writer| {
+            let mut archive = archive(&path)?;
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+                if entry_name(&entry.path()?) == name {
+                    io::copy(&mut entry, writer)?;
+                    return Ok(());
+                }
+            }
+            Err(FileErrors::InvalidFileForReading(name).into())
+        }))
+    }
+
+    fn write(&self, rel_path: &str, content: &mut dyn Read) -> Result<()> {
+        self.write_with_mtime(rel_path, content, SystemTime::now())
+    }
+
+    // The header gets the time of the source, set_mtime would recompress the archive once more
+    fn write_with_mtime(
+        &self,
+        rel_path: &str,
+        content: &mut dyn Read,
+        mtime: SystemTime,
+    ) -> Result<()> {
+        if !self.writable {
+            return Err(self.read_only_error());
+        }
+        let mut spool_path = self.path.outer().as_os_str().to_owned();
+        spool_path.push(format!(
+            "{}-{}",
+            ENTRY_SUFFIX,
+            SPOOLED.fetch_add(1, Ordering::Relaxed)
+        ));
+        let result = (|| {
+            io::copy(content, &mut File::create(&spool_path)?)?;
+            let mut spool = File::open(&spool_path)?;
+            let mut header = Header::new_gnu();
+            header.set_size(spool.metadata()?.len());
+            header.set_mode(0o644);
+            header.set_mtime(unix_secs(mtime));
+            self.rewrite(
+                |name| {
+                    if name == rel_path {
+                        Keep::Drop
+                    } else {
+                        Keep::Copy
+                    }
+                },
+                |builder| {
+                    let name = self.path.entry(rel_path);
+                    Ok(builder.append_data(&mut header, name, &mut spool)?)
+                },
+            )
+        })();
+        let _ = fs::remove_file(&spool_path);
+        result
+    }
+
+    fn mkdir(&self, rel_path: &str) -> Result<()> {
+        if self.list()?.contains_key(rel_path) {
+            return Ok(());
+        }
+        self.rewrite(
+            |_| Keep::Copy,
+            |builder| {
+                let mut header = Header::new_gnu();
+                header.set_entry_type(EntryType::Directory);
+                header.set_size(0);
+                header.set_mode(0o755);
+                header.set_mtime(unix_secs(SystemTime::now()));
+                Ok(builder.append_data(
+                    &mut header,
+                    format!("{}/", self.path.entry(rel_path)),
+                    io::empty(),
+                )?)
+            },
+        )?;
+        println!("Created: {}/{}", self, rel_path);
+        Ok(())
+    }
+
+    fn remove(&self, rel_path: &str) -> Result<()> {
+        if !self.writable {
+            return Err(
+                FileErrors::InvalidFileForDelete("tar file is read-only".to_string()).into(),
+            );
+        }
+        let prefix = format!("{}/", rel_path);
+        self.rewrite(
+            |name| {
+                if name == rel_path || name.starts_with(&prefix) {
+                    Keep::Drop
+                } else {
+                    Keep::Copy
+                }
+            },
+            |_| Ok(()),
+        )?;
+        println!("Deleted: {}/{}", self, rel_path);
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let prefix = format!("{}/", from);
+        self.rewrite(
+            |name| {
+                if name == from {
+                    Keep::Rename(to.to_string())
+                } else if let Some(rest) = name.strip_prefix(&prefix) {
+                    Keep::Rename(format!("{}/{}", to, rest))
+                } else {
+                    Keep::Copy
+                }
+            },
+            |_| Ok(()),
+        )
+    }
+
+    fn set_mtime(&self, rel_path: &str, mtime: SystemTime) -> Result<()> {
+        if !self.writable {
+            return Ok(());
+        }
+        self.rewrite(
+            |name| {
+                if name == rel_path {
+                    Keep::Touch(mtime)
+                } else {
+                    Keep::Copy
+                }
+            },
+            |_| Ok(()),
+        )
+    }
+
+    fn read_only(&self) -> bool {
+        !self.writable
+    }
+}
+
+impl fmt::Display for TarLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = if self.writable { "tar+rw" } else { "tar" };
+        write!(f, "{}:{}", scheme, self.path)
+    }
+}
+
+// Opens the archive through the decompressor it needs
+pub fn archive(path: &Path) -> Result<Archive<Box<dyn Read>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let reader: Box<dyn Read> = match Compression::detect(reader.fill_buf()?) {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+        Compression::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+        Compression::Xz => Box::new(XzDecoder::new(reader)),
+    };
+    Ok(Archive::new(reader))
+}
+
+// The pax records of an entry (sub-second times, extended attributes...) to write it again with,
+// but for it's path and link name, which the builder writes from the ones it is given
+fn pax_extensions<R: Read>(entry: &mut tar::Entry<R>) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut extensions = Vec::new();
+    if let Some(records) = entry.pax_extensions()? {
+        for record in records {
+            let record = record?;
+            let key = record.key()?;
+            if key != "path" && key != "linkpath" {
+                extensions.push((key.to_string(), record.value_bytes().to_vec()));
+            }
+        }
+    }
+    Ok(extensions)
+}
+
+// Path of an entry relative to the archive root ("./dir/" -> "dir")
+pub fn entry_name(path: &Path) -> String {
+    let name = path.to_string_lossy().replace('\\', "/");
+    let name = name.trim_start_matches("./").trim_end_matches('/');
+    if name == "." {
+        String::new()
+    } else {
+        name.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::env;
+    use std::process;
+
+    const LONG_TARGET: &str = "a/target/whose/name/is/much/longer/than/the/hundred/bytes/a/tar/header/has/room/for/in/it's/link/name/field";
+
+    // An archive with an entry that has pax records, a link to a long name and a long path
+    fn archive_with_metadata(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("adv_rsync-tar-{}-{}.tar", name, process::id()));
+        let mut builder = Builder::new(File::create(&path).unwrap());
+
+        builder
+            .append_pax_extensions([
+                ("mtime", &b"1700000000.123456789"[..]),
+                ("SCHILY.xattr.user.tag", &b"blue"[..]),
+            ])
+            .unwrap();
+        let mut header = Header::new_ustar();
+        header.set_size(4);
+        header.set_mtime(1_700_000_000);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, "dir/keep.txt", &b"keep"[..])
+            .unwrap();
+
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Symlink);
+        header.set_size(0);
+        header.set_mode(0o777);
+        builder
+            .append_link(&mut header, "dir/link", LONG_TARGET)
+            .unwrap();
+
+        let mut header = Header::new_gnu();
+        header.set_size(4);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, format!("dir/{}", LONG_TARGET), &b"long"[..])
+            .unwrap();
+        builder.into_inner().unwrap();
+        path
+    }
+
+    // pax records, link name and mtime of the header
+    type Metadata = (Vec<(String, Vec<u8>)>, Option<String>, u64);
+
+    fn entries(path: &Path) -> HashMap<String, Metadata> {
+        let mut archive = archive(path).unwrap();
+        let mut entries = HashMap::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let name = entry_name(&entry.path().unwrap());
+            let link_name = entry
+                .link_name()
+                .unwrap()
+                .map(|target| target.to_string_lossy().to_string());
+            let mtime = entry.header().mtime().unwrap();
+            let mut extensions = pax_extensions(&mut entry).unwrap();
+            extensions.sort();
+            entries.insert(name, (extensions, link_name, mtime));
+        }
+        entries
+    }
+
+    fn pax(records: &[(&str, &str)]) -> Vec<(String, Vec<u8>)> {
+        records
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.as_bytes().to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn rewrite_keeps_the_metadata_of_other_entries() {
+        let path = archive_with_metadata("rewrite");
+        let location = TarLocation::open("tar+rw", &path.to_string_lossy()).unwrap();
+        location.write("dir/new.txt", &mut &b"new"[..]).unwrap();
+
+        let kept = entries(&path);
+        let keep_records = pax(&[
+            ("SCHILY.xattr.user.tag", "blue"),
+            ("mtime", "1700000000.123456789"),
+        ]);
+        assert_eq!(kept["dir/keep.txt"], (keep_records, None, 1_700_000_000));
+        assert_eq!(kept["dir/link"].1.as_deref(), Some(LONG_TARGET));
+        assert!(kept.contains_key(&format!("dir/{}", LONG_TARGET)));
+        assert!(kept.contains_key("dir/new.txt"));
+        let mut content = String::new();
+        let long_name = format!("dir/{}", LONG_TARGET);
+        location
+            .open_read(&long_name)
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "long");
+
+        // A new mtime replaces the precise one, the other records stay
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        location.set_mtime("dir/keep.txt", mtime).unwrap();
+        let kept = entries(&path);
+        let keep_records = pax(&[("SCHILY.xattr.user.tag", "blue")]);
+        assert_eq!(kept["dir/keep.txt"], (keep_records, None, 1_600_000_000));
+        assert_eq!(location.stat("dir/keep.txt").unwrap().unwrap().mtime, mtime);
+        fs::remove_file(&path).unwrap();
+    }
+}
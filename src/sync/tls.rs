@@ -0,0 +1,116 @@
+use anyhow::{anyhow, Result};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+// Builds the TLS client configuration used by the secure locations
+// ca_file = PEM bundle trusted instead of the bundled webpki roots
+// pin = hex SHA-256 fingerprint of the server certificate, when given only that certificate is accepted
+pub fn client_config(ca_file: Option<&str>, pin: Option<&str>) -> Result<Arc<ClientConfig>> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+
+    let config = match pin {
+        Some(pin) => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier::new(pin, provider)?))
+            .with_no_client_auth(),
+        None => {
+            let mut roots = RootCertStore::empty();
+            match ca_file {
+                Some(ca_file) => {
+                    for cert in CertificateDer::pem_file_iter(ca_file)? {
+                        roots.add(cert?)?;
+                    }
+                }
+                None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+    };
+    Ok(Arc::new(config))
+}
+
+// Certificate pinning: the chain is not checked against any CA, instead the server
+// certificate itself has to match the configured fingerprint (self-signed corporate servers)
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    fingerprint: Vec<u8>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl PinnedCertVerifier {
+    fn new(pin: &str, provider: Arc<CryptoProvider>) -> Result<Self> {
+        // Accept the usual "AB:CD:..." notation as well as plain hex
+        let fingerprint = hex::decode(pin.replace(':', ""))
+            .map_err(|_| anyhow!("Invalid certificate pin: {}", pin))?;
+        if fingerprint.len() != 32 {
+            return Err(anyhow!(
+                "Certificate pin must be a SHA-256 fingerprint: {}",
+                pin
+            ));
+        }
+        Ok(Self {
+            fingerprint,
+            provider,
+        })
+    }
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if Sha256::digest(end_entity.as_ref()).as_slice() == self.fingerprint.as_slice() {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "server certificate does not match the pinned fingerprint".to_string(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
@@ -0,0 +1,508 @@
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::DateTime;
+use std::collections::HashSet;
+use std::fmt;
+use std::io::Read;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::sync::location::{FileEntry, Listing, Location};
+use crate::sync::xml;
+use crate::utils::{human_readable_time, uri_decode, uri_encode};
+
+// Properties asked for every resource of a listing
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:"><d:prop><d:resourcetype/><d:getcontentlength/><d:getlastmodified/><d:getetag/></d:prop></d:propfind>"#;
+
+// WebDAV share (Nextcloud, Apache mod_dav...): webdav[s]://[user[:password]@]host[:port]/path
+// webdav: talks plain HTTP, webdavs: HTTPS
+pub struct WebDavLocation {
+    secure: bool,
+    host: String, // host[:port]
+    root: String, // path of the share on the server, without leading or trailing '/'
+    user: Option<String>,
+    authorization: Option<String>, // Basic credentials, sent with every request
+    agent: ureq::Agent,
+    collections: Mutex<HashSet<String>>, // folders known to exist, so writes don't create them again
+}
+
+// One resource of a PROPFIND response
+struct Resource {
+    path: String, // decoded path on the server, without trailing '/'
+    is_collection: bool,
+    size: Option<u64>,
+    last_modified: Option<SystemTime>,
+    etag: Option<String>, // changes with the content, even within the second of Last-Modified
+}
+
+impl WebDavLocation {
+    pub fn open(scheme: &str, spec: &str) -> Result<Box<dyn Location>> {
+        let spec = spec
+            .strip_prefix("//")
+            .ok_or_else(|| anyhow!("expected {}://host/path", scheme))?;
+        let (credentials, host_path) = match spec.rsplit_once('@') {
+            Some((credentials, host_path)) => (Some(credentials), host_path),
+            None => (None, spec),
+        };
+        let (host, root) = host_path.split_once('/').unwrap_or((host_path, ""));
+        if host.is_empty() {
+            return Err(anyhow!("expected {}://host/path", scheme));
+        }
+        let (user, authorization) = match credentials {
+            Some(credentials) => {
+                let (user, password) = credentials.split_once(':').unwrap_or((credentials, ""));
+                let basic = BASE64.encode(format!("{}:{}", user, password));
+                (Some(user.to_string()), Some(format!("Basic {}", basic)))
+            }
+            None => (None, None),
+        };
+
+        Ok(Box::new(Self {
+            secure: scheme == "webdavs",
+            host: host.to_string(),
+            root: root.trim_matches('/').to_string(),
+            user,
+            authorization,
+            agent: ureq::AgentBuilder::new().build(),
+            collections: Mutex::new(HashSet::new()),
+        }))
+    }
+
+    // Path on the server of a path relative to the location
+    fn path(&self, rel_path: &str) -> String {
+        let path = format!("{}/{}", self.root, rel_path);
+        format!("/{}", path.trim_matches('/'))
+    }
+
+    fn url(&self, path: &str) -> String {
+        let scheme = if self.secure { "https" } else { "http" };
+        format!("{}://{}{}", scheme, self.host, uri_encode(path, false))
+    }
+
+    // Sends a request about a path on the server
+    // Error statuses are turned into errors, except the ones listed in allowed
+    fn send(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: Option<&mut dyn Read>,
+        allowed: &[u16],
+    ) -> Result<ureq::Response> {
+        let mut request = self.agent.request(method, &self.url(path));
+        if let Some(authorization) = &self.authorization {
+            request = request.set("Authorization", authorization);
+        }
+        for (name, value) in headers {
+            request = request.set(name, value);
+        }
+        let response = match body {
+            Some(body) => request.send(body),
+            None => request.call(),
+        };
+        match response {
+            Ok(response) => Ok(response),
+            Err(ureq::Error::Status(status, response)) if allowed.contains(&status) => Ok(response),
+            Err(ureq::Error::Status(status, response)) => Err(anyhow!(
+                "WebDAV {} {}{} failed: {} {}",
+                method,
+                self.host,
+                path,
+                status,
+                response.status_text()
+            )),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // PROPFIND of a resource (depth "0") or of a folder and it's children (depth "1")
+    // None when the resource does not exist
+    fn propfind(&self, rel_path: &str, depth: &str) -> Result<Option<Vec<Resource>>> {
+        let response = self.send(
+            "PROPFIND",
+            &self.path(rel_path),
+            &[
+                ("Depth", depth),
+                ("Content-Type", "application/xml; charset=utf-8"),
+            ],
+            Some(&mut PROPFIND_BODY.as_bytes()),
+            &[404],
+        )?;
+        if response.status() == 404 {
+            return Ok(None);
+        }
+        Ok(Some(resources(&response.into_string()?)))
+    }
+
+    // Path relative to the location of a path on the server, None for the root itself
+    fn rel_path<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let rel_path = if self.root.is_empty() {
+            path.trim_start_matches('/')
+        } else {
+            path.trim_start_matches('/')
+                .strip_prefix(&self.root)?
+                .strip_prefix('/')?
+        };
+        (!rel_path.is_empty()).then_some(rel_path)
+    }
+
+    // Every folder on the way to rel_path (the ones above the location included) is created,
+    // unless we already know it exists
+    fn create_parents(&self, rel_path: &str) -> Result<()> {
+        let path = self.path(rel_path);
+        for (end, _) in path.match_indices('/').skip(1) {
+            self.create_folder(&path[..end])?;
+        }
+        Ok(())
+    }
+
+    fn create_folder(&self, path: &str) -> Result<()> {
+        if self.collections.lock().unwrap().contains(path) {
+            return Ok(());
+        }
+        // 405 Method Not Allowed: it already exists
+        self.send("MKCOL", path, &[], None, &[405])?;
+        self.collections.lock().unwrap().insert(path.to_string());
+        Ok(())
+    }
+
+    // A folder that was removed (or moved) and everything in it may have to be created again
+    fn forget(&self, path: &str) {
+        let prefix = format!("{}/", path);
+        self.collections
+            .lock()
+            .unwrap()
+            .retain(|folder| folder != path && !folder.starts_with(&prefix));
+    }
+}
+
+impl Location for WebDavLocation {
+    // Many servers refuse "Depth: infinity", so the tree is walked one folder at a time
+    fn list(&self) -> Result<Listing> {
+        let mut files = Listing::new();
+        let mut folders = vec![String::new()];
+        while let Some(folder) = folders.pop() {
+            let Some(resources) = self.propfind(&folder, "1")? else {
+                continue;
+            };
+            self.collections.lock().unwrap().insert(self.path(&folder));
+            for resource in resources {
+                let Some(rel_path) = self.rel_path(&resource.path) else {
+                    continue;
+                };
+                // The folder itself is part of it's own listing
+                if rel_path == folder || rel_path.contains(".DS") {
+                    continue;
+                }
+                if resource.is_collection {
+                    folders.push(rel_path.to_string());
+                }
+                files.insert(rel_path.to_string(), resource.entry());
+            }
+        }
+        Ok(files)
+    }
+
+    fn stat(&self, rel_path: &str) -> Result<Option<FileEntry>> {
+        let resources = self.propfind(rel_path, "0")?.unwrap_or_default();
+        Ok(resources.first().map(Resource::entry))
+    }
+
+    fn open_read(&self, rel_path: &str) -> Result<Box<dyn Read + '_>> {
+        let response = self.send("GET", &self.path(rel_path), &[], None, &[])?;
+        Ok(Box::new(response.into_reader()))
+    }
+
+    // The content is streamed in the body of a PUT
+    fn write(&self, rel_path: &str, content: &mut dyn Read) -> Result<()> {
+        self.create_parents(rel_path)?;
+        self.send("PUT", &self.path(rel_path), &[], Some(content), &[])?;
+        Ok(())
+    }
+
+    fn mkdir(&self, rel_path: &str) -> Result<()> {
+        self.create_parents(rel_path)?;
+        self.create_folder(&self.path(rel_path))?;
+        println!("Created: {}/{}", self, rel_path);
+        Ok(())
+    }
+
+    // DELETE of a folder removes everything in it
+    fn remove(&self, rel_path: &str) -> Result<()> {
+        let path = self.path(rel_path);
+        self.send("DELETE", &path, &[], None, &[404])?;
+        self.forget(&path);
+        println!("Deleted: {}/{}", self, rel_path);
+        Ok(())
+    }
+
+    // Overwrite: T, like a rename on a file system
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.create_parents(to)?;
+        let from = self.path(from);
+        let destination = self.url(&self.path(to));
+        self.send(
+            "MOVE",
+            &from,
+            &[("Destination", &destination), ("Overwrite", "T")],
+            None,
+            &[],
+        )?;
+        self.forget(&from);
+        Ok(())
+    }
+}
+
+// The password is never shown (locations are printed in logs)
+impl fmt::Display for WebDavLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = if self.secure { "webdavs" } else { "webdav" };
+        match &self.user {
+            Some(user) => write!(f, "{}://{}@{}/{}", scheme, user, self.host, self.root),
+            None => write!(f, "{}://{}/{}", scheme, self.host, self.root),
+        }
+    }
+}
+
+impl Resource {
+    fn entry(&self) -> FileEntry {
+        if self.is_collection {
+            return FileEntry {
+                mtime: UNIX_EPOCH,
+                modified: "Unknown".to_string(),
+                size: None,
+                version: None,
+            };
+        }
+        let mtime = self.last_modified.unwrap_or(UNIX_EPOCH);
+        FileEntry {
+            mtime,
+            modified: human_readable_time(mtime),
+            size: Some(self.size.unwrap_or(0)),
+            version: self.etag.clone(),
+        }
+    }
+}
+
+// Resources of a multistatus answer
+fn resources(body: &str) -> Vec<Resource> {
+    xml::blocks(body, "response")
+        .into_iter()
+        .filter_map(resource)
+        .collect()
+}
+
+// A <response> of a multistatus, only the properties the server found (status 200) are read
+// The href is either a path or a full URL, percent-encoded
+fn resource(response: &str) -> Option<Resource> {
+    let href = xml::value(response, "href")?;
+    let href = href.trim();
+    let path = match href.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("/", |start| &rest[start..]),
+        None => href,
+    };
+    let mut resource = Resource {
+        path: uri_decode(path).trim_end_matches('/').to_string(),
+        is_collection: false,
+        size: None,
+        last_modified: None,
+        etag: None,
+    };
+    for propstat in xml::blocks(response, "propstat") {
+        let found = xml::value(propstat, "status").is_none_or(|status| status.contains(" 200 "));
+        if !found {
+            continue;
+        }
+        if let Some(resource_type) = xml::blocks(propstat, "resourcetype").first() {
+            resource.is_collection = !xml::blocks(resource_type, "collection").is_empty();
+        }
+        if let Some(size) = xml::value(propstat, "getcontentlength") {
+            resource.size = size.trim().parse().ok();
+        }
+        if let Some(etag) = xml::value(propstat, "getetag") {
+            resource.etag = Some(etag.trim().to_string()).filter(|etag| !etag.is_empty());
+        }
+        if let Some(time) = xml::value(propstat, "getlastmodified") {
+            resource.last_modified = DateTime::parse_from_rfc2822(time.trim())
+                .ok()
+                .map(SystemTime::from);
+        }
+    }
+    Some(resource)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // Depth 1 PROPFIND of a folder on Apache mod_dav
+    const APACHE: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<D:multistatus xmlns:D="DAV:" xmlns:ns0="DAV:">
+<D:response xmlns:lp1="DAV:" xmlns:lp2="http://apache.org/dav/props/">
+<D:href>/dav/share/</D:href>
+<D:propstat>
+<D:prop>
+<lp1:resourcetype><D:collection/></lp1:resourcetype>
+<lp1:getlastmodified>Tue, 02 Jan 2024 03:04:05 GMT</lp1:getlastmodified>
+<lp1:getetag>"1000-60ddc8a2b3c40"</lp1:getetag>
+</D:prop>
+<D:status>HTTP/1.1 200 OK</D:status>
+</D:propstat>
+<D:propstat>
+<D:prop>
+<D:getcontentlength/>
+</D:prop>
+<D:status>HTTP/1.1 404 Not Found</D:status>
+</D:propstat>
+</D:response>
+<D:response xmlns:lp1="DAV:" xmlns:lp2="http://apache.org/dav/props/">
+<D:href>/dav/share/My%20Notes.txt</D:href>
+<D:propstat>
+<D:prop>
+<lp1:resourcetype/>
+<lp1:getcontentlength>1403</lp1:getcontentlength>
+<lp1:getlastmodified>Wed, 03 Jan 2024 10:00:00 GMT</lp1:getlastmodified>
+<lp1:getetag>"57b-60ddc8a2b3c40"</lp1:getetag>
+</D:prop>
+<D:status>HTTP/1.1 200 OK</D:status>
+</D:propstat>
+</D:response>
+</D:multistatus>
+"#;
+
+    // Nextcloud (sabre/dav): d: prefix, escaped quotes in the ETag
+    const NEXTCLOUD: &str = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:" xmlns:s="http://sabredav.org/ns" xmlns:oc="http://owncloud.org/ns"><d:response><d:href>/remote.php/dav/files/alice/Photos/</d:href><d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype><d:getlastmodified>Tue, 02 Jan 2024 03:04:05 GMT</d:getlastmodified><d:getetag>&quot;65937e05a1b2c&quot;</d:getetag></d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat><d:propstat><d:prop><d:getcontentlength/></d:prop><d:status>HTTP/1.1 404 Not Found</d:status></d:propstat></d:response><d:response><d:href>/remote.php/dav/files/alice/Photos/caf%C3%A9%20%26%20bar.jpg</d:href><d:propstat><d:prop><d:resourcetype/><d:getcontentlength>2048</d:getcontentlength><d:getlastmodified>Thu, 04 Jan 2024 05:06:07 GMT</d:getlastmodified><d:getetag>&quot;a1b2c3&quot;</d:getetag></d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response></d:multistatus>"#;
+
+    // Full URLs as hrefs, no status, an empty or weak ETag (IIS and others)
+    const FULL_URLS: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<a:multistatus xmlns:a="DAV:">
+<a:response><a:href>https://example.com:8443/share/Docs/</a:href><a:propstat><a:prop><a:resourcetype><a:collection/></a:resourcetype><a:getetag></a:getetag></a:prop></a:propstat></a:response>
+<a:response><a:href>http://example.com</a:href><a:propstat><a:prop><a:resourcetype><a:collection/></a:resourcetype></a:prop></a:propstat></a:response>
+<a:response><a:href>https://example.com/share/Docs/report.pdf</a:href><a:propstat><a:prop><a:resourcetype/><a:getcontentlength> 10 </a:getcontentlength><a:getlastmodified>not a date</a:getlastmodified><a:getetag>W/"weak-1"</a:getetag></a:prop></a:propstat></a:response>
+</a:multistatus>
+"#;
+
+    fn at(secs: u64) -> Option<SystemTime> {
+        Some(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    // path, collection, size, last modified, etag
+    type Parsed = (
+        String,
+        bool,
+        Option<u64>,
+        Option<SystemTime>,
+        Option<String>,
+    );
+
+    fn parse(body: &str) -> Vec<Parsed> {
+        resources(body)
+            .into_iter()
+            .map(|resource| {
+                (
+                    resource.path,
+                    resource.is_collection,
+                    resource.size,
+                    resource.last_modified,
+                    resource.etag,
+                )
+            })
+            .collect()
+    }
+
+    fn etag(etag: &str) -> Option<String> {
+        Some(etag.to_string())
+    }
+
+    #[test]
+    fn apache_propfind() {
+        assert_eq!(
+            parse(APACHE),
+            [
+                (
+                    "/dav/share".to_string(),
+                    true,
+                    None,
+                    at(1704164645),
+                    etag("\"1000-60ddc8a2b3c40\"")
+                ),
+                (
+                    "/dav/share/My Notes.txt".to_string(),
+                    false,
+                    Some(1403),
+                    at(1704276000),
+                    etag("\"57b-60ddc8a2b3c40\"")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn nextcloud_propfind() {
+        assert_eq!(
+            parse(NEXTCLOUD),
+            [
+                (
+                    "/remote.php/dav/files/alice/Photos".to_string(),
+                    true,
+                    None,
+                    at(1704164645),
+                    etag("\"65937e05a1b2c\"")
+                ),
+                (
+                    "/remote.php/dav/files/alice/Photos/café & bar.jpg".to_string(),
+                    false,
+                    Some(2048),
+                    at(1704344767),
+                    etag("\"a1b2c3\"")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn full_url_hrefs() {
+        assert_eq!(
+            parse(FULL_URLS),
+            [
+                ("/share/Docs".to_string(), true, None, None, None),
+                ("".to_string(), true, None, None, None),
+                (
+                    "/share/Docs/report.pdf".to_string(),
+                    false,
+                    Some(10),
+                    None,
+                    etag("W/\"weak-1\"")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn etag_is_the_version_of_files_only() {
+        let apache = resources(APACHE);
+        let folder = apache[0].entry();
+        assert_eq!((folder.size, folder.version), (None, None));
+        let file = apache[1].entry();
+        assert_eq!(file.size, Some(1403));
+        assert_eq!(file.mtime, at(1704276000).unwrap());
+        assert_eq!(file.version, etag("\"57b-60ddc8a2b3c40\""));
+
+        // The same file rewritten within the same second with the same size
+        let rewritten = APACHE.replace("57b-60ddc8a2b3c40", "57b-60ddc8a2b3c41");
+        let rewritten = resources(&rewritten)[1].entry();
+        assert_eq!((rewritten.mtime, rewritten.size), (file.mtime, file.size));
+        assert_ne!(rewritten.version, file.version);
+    }
+
+    #[test]
+    fn responses_without_href_are_skipped() {
+        let body = "<d:multistatus xmlns:d=\"DAV:\"><d:response><d:propstat/></d:response></d:multistatus>";
+        assert!(resources(body).is_empty());
+        assert!(resources("").is_empty());
+    }
+}
@@ -0,0 +1,60 @@
+// Just enough XML for the responses of S3 and WebDAV servers
+// Tags are matched by their local name, whatever namespace prefix the server picked
+// (<Key>, <d:href>, <D:href> and <lp1:getlastmodified> are all found by their name)
+
+// Contents of every <tag>...</tag> of a (flat enough) XML document, "" for <tag/>
+pub fn blocks<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let mut blocks = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let Some(end) = rest.find('>') else {
+            break;
+        };
+        let open = &rest[..end];
+        rest = &rest[end + 1..];
+        let name = open
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default();
+        if name.rsplit(':').next() != Some(tag) {
+            continue;
+        }
+        if open.ends_with('/') {
+            blocks.push("");
+            continue;
+        }
+        // The closing tag uses the same prefix as the opening one
+        let close = format!("</{}>", name);
+        match rest.find(&close) {
+            Some(end) => {
+                blocks.push(&rest[..end]);
+                rest = &rest[end + close.len()..];
+            }
+            None => break,
+        }
+    }
+    blocks
+}
+
+pub fn value(xml: &str, tag: &str) -> Option<String> {
+    blocks(xml, tag).first().map(|value| unescape(value))
+}
+
+pub fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+pub fn unescape(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
@@ -0,0 +1,337 @@
+use anyhow::Result;
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use zip::read::ZipFile;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::errors::FileErrors;
+use crate::sync::archive_path::ArchivePath;
+use crate::sync::folder::write_part;
+use crate::sync::location::{FileEntry, Listing, Location};
+use crate::utils::pipe_reader;
+
+// ZIP archive (or a folder inside it), a read-only source (zip:) unless it is opened as writable (zip+rw:)
+pub struct ZipLocation {
+    path: ArchivePath,
+    writable: bool,
+    rewriting: Mutex<()>, // files are copied in parallel, but the archive is rewritten by one at a time
+}
+
+// What happens to an entry of the old archive when it is rewritten
+enum Keep {
+    Copy,
+    Drop,
+    Rename(String),
+    Touch(zip::DateTime),
+}
+
+impl ZipLocation {
+    // zip:/path/to/archive.zip[!/dir] or zip+rw:/path/to/archive.zip[!/dir], see ArchivePath
+    pub fn open(scheme: &str, spec: &str) -> Result<Box<dyn Location>> {
+        let path = ArchivePath::parse(spec);
+        let writable = scheme == "zip+rw";
+        if writable && path.is_nested() {
+            return Err(FileErrors::InvalidFileForWriting(
+                "archives inside archives are read-only".to_string(),
+            )
+            .into());
+        }
+        Ok(Box::new(Self {
+            path,
+            writable,
+            rewriting: Mutex::new(()),
+        }))
+    }
+
+    fn archive(&self) -> Result<ZipArchive<File>> {
+        archive(&self.path.file()?)
+    }
+
+    fn read_only_error(&self) -> anyhow::Error {
+        FileErrors::InvalidFileForWriting("ZIP file is read-only".to_string()).into()
+    }
+
+    // Builds the new archive in a part file next to the old one and renames it over it, so a crash
+    // never leaves a half written archive behind
+    // Entries that are kept are copied as they are, without being decompressed and compressed again
+    // keep is only asked about the entries inside the folder of the location, by their relative path
+    fn rewrite(
+        &self,
+        keep: impl Fn(&str) -> Keep,
+        add: impl FnOnce(&mut ZipWriter<&mut File>) -> Result<()>,
+    ) -> Result<()> {
+        if !self.writable {
+            return Err(self.read_only_error());
+        }
+        let _rewriting = self.rewriting.lock().unwrap();
+        let outer = self.path.outer();
+        write_part(outer, |file| {
+            let mut writer = ZipWriter::new(file);
+            if outer.exists() {
+                let mut archive = self.archive()?;
+                for i in 0..archive.len() {
+                    let entry = archive.by_index_raw(i)?;
+                    let keep = match self.path.rel_path(entry.name().trim_end_matches('/')) {
+                        Some(rel_path) => keep(rel_path),
+                        None => Keep::Copy,
+                    };
+                    match keep {
+                        Keep::Copy => writer.raw_copy_file(entry)?,
+                        Keep::Drop => {}
+                        Keep::Rename(rel_path) if entry.is_dir() => writer.raw_copy_file_rename(
+                            entry,
+                            format!("{}/", self.path.entry(&rel_path)),
+                        )?,
+                        Keep::Rename(rel_path) => {
+                            writer.raw_copy_file_rename(entry, self.path.entry(&rel_path))?
+                        }
+                        Keep::Touch(time) => {
+                            let mode = entry.unix_mode();
+                            writer.raw_copy_file_touch(entry, time, mode)?
+                        }
+                    }
+                }
+            }
+            add(&mut writer)?;
+            writer.finish()?;
+            Ok(())
+        })
+    }
+}
+
+impl Location for ZipLocation {
+    // Function that uses zip crate to list the files from a zip archive
+    // Folders are listed too, even the ones that only exist as the parent of an entry
+    fn list(&self) -> Result<Listing> {
+        let mut files = Listing::new();
+        if self.writable && !self.path.outer().exists() {
+            // Created on the first write
+            return Ok(files);
+        }
+        let mut archive = self.archive()?;
+
+        for i in 0..archive.len() {
+            let file = archive.by_index(i)?;
+            let name = match self.path.rel_path(file.name().trim_end_matches('/')) {
+                Some(name) if !name.contains(".DS") => name.to_string(),
+                _ => continue,
+            };
+            let mut parent = name.as_str();
+            while let Some((dir, _)) = parent.rsplit_once('/') {
+                files.entry(dir.to_string()).or_insert_with(dir_entry);
+                parent = dir;
+            }
+            if file.is_dir() {
+                files.insert(name, dir_entry());
+            } else {
+                files.insert(name, zip_entry(&file));
+            }
+        }
+
+        Ok(files)
+    }
+
+    fn stat(&self, rel_path: &str) -> Result<Option<FileEntry>> {
+        Ok(self.list()?.remove(rel_path))
+    }
+
+    // An entry borrows it's archive, so it is decompressed on it's own thread and piped to us
+    fn open_read(&self, rel_path: &str) -> Result<Box<dyn Read + '_>> {
+        let name = self.path.entry(rel_path);
+        // Missing entries are reported here rather than on the first read
+        self.archive()?.by_name(&name)?;
+
+        let path = self.path.file()?;
+        Ok(pipe_reader(move |writer| {
+            let mut archive = archive(&path)?;
+            let mut zip_file = archive.by_name(&name)?;
+            io::copy(&mut zip_file, writer)?;
+            Ok(())
+        }))
+    }
+
+    fn write(&self, rel_path: &str, content: &mut dyn Read) -> Result<()> {
+        self.write_with_mtime(rel_path, content, SystemTime::now())
+    }
+
+    // The new entry replaces the old one, it's parents are implied by it's path
+    // It is written with the time of it's source, set_mtime would rewrite the archive once more
+    fn write_with_mtime(
+        &self,
+        rel_path: &str,
+        content: &mut dyn Read,
+        mtime: SystemTime,
+    ) -> Result<()> {
+        self.rewrite(
+            |name| {
+                if name == rel_path {
+                    Keep::Drop
+                } else {
+                    Keep::Copy
+                }
+            },
+            |writer| {
+                let options = SimpleFileOptions::default()
+                    .compression_method(CompressionMethod::Deflated)
+                    .last_modified_time(zip_time(mtime))
+                    .large_file(true);
+                writer.start_file(self.path.entry(rel_path), options)?;
+                io::copy(content, writer)?;
+                Ok(())
+            },
+        )
+    }
+
+    fn mkdir(&self, rel_path: &str) -> Result<()> {
+        if self.list()?.contains_key(rel_path) {
+            return Ok(());
+        }
+        self.rewrite(
+            |_| Keep::Copy,
+            |writer| {
+                writer.add_directory(self.path.entry(rel_path), SimpleFileOptions::default())?;
+                Ok(())
+            },
+        )?;
+        println!("Created: {}/{}", self, rel_path);
+        Ok(())
+    }
+
+    fn remove(&self, rel_path: &str) -> Result<()> {
+        if !self.writable {
+            return Err(
+                FileErrors::InvalidFileForDelete("ZIP file is read-only".to_string()).into(),
+            );
+        }
+        let prefix = format!("{}/", rel_path);
+        self.rewrite(
+            |name| {
+                if name == rel_path || name.starts_with(&prefix) {
+                    Keep::Drop
+                } else {
+                    Keep::Copy
+                }
+            },
+            |_| Ok(()),
+        )?;
+        println!("Deleted: {}/{}", self, rel_path);
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let prefix = format!("{}/", from);
+        self.rewrite(
+            |name| {
+                if name == from {
+                    Keep::Rename(to.to_string())
+                } else if let Some(rest) = name.strip_prefix(&prefix) {
+                    Keep::Rename(format!("{}/{}", to, rest))
+                } else {
+                    Keep::Copy
+                }
+            },
+            |_| Ok(()),
+        )
+    }
+
+    // Only the header of the entry changes, it's data is copied as it is
+    fn set_mtime(&self, rel_path: &str, mtime: SystemTime) -> Result<()> {
+        if !self.writable {
+            return Ok(());
+        }
+        self.rewrite(
+            |name| {
+                if name == rel_path {
+                    Keep::Touch(zip_time(mtime))
+                } else {
+                    Keep::Copy
+                }
+            },
+            |_| Ok(()),
+        )
+    }
+
+    fn read_only(&self) -> bool {
+        !self.writable
+    }
+}
+
+impl fmt::Display for ZipLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = if self.writable { "zip+rw" } else { "zip" };
+        write!(f, "{}:{}", scheme, self.path)
+    }
+}
+
+pub fn archive(path: &Path) -> Result<ZipArchive<File>> {
+    Ok(ZipArchive::new(File::open(path)?)?)
+}
+
+// Folders have no time of their own inside an archive
+fn dir_entry() -> FileEntry {
+    FileEntry {
+        mtime: UNIX_EPOCH,
+        modified: "Unknown".to_string(),
+        size: None,
+        version: None,
+    }
+}
+
+// DOS time of an entry, read back as UTC by zip_entry
+// Times a DOS date can't hold (before 1980) become the default one
+fn zip_time(time: SystemTime) -> zip::DateTime {
+    let time: DateTime<Utc> = time.into();
+    zip::DateTime::from_date_and_time(
+        time.year() as u16,
+        time.month() as u8,
+        time.day() as u8,
+        time.hour() as u8,
+        time.minute() as u8,
+        time.second() as u8,
+    )
+    .unwrap_or_default()
+}
+
+// (unix epoch - human readable time - size) of a file inside the archive
+// ZIP entries store a DOS time (2 seconds precision, no timezone)
+fn zip_entry(file: &ZipFile<'_>) -> FileEntry {
+    let (mtime, modified) = match file.last_modified() {
+        Some(time) => {
+            let naive_date = chrono::NaiveDate::from_ymd_opt(
+                time.year().into(),
+                time.month().into(),
+                time.day().into(),
+            )
+            .and_then(|date| {
+                date.and_hms_opt(
+                    time.hour().into(),
+                    time.minute().into(),
+                    time.second().into(),
+                )
+            });
+            match naive_date {
+                Some(naive_date) => {
+                    let unix_timestamp = naive_date.and_utc().timestamp() as u64;
+                    (
+                        UNIX_EPOCH + Duration::from_secs(unix_timestamp),
+                        naive_date.format("%Y-%m-%d %H:%M:%S").to_string(),
+                    )
+                }
+                None => (UNIX_EPOCH, "Unknown".to_string()),
+            }
+        }
+        None => (UNIX_EPOCH, "Unknown".to_string()),
+    };
+    FileEntry {
+        mtime,
+        modified,
+        size: Some(file.size()),
+        version: None,
+    }
+}
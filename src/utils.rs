@@ -1,209 +1,160 @@
 use anyhow::Result;
 use chrono::{DateTime, Local};
-use fs::{create_dir_all, metadata, File};
-use std::collections::HashMap;
-use std::io::Read;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
 use std::path::Path;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use std::{fs, thread, time};
-use walkdir::WalkDir;
-use zip::ZipArchive;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+use std::time::{Duration, SystemTime};
 
 use crate::cli_parsing;
-use crate::errors::FileErrors;
-pub use crate::sync::modes::CreateType;
-pub use crate::sync::*;
-
-// Function that uses zip crate to list the files from a zip archive
-pub fn list_files_in_zip(
-    zip_path: &str,
-) -> Result<HashMap<String, (LocTypes, SystemTime, String)>> {
-    let mut files = HashMap::new();
-    let file = File::open(zip_path)?;
-    let mut archive = ZipArchive::new(file)?;
-
-    for i in 0..archive.len() {
-        let file = archive.by_index(i)?;
-        if file.is_dir() || file.name().contains(".DS") {
-            continue;
-        }
-        let file_path = format!("{}/{}", zip_path, file.name());
-
-        let (system_time, human_readable_time) = match file.last_modified() {
-            Some(time) => {
-                let naive_date = chrono::NaiveDate::from_ymd_opt(
-                    time.year().into(),
-                    time.month().into(),
-                    time.day().into(),
-                )
-                .and_then(|date| {
-                    date.and_hms_opt(
-                        time.hour().into(),
-                        time.minute().into(),
-                        time.second().into(),
-                    )
-                });
-                match naive_date {
-                    Some(naive_date) => {
-                        let unix_timestamp = naive_date.and_utc().timestamp() as u64;
-                        let system_time = UNIX_EPOCH + time::Duration::from_secs(unix_timestamp);
-                        (
-                            system_time,
-                            format!(
-                                "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
-                                time.year(),
-                                time.month(),
-                                time.day(),
-                                time.hour(),
-                                time.minute(),
-                                time.second()
-                            ),
-                        )
-                    }
-                    None => (UNIX_EPOCH, "Unknown".to_string()),
-                }
-            }
-            None => (UNIX_EPOCH, "Unknown".to_string()),
-        };
-        let rel_path = relative_path(zip_path, &file_path).unwrap();
-        files.insert(
-            rel_path,
-            (LocTypes::Zip(file_path), system_time, human_readable_time),
-        );
+
+// Local time of a file, as shown to the user
+pub fn human_readable_time(time: SystemTime) -> String {
+    let datetime: DateTime<Local> = time.into();
+    datetime.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+// Size of a file, as shown to the user
+pub fn human_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", size)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
     }
+}
 
-    Ok(files)
+// Name of this machine, used to tell where a conflicting version comes from
+pub fn host_name() -> String {
+    std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .or_else(|_| fs::read_to_string("/etc/hostname"))
+        .map(|name| name.trim().to_string())
+        .ok()
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "localhost".to_string())
 }
 
-// Function that list files from a dir and stores them in a HashMap that is convenient for searching in O(1)
-// Result <Hashmap<relative_path, (absolute_path, unix_epoch modif time, human read. modif time)
-pub fn list_files_recursive(dir: &str) -> Result<HashMap<String, (LocTypes, SystemTime, String)>> {
-    let mut files = HashMap::new();
-    for entry in WalkDir::new(dir) {
-        let entry = entry?;
-        let entry_path = entry.path().to_str().unwrap().to_string();
-        if entry_path.contains(".DS") {
-            continue;
-        }
-        let last_modified_tuple = get_last_modified_time(&entry_path)?;
-        let rel_path = relative_path(dir, &entry_path).unwrap();
-        if entry.path().is_dir() {
-            files.insert(
-                rel_path,
-                (
-                    LocTypes::Folder(entry_path),
-                    last_modified_tuple.0,
-                    last_modified_tuple.1,
-                ),
-            );
-        } else {
-            files.insert(
-                rel_path,
-                (
-                    LocTypes::SimpleFile(entry_path),
-                    last_modified_tuple.0,
-                    last_modified_tuple.1,
-                ),
-            );
+// Size of the chunks that go through a pipe, at most PIPE_CHUNKS of them are in memory at once
+const PIPE_CHUNK_SIZE: usize = 64 * 1024;
+const PIPE_CHUNKS: usize = 4;
+
+// Runs the producer on it's own thread and returns what it writes as a stream
+// Used for readers that borrow what they read from (like the entries of an archive),
+// memory stays bounded whatever the size of the file
+pub fn pipe_reader<F>(producer: F) -> Box<dyn Read + Send>
+where
+    F: FnOnce(&mut dyn Write) -> Result<()> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::sync_channel(PIPE_CHUNKS);
+    thread::spawn(move || {
+        let mut writer = BufWriter::with_capacity(
+            PIPE_CHUNK_SIZE,
+            PipeWriter {
+                sender: sender.clone(),
+            },
+        );
+        let result = producer(&mut writer).and_then(|_| Ok(writer.flush()?));
+        if let Err(e) = result {
+            // The reader may already be gone, then nobody needs the error
+            let _ = sender.send(Err(io::Error::other(e.to_string())));
         }
-    }
-    Ok(files)
+    });
+    Box::new(PipeReader {
+        receiver,
+        chunk: Vec::new(),
+        pos: 0,
+    })
 }
 
-// (unix epoch - human readable time)
-fn get_last_modified_time(file_path: &str) -> Result<(SystemTime, String)> {
-    let metadata = fs::metadata(file_path)?;
-    let modified_time = metadata.modified()?;
-    let datetime: DateTime<Local> = modified_time.into();
-    Ok((
-        modified_time,
-        datetime.format("%Y-%m-%d %H:%M:%S").to_string(),
-    ))
+struct PipeWriter {
+    sender: SyncSender<io::Result<Vec<u8>>>,
 }
 
-pub fn file_as_bytes(file_path: &str) -> Option<Vec<u8>> {
-    if let Some(zip_pos) = file_path.find(".zip") {
-        let (zip_path, inner_path) = file_path.split_at(zip_pos + 4); // +4 for ".zip"
-        let inner_path = inner_path.trim_start_matches('/');
-        let zip_file = File::open(zip_path).ok()?;
-        let mut zip_archive = ZipArchive::new(zip_file).ok()?;
-        let mut zip_file = zip_archive.by_name(inner_path).ok()?;
-        let mut buffer = Vec::new();
-        zip_file.read_to_end(&mut buffer).ok()?;
-
-        return Some(buffer);
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sender
+            .send(Ok(buf.to_vec()))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
     }
 
-    let file = File::open(file_path).ok()?;
-    let upper_bound_size = 1024 * 1024 * 1024 * 200; // 200MB stack max
-    let mut buffer = Vec::new();
-    let mut handle = file.take(upper_bound_size);
-    match handle.read_to_end(&mut buffer) {
-        Ok(_) => Some(buffer),
-        Err(_) => None,
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
     }
 }
 
-pub fn paste_to_file(path: &str, content: &[u8]) -> Result<()> {
-    Ok(fs::write(path, content)?)
+struct PipeReader {
+    receiver: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
 }
 
-pub fn delete(path: &str) -> Result<()> {
-    let path = Path::new(path);
-
-    if path.is_dir() {
-        fs::remove_dir_all(path)?;
-    } else if path.is_file() {
-        fs::remove_file(path)?;
-    } else {
-        return Err(anyhow::anyhow!("No such file or directory"));
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.receiver.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk?;
+                    self.pos = 0;
+                }
+                // The producer is done
+                Err(_) => return Ok(0),
+            }
+        }
+        let read = buf.len().min(self.chunk.len() - self.pos);
+        buf[..read].copy_from_slice(&self.chunk[self.pos..self.pos + read]);
+        self.pos += read;
+        Ok(read)
     }
-    println!("Deleted: {:?}", path);
-    Ok(())
 }
 
-// Function that creates a file and all of it's parents if necessary
-pub fn create(path: &str, create_type: CreateType) -> Result<()> {
-    match create_type {
-        CreateType::Folder => {
-            if path.contains(".") {
-                return Err(FileErrors::InvalidFileForCreating(
-                    "Folders that contain a dot (.) are not accepted. Please rename".to_string(),
-                )
-                .into());
+// Percent-encoding of URLs (and of SigV4): everything but A-Z a-z 0-9 - _ . ~ (and '/' in paths)
+pub fn uri_encode(value: &str, encode_slash: bool) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
             }
-            fs::create_dir_all(path)?;
-            println!("Created: {:?}", path);
-            Ok(())
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
         }
-        CreateType::File => {
-            let path = Path::new(path);
-
-            if let Ok(meta) = metadata(path) {
-                if meta.is_file() {
-                    // If the path already exists and is a file
-                    println!("File exists: {:?}", path);
-                    return Ok(());
-                } else if meta.is_dir() {
-                    // If the path already exists and is a directory
-                    println!("Path exists as a directory: {:?}", path);
-                    return Ok(());
-                }
-            }
+    }
+    encoded
+}
 
-            // If the path doesn't exist or isn't a file, create it
-            if let Some(parent) = path.parent() {
-                create_dir_all(parent)?;
+// Reverse of uri_encode, invalid escapes are kept as they are
+pub fn uri_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok());
+        match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            Some(byte) if bytes[i] == b'%' => {
+                decoded.push(byte);
+                i += 3;
+            }
+            _ => {
+                decoded.push(bytes[i]);
+                i += 1;
             }
-
-            File::create(path)?;
-            println!("Created: {:?}", path);
-            Ok(())
         }
     }
+    String::from_utf8_lossy(&decoded).to_string()
 }
 
-fn relative_path(base: &str, target: &str) -> Option<String> {
+pub fn relative_path(base: &str, target: &str) -> Option<String> {
     let base_path = Path::new(base);
     let target_path = Path::new(target);
 
@@ -233,10 +184,10 @@ pub fn perform_check() -> Result<()> {
         let locations = cli_parsing::retrieve_locations()?;
         let file_name = ".temp_check";
         for loc in locations {
-            if let LocTypes::Folder(path) = loc.clone() {
-                loc.create_file(file_name, CreateType::File)?;
-                let that_file = LocTypes::SimpleFile(format!("{}/{}", path, file_name));
-                that_file.delete_file()?;
+            if let Some(path) = loc.watch_path() {
+                let check_path = path.join(file_name);
+                File::create(&check_path)?;
+                fs::remove_file(&check_path)?;
                 break;
             }
         }